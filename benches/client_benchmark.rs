@@ -50,7 +50,10 @@ fn create_account_benchmark(c: &mut Criterion) {
                     email: Some(String::from("john_doe@example.com")),
                     balance: Some(0),
                     created_at: None,
+                    metadata: None,
+                    tags: None,
                 },
+                None,
             )
         })
     });
@@ -1,4 +1,5 @@
 use chrono::Utc;
+use std::fs;
 use std::process::Command;
 
 fn main() {
@@ -36,4 +37,25 @@ fn main() {
         .unwrap();
     let git_ver = String::from_utf8(git_output.stdout).unwrap();
     println!("cargo:rustc-env=GIT_VERSION_TAG={}", git_ver);
+
+    // Latest schema migration version shipped with this build, used by the
+    // readiness check to detect pending migrations
+    let latest_migration = fs::read_dir("sql/migrations")
+        .unwrap()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| name.ends_with(".up.sql"))
+        .filter_map(|name| name.split('_').next()?.parse::<i64>().ok())
+        .max()
+        .unwrap_or(0);
+    println!("cargo:rustc-env=LATEST_MIGRATION={}", latest_migration);
+
+    // gRPC services and messages, compiled with a vendored protoc so that no
+    // system install is needed. No rerun-if-changed is emitted, which would
+    // stop the script from rerunning on new commits and migrations.
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_build::configure()
+        .emit_rerun_if_changed(false)
+        .compile_protos(&["proto/ledger.proto"], &["proto"])
+        .unwrap();
 }
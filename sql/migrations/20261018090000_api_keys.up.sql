@@ -0,0 +1,10 @@
+CREATE TABLE "api_keys" (
+  "id" bigserial PRIMARY KEY,
+  "name" varchar NOT NULL,
+  "key_prefix" varchar NOT NULL,
+  "key_hash" varchar NOT NULL UNIQUE,
+  "scopes" text[] NOT NULL,
+  "created_at" timestamptz DEFAULT (now()),
+  "last_used_at" timestamptz,
+  "revoked_at" timestamptz
+);
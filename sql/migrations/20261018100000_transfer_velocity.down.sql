@@ -0,0 +1 @@
+DROP INDEX IF EXISTS "transactions_from_account_created_at_idx";
@@ -0,0 +1 @@
+CREATE INDEX "transactions_from_account_created_at_idx" ON "transactions" ("from_account", "created_at");
@@ -0,0 +1,33 @@
+CREATE TABLE "audit_log" (
+  "id" bigserial PRIMARY KEY,
+  "actor" varchar NOT NULL,
+  "request_id" varchar,
+  "operation" varchar NOT NULL,
+  "entity" varchar NOT NULL,
+  "entity_id" bigint,
+  "before" jsonb,
+  "after" jsonb,
+  "source_ip" varchar,
+  "created_at" timestamptz NOT NULL,
+  "prev_hash" char(64) NOT NULL,
+  "hash" char(64) NOT NULL UNIQUE
+);
+
+CREATE INDEX ON "audit_log" ("entity", "entity_id");
+
+CREATE INDEX ON "audit_log" ("actor");
+
+CREATE INDEX ON "audit_log" ("created_at");
+
+-- the audit log is append-only
+CREATE FUNCTION audit_log_immutable() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "audit_log_no_update" BEFORE UPDATE OR DELETE ON "audit_log"
+  FOR EACH ROW EXECUTE FUNCTION audit_log_immutable();
+
+CREATE TRIGGER "audit_log_no_truncate" BEFORE TRUNCATE ON "audit_log"
+  FOR EACH STATEMENT EXECUTE FUNCTION audit_log_immutable();
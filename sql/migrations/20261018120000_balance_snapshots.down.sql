@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS "transactions_to_account_created_at_idx";
+
+DROP TABLE IF EXISTS "balance_snapshots";
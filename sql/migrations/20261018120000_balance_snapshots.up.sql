@@ -0,0 +1,12 @@
+CREATE TABLE "balance_snapshots" (
+  "account_id" bigint NOT NULL,
+  "as_of" timestamptz NOT NULL,
+  "balance" bigint NOT NULL,
+  PRIMARY KEY ("account_id", "as_of")
+);
+
+CREATE INDEX ON "balance_snapshots" ("as_of");
+
+CREATE INDEX "transactions_to_account_created_at_idx" ON "transactions" ("to_account", "created_at");
+
+ALTER TABLE "balance_snapshots" ADD FOREIGN KEY ("account_id") REFERENCES "accounts" ("id");
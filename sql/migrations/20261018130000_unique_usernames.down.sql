@@ -0,0 +1,3 @@
+CREATE INDEX "accounts_username_idx" ON "accounts" ("username");
+
+DROP INDEX IF EXISTS "accounts_username_key";
@@ -0,0 +1,26 @@
+-- usernames identify accounts in batch creation and imports, so they must be
+-- unique. The index build would fail on the first duplicate already stored, so
+-- they are looked for first and the migration stops listing all of them; they
+-- have to be renamed by hand before running it again (see Migrations in the
+-- README).
+DO $$
+DECLARE
+  duplicates text;
+BEGIN
+  SELECT string_agg(format('%L (accounts %s)', "username", ids), ', ' ORDER BY "username") INTO duplicates
+  FROM (
+    SELECT "username", string_agg("id"::text, ', ' ORDER BY "id") AS ids
+    FROM "accounts"
+    GROUP BY "username"
+    HAVING count(*) > 1
+  ) d;
+  IF duplicates IS NOT NULL THEN
+    RAISE EXCEPTION 'usernames used by several accounts: %', duplicates
+      USING HINT = 'rename the duplicates so that every username is unique, then run the migration again';
+  END IF;
+END
+$$;
+
+CREATE UNIQUE INDEX "accounts_username_key" ON "accounts" ("username");
+
+DROP INDEX IF EXISTS "accounts_username_idx";
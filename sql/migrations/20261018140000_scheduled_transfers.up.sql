@@ -0,0 +1,44 @@
+CREATE TABLE "scheduled_transfers" (
+  "id" bigserial PRIMARY KEY,
+  "from_account" bigint NOT NULL,
+  "to_account" bigint NOT NULL,
+  "amount" bigint NOT NULL,
+  -- NULL for a one-off transfer, otherwise daily, weekly, monthly or a cron expression
+  "recurrence" varchar,
+  -- active, paused, completed, failed or cancelled
+  "status" varchar NOT NULL DEFAULT 'active',
+  -- first occurrence, recurrences are computed from it
+  "starts_at" timestamptz NOT NULL,
+  "next_run_at" timestamptz,
+  -- set while a failed run waits to be retried
+  "retry_at" timestamptz,
+  "attempts" int NOT NULL DEFAULT 0,
+  "last_error" varchar,
+  "last_run_at" timestamptz,
+  "runs" bigint NOT NULL DEFAULT 0,
+  "created_by" varchar,
+  "created_at" timestamptz NOT NULL DEFAULT (now()),
+  "updated_at" timestamptz NOT NULL DEFAULT (now())
+);
+
+CREATE INDEX "scheduled_transfers_due_idx" ON "scheduled_transfers" ((COALESCE("retry_at", "next_run_at"))) WHERE "status" = 'active';
+
+CREATE INDEX ON "scheduled_transfers" ("from_account");
+
+-- one row per executed occurrence, the primary key guarantees that no
+-- occurrence is executed twice
+CREATE TABLE "scheduled_transfer_runs" (
+  "schedule_id" bigint NOT NULL,
+  "scheduled_for" timestamptz NOT NULL,
+  "transaction_id" bigint NOT NULL,
+  "executed_at" timestamptz NOT NULL DEFAULT (now()),
+  PRIMARY KEY ("schedule_id", "scheduled_for")
+);
+
+ALTER TABLE "scheduled_transfers" ADD FOREIGN KEY ("from_account") REFERENCES "accounts" ("id");
+
+ALTER TABLE "scheduled_transfers" ADD FOREIGN KEY ("to_account") REFERENCES "accounts" ("id");
+
+ALTER TABLE "scheduled_transfer_runs" ADD FOREIGN KEY ("schedule_id") REFERENCES "scheduled_transfers" ("id");
+
+ALTER TABLE "scheduled_transfer_runs" ADD FOREIGN KEY ("transaction_id") REFERENCES "transactions" ("id");
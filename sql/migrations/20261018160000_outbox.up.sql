@@ -0,0 +1,13 @@
+-- events written in the transaction of the change they describe and published
+-- by the relay in id order
+CREATE TABLE "outbox" (
+  "id" bigserial PRIMARY KEY,
+  "event_type" varchar NOT NULL,
+  "aggregate" varchar NOT NULL,
+  "aggregate_id" bigint NOT NULL,
+  "payload" jsonb NOT NULL,
+  "created_at" timestamptz NOT NULL DEFAULT (now()),
+  "delivered_at" timestamptz
+);
+
+CREATE INDEX "outbox_pending_idx" ON "outbox" ("id") WHERE "delivered_at" IS NULL;
@@ -0,0 +1,11 @@
+-- announce every outbox event on the ledger_events channel once its transaction
+-- commits, so that all server instances stream it
+CREATE FUNCTION "notify_ledger_event"() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify('ledger_events', NEW.id::text);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER "outbox_notify" AFTER INSERT ON "outbox"
+  FOR EACH ROW EXECUTE FUNCTION "notify_ledger_event"();
@@ -0,0 +1,9 @@
+-- caller supplied identifiers and labels of accounts; size limits are enforced
+-- by the server (accounts config section)
+ALTER TABLE "accounts"
+  ADD COLUMN "metadata" jsonb NOT NULL DEFAULT '{}',
+  ADD COLUMN "tags" text[] NOT NULL DEFAULT '{}';
+
+-- containment (@>) filters on metadata and tags
+CREATE INDEX "accounts_metadata_idx" ON "accounts" USING GIN ("metadata" jsonb_path_ops);
+CREATE INDEX "accounts_tags_idx" ON "accounts" USING GIN ("tags");
@@ -0,0 +1,10 @@
+DROP INDEX IF EXISTS "transactions_category_idx";
+DROP INDEX IF EXISTS "transactions_reference_idx";
+DROP INDEX IF EXISTS "transactions_external_id_idx";
+
+ALTER TABLE "transactions"
+  DROP COLUMN IF EXISTS "created_by",
+  DROP COLUMN IF EXISTS "external_id",
+  DROP COLUMN IF EXISTS "category",
+  DROP COLUMN IF EXISTS "reference",
+  DROP COLUMN IF EXISTS "description";
@@ -0,0 +1,15 @@
+-- caller supplied details of transfers. external_id is the caller's own id of
+-- the transfer and is unique per caller (created_by, the authenticated
+-- principal), so a retried request cannot transfer twice.
+ALTER TABLE "transactions"
+  ADD COLUMN "description" varchar,
+  ADD COLUMN "reference" varchar,
+  ADD COLUMN "category" varchar,
+  ADD COLUMN "external_id" varchar,
+  ADD COLUMN "created_by" varchar;
+
+CREATE UNIQUE INDEX "transactions_external_id_idx" ON "transactions" ("created_by", "external_id") WHERE "external_id" IS NOT NULL;
+
+CREATE INDEX "transactions_reference_idx" ON "transactions" ("reference") WHERE "reference" IS NOT NULL;
+
+CREATE INDEX "transactions_category_idx" ON "transactions" ("category") WHERE "category" IS NOT NULL;
@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS "transactions_search_idx";
+DROP INDEX IF EXISTS "transactions_reference_trgm_idx";
+DROP INDEX IF EXISTS "transactions_description_trgm_idx";
+DROP INDEX IF EXISTS "accounts_email_trgm_idx";
+DROP INDEX IF EXISTS "accounts_username_trgm_idx";
+
+-- pg_trgm is left installed, other objects may use it
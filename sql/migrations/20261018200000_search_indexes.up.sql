@@ -0,0 +1,16 @@
+-- indexes of GET /search: trigram indexes for partial and fuzzy matches on
+-- names, emails and references, and a full-text index over the transfer
+-- details. pg_trgm ships with Postgres; creating it needs the CREATE
+-- privilege on the database.
+CREATE EXTENSION IF NOT EXISTS "pg_trgm";
+
+CREATE INDEX "accounts_username_trgm_idx" ON "accounts" USING GIN ("username" gin_trgm_ops);
+CREATE INDEX "accounts_email_trgm_idx" ON "accounts" USING GIN ("email" gin_trgm_ops);
+
+CREATE INDEX "transactions_description_trgm_idx" ON "transactions" USING GIN ("description" gin_trgm_ops);
+CREATE INDEX "transactions_reference_trgm_idx" ON "transactions" USING GIN ("reference" gin_trgm_ops);
+
+-- the expression must match the one searched in db.rs
+CREATE INDEX "transactions_search_idx" ON "transactions" USING GIN (
+  to_tsvector('simple', COALESCE("description", '') || ' ' || COALESCE("reference", ''))
+);
@@ -0,0 +1,2 @@
+ALTER TABLE "scheduled_transfers" DROP CONSTRAINT IF EXISTS "scheduled_transfers_amount_positive";
+ALTER TABLE "transactions" DROP CONSTRAINT IF EXISTS "transactions_amount_positive";
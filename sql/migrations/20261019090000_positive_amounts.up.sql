@@ -0,0 +1,29 @@
+-- transfers move a positive amount from the debited account; a negative one
+-- would pull funds out of the credited account.
+-- The constraints are added NOT VALID so that new rows are checked at once
+-- without scanning the existing ones under an exclusive lock. The existing rows
+-- are validated below when none of them breaks the constraint; otherwise they
+-- are reported and the constraint stays NOT VALID until they are corrected and
+-- it is validated by hand (see Migrations in the README).
+ALTER TABLE "transactions" ADD CONSTRAINT "transactions_amount_positive" CHECK ("amount" > 0) NOT VALID;
+ALTER TABLE "scheduled_transfers" ADD CONSTRAINT "scheduled_transfers_amount_positive" CHECK ("amount" > 0) NOT VALID;
+
+DO $$
+DECLARE
+  offending bigint[];
+BEGIN
+  SELECT array_agg("id" ORDER BY "id") INTO offending FROM "transactions" WHERE "amount" <= 0;
+  IF offending IS NULL THEN
+    ALTER TABLE "transactions" VALIDATE CONSTRAINT "transactions_amount_positive";
+  ELSE
+    RAISE WARNING 'transactions_amount_positive left NOT VALID, transactions without a positive amount: %', offending;
+  END IF;
+
+  SELECT array_agg("id" ORDER BY "id") INTO offending FROM "scheduled_transfers" WHERE "amount" <= 0;
+  IF offending IS NULL THEN
+    ALTER TABLE "scheduled_transfers" VALIDATE CONSTRAINT "scheduled_transfers_amount_positive";
+  ELSE
+    RAISE WARNING 'scheduled_transfers_amount_positive left NOT VALID, scheduled transfers without a positive amount: %', offending;
+  END IF;
+END
+$$;
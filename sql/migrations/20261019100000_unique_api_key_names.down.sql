@@ -0,0 +1 @@
+DROP INDEX IF EXISTS "api_keys_active_name_key";
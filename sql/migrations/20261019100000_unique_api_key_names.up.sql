@@ -0,0 +1,3 @@
+-- the key name is the principal recorded in the audit log and used for rate
+-- limits and transfer idempotency, so active keys must not share one
+CREATE UNIQUE INDEX "api_keys_active_name_key" ON "api_keys" ("name") WHERE "revoked_at" IS NULL;
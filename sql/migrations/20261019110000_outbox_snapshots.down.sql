@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS "outbox_xid_idx";
+
+ALTER TABLE "outbox" DROP COLUMN IF EXISTS "snapshot", DROP COLUMN IF EXISTS "xid";
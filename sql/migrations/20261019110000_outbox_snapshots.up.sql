@@ -0,0 +1,11 @@
+-- the transaction and snapshot each event was written in. Ids are taken before
+-- commit, so a lower id can commit after a higher one; readers resuming after
+-- an event use its snapshot to find the lower ids that were still in flight.
+-- Existing events are left without one, they all committed long ago.
+ALTER TABLE "outbox" ADD COLUMN "xid" xid8, ADD COLUMN "snapshot" pg_snapshot;
+
+ALTER TABLE "outbox"
+  ALTER COLUMN "xid" SET DEFAULT (pg_current_xact_id()),
+  ALTER COLUMN "snapshot" SET DEFAULT (pg_current_snapshot());
+
+CREATE INDEX "outbox_xid_idx" ON "outbox" ("xid");
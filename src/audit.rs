@@ -0,0 +1,311 @@
+// Append-only audit log. Every state-changing operation writes an entry in
+// the same database transaction as the change itself, recording who did it,
+// from where and the entity before/after. Entries are hash-chained: each hash
+// covers the entry and the previous entry's hash, so modifying, removing or
+// reordering entries breaks the chain and is reported by `audit verify`.
+use crate::{
+    auth::principal,
+    db,
+    errors::MyError,
+    middleware::request_id,
+    model::{AuditEntry, AuditQuery},
+};
+use actix_web::HttpRequest;
+use chrono::{DurationRound, SecondsFormat, TimeDelta, Utc};
+use deadpool_postgres::{Client, GenericClient};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+// prev_hash of the first entry in the chain
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+// number of entries read at a time by verify
+const VERIFY_BATCH_SIZE: i64 = 1000;
+
+// AuditContext identifies who performed an operation.
+#[derive(Clone, Debug)]
+pub struct AuditContext {
+    pub actor: String,
+    pub request_id: Option<String>,
+    pub source_ip: Option<String>,
+}
+
+impl AuditContext {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        AuditContext {
+            actor: principal(req)
+                .map(|p| p.name)
+                .unwrap_or_else(|| "anonymous".to_string()),
+            request_id: request_id(req),
+            source_ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+        }
+    }
+
+    // cli is used for operations run from the command line on this host.
+    pub fn cli() -> Self {
+        let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        AuditContext {
+            actor: format!("cli:{}", user),
+            request_id: None,
+            source_ip: None,
+        }
+    }
+
+    // system is used for jobs run by the server itself.
+    pub fn system(job: &str) -> Self {
+        AuditContext {
+            actor: format!("system:{}", job),
+            request_id: None,
+            source_ip: None,
+        }
+    }
+}
+
+// record appends an entry for an operation on an entity to the audit chain.
+// It must be called on the transaction performing the change.
+pub async fn record<T: Serialize>(
+    client: &impl GenericClient,
+    context: &AuditContext,
+    operation: &str,
+    entity: &str,
+    entity_id: Option<i64>,
+    before: Option<&T>,
+    after: Option<&T>,
+) -> Result<AuditEntry, MyError> {
+    db::lock_audit_chain(client).await?;
+    let prev_hash = db::last_audit_hash(client)
+        .await?
+        .unwrap_or_else(|| GENESIS_HASH.to_string());
+
+    let mut entry = AuditEntry {
+        id: 0, // To be set by Postgres
+        actor: context.actor.clone(),
+        request_id: context.request_id.clone(),
+        operation: operation.to_string(),
+        entity: entity.to_string(),
+        entity_id,
+        before: before.map(to_json),
+        after: after.map(to_json),
+        source_ip: context.source_ip.clone(),
+        // Postgres stores microseconds, truncate so the hash survives the round trip
+        created_at: Utc::now().duration_trunc(TimeDelta::microseconds(1)).ok(),
+        prev_hash,
+        hash: String::new(),
+    };
+    entry.hash = entry_hash(&entry);
+
+    db::insert_audit_entry(client, &entry).await
+}
+
+// entry_hash computes the chain hash of an entry from its content and prev_hash.
+pub fn entry_hash(entry: &AuditEntry) -> String {
+    let content = json!({
+        "actor": entry.actor,
+        "request_id": entry.request_id,
+        "operation": entry.operation,
+        "entity": entry.entity,
+        "entity_id": entry.entity_id,
+        "before": entry.before,
+        "after": entry.after,
+        "source_ip": entry.source_ip,
+        "created_at": entry
+            .created_at
+            .map(|t| t.to_rfc3339_opts(SecondsFormat::Micros, true)),
+    });
+
+    let mut hasher = Sha256::new();
+    hasher.update(entry.prev_hash.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(canonical_json(&content).as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+// VerifyReport is the outcome of checking the whole chain.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub entries: u64,
+    // id of the first entry that does not match the chain, and why
+    pub broken_at: Option<(i64, String)>,
+}
+
+// verify walks the audit log from the start, recomputing every hash.
+pub async fn verify(client: &Client) -> Result<VerifyReport, MyError> {
+    let mut report = VerifyReport::default();
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut query = AuditQuery::default();
+
+    loop {
+        let entries = db::get_audit_entries(client, &query, VERIFY_BATCH_SIZE).await?;
+        let Some(last) = entries.last() else {
+            return Ok(report);
+        };
+        query.after_id = Some(last.id);
+
+        if !check_entries(&mut report, &mut prev_hash, entries) {
+            return Ok(report);
+        }
+    }
+}
+
+// check_entries continues the chain ending with prev_hash with entries,
+// stopping at the first entry that does not belong to it. It returns false
+// once the chain is broken.
+fn check_entries(
+    report: &mut VerifyReport,
+    prev_hash: &mut String,
+    entries: Vec<AuditEntry>,
+) -> bool {
+    for entry in entries {
+        if entry.prev_hash != *prev_hash {
+            report.broken_at = Some((
+                entry.id,
+                format!(
+                    "prev_hash {} does not match the preceding entry hash {}",
+                    entry.prev_hash, prev_hash
+                ),
+            ));
+            return false;
+        }
+        let expected = entry_hash(&entry);
+        if entry.hash != expected {
+            report.broken_at = Some((
+                entry.id,
+                format!(
+                    "hash {} does not match its content ({})",
+                    entry.hash, expected
+                ),
+            ));
+            return false;
+        }
+        report.entries += 1;
+        *prev_hash = entry.hash;
+    }
+    true
+}
+
+fn to_json<T: Serialize>(value: &T) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+// canonical_json renders a value with object keys sorted, so that the hash does
+// not depend on the key order returned by jsonb.
+fn canonical_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|key| {
+                    format!(
+                        "{}:{}",
+                        Value::from(key.as_str()),
+                        canonical_json(&map[key])
+                    )
+                })
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(canonical_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canonical_json, check_entries, entry_hash, VerifyReport, GENESIS_HASH};
+    use crate::model::AuditEntry;
+    use serde_json::json;
+
+    // chain links entries with the given after values, as record would.
+    fn chain(afters: &[i64]) -> Vec<AuditEntry> {
+        let mut prev_hash = GENESIS_HASH.to_string();
+        let mut entries = Vec::new();
+        for (i, amount) in afters.iter().enumerate() {
+            let mut entry = AuditEntry {
+                id: i as i64 + 1,
+                actor: "api-key:ops".to_string(),
+                request_id: Some(format!("req-{}", i)),
+                operation: "create_transaction".to_string(),
+                entity: "transaction".to_string(),
+                entity_id: Some(i as i64 + 1),
+                before: None,
+                after: Some(json!({"amount": amount, "from_account": 1, "to_account": 2})),
+                source_ip: Some("10.0.0.1".to_string()),
+                created_at: Some("2026-10-19T09:00:00.123456Z".parse().unwrap()),
+                prev_hash: prev_hash.clone(),
+                hash: String::new(),
+            };
+            entry.hash = entry_hash(&entry);
+            prev_hash = entry.hash.clone();
+            entries.push(entry);
+        }
+        entries
+    }
+
+    fn verify(entries: Vec<AuditEntry>) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        check_entries(&mut report, &mut GENESIS_HASH.to_string(), entries);
+        report
+    }
+
+    #[test]
+    fn same_entry_hashes_the_same() {
+        let (a, b) = (chain(&[100]), chain(&[100]));
+        assert_eq!(entry_hash(&a[0]), entry_hash(&b[0]));
+        assert_eq!(a[0].hash.len(), 64);
+        assert_ne!(entry_hash(&a[0]), entry_hash(&chain(&[101])[0]));
+    }
+
+    #[test]
+    fn canonical_json_ignores_key_order() {
+        let a = json!({"b": 1, "a": {"y": [1, {"q": null, "p": "x"}], "x": true}});
+        let b = json!({"a": {"x": true, "y": [1, {"p": "x", "q": null}]}, "b": 1});
+        assert_eq!(canonical_json(&a), canonical_json(&b));
+        assert_eq!(
+            canonical_json(&a),
+            r#"{"a":{"x":true,"y":[1,{"p":"x","q":null}]},"b":1}"#
+        );
+        // array order is significant
+        assert_ne!(
+            canonical_json(&json!([1, 2])),
+            canonical_json(&json!([2, 1]))
+        );
+    }
+
+    #[test]
+    fn verify_accepts_an_intact_chain() {
+        let report = verify(chain(&[100, 200, 300]));
+        assert_eq!(report.entries, 3);
+        assert!(report.broken_at.is_none());
+    }
+
+    #[test]
+    fn verify_detects_edited_entries() {
+        let mut entries = chain(&[100, 200, 300]);
+        entries[1].after = Some(json!({"amount": 9999, "from_account": 1, "to_account": 2}));
+        let report = verify(entries.clone());
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.broken_at.map(|(id, _)| id), Some(2));
+
+        // rehashing the edited entry breaks the link from the next one
+        entries[1].hash = entry_hash(&entries[1]);
+        let report = verify(entries);
+        assert_eq!(report.entries, 2);
+        let (id, reason) = report.broken_at.unwrap();
+        assert_eq!(id, 3);
+        assert!(reason.starts_with("prev_hash"), "{}", reason);
+    }
+
+    #[test]
+    fn verify_detects_removed_entries() {
+        let mut entries = chain(&[100, 200, 300]);
+        entries.remove(1);
+        let report = verify(entries);
+        assert_eq!(report.broken_at.map(|(id, _)| id), Some(3));
+    }
+}
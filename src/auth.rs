@@ -0,0 +1,303 @@
+// Request authentication. Clients send either an API key in the X-Api-Key
+// header or a JWT in the Authorization header (see jwt.rs). API keys are
+// stored as SHA-256 hashes in Postgres together with the scopes they grant.
+// Protected resources are wrapped with one of the require_* middleware
+// functions below, which reject requests with 401 (missing, unknown or
+// revoked credentials) or 403 (caller lacks the required scope).
+use crate::{
+    config::AuthConfig,
+    db,
+    jwt::JwtVerifier,
+    middleware::request_id,
+    model::{ApiKey, Status},
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next,
+    web, Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use deadpool_postgres::Pool;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+const API_KEY_PREFIX: &str = "pl_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // read accounts and transactions
+    Read,
+    CreateAccounts,
+    Transfer,
+    // every scope, including key management
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::CreateAccounts => "create_accounts",
+            Scope::Transfer => "transfer",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Scope::Read),
+            "create_accounts" => Ok(Scope::CreateAccounts),
+            "transfer" => Ok(Scope::Transfer),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!(
+                "unknown scope {} (expected read, create_accounts, transfer or admin)",
+                s
+            )),
+        }
+    }
+}
+
+// Principal is the authenticated caller, stored in the request extensions.
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    // ledger accounts the caller may act on, None when unrestricted
+    // (API keys and service tokens)
+    pub accounts: Option<Vec<i64>>,
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub fn owns(&self, account_id: i64) -> bool {
+        match &self.accounts {
+            Some(accounts) => accounts.contains(&account_id),
+            None => true,
+        }
+    }
+}
+
+// principal returns the authenticated caller, if authentication is enabled.
+pub fn principal(req: &HttpRequest) -> Option<Principal> {
+    req.extensions().get::<Principal>().cloned()
+}
+
+// owns_account reports whether the caller may act on the given account. Requests
+// are unrestricted when authentication is disabled.
+pub fn owns_account(req: &HttpRequest, account_id: i64) -> bool {
+    principal(req).is_none_or(|p| p.owns(account_id))
+}
+
+impl From<ApiKey> for Principal {
+    fn from(key: ApiKey) -> Self {
+        Principal {
+            name: format!("api-key:{}", key.name),
+            // unknown scopes (e.g. from a newer release) grant nothing
+            scopes: key.scopes.iter().filter_map(|s| s.parse().ok()).collect(),
+            accounts: None,
+        }
+    }
+}
+
+// generate_api_key returns a new random key and its display prefix.
+pub fn generate_api_key() -> (String, String) {
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let key = format!("{}{}", API_KEY_PREFIX, hex::encode(secret));
+    let prefix = key[..API_KEY_PREFIX.len() + 8].to_string();
+    (key, prefix)
+}
+
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub async fn require_read<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    authorize(Scope::Read, req, next).await
+}
+
+pub async fn require_create_accounts<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    authorize(Scope::CreateAccounts, req, next).await
+}
+
+pub async fn require_transfer<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    authorize(Scope::Transfer, req, next).await
+}
+
+pub async fn require_admin<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    authorize(Scope::Admin, req, next).await
+}
+
+async fn authorize<B: MessageBody>(
+    scope: Scope,
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let enabled = req
+        .app_data::<web::Data<AuthConfig>>()
+        .map(|config| config.enabled)
+        .unwrap_or(false);
+    if !enabled {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let principal = match authenticate_request(&req).await {
+        Ok(principal) => principal,
+        Err((status, message)) => return Ok(reject(req, status, &message)),
+    };
+
+    if !principal.has_scope(scope) {
+        let message = format!("Credentials are missing the {} scope", scope);
+        return Ok(reject(req, StatusCode::FORBIDDEN, &message));
+    }
+
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+// AuthFailure is a failed authenticate_request, kept in the request
+// extensions like the principal of a successful one.
+#[derive(Clone)]
+struct AuthFailure(StatusCode, String);
+
+// authenticate_request resolves the caller from the credentials of a request.
+// The outcome is kept in the request extensions, so that the credentials are
+// checked once even though both the rate limiter and the scope check need
+// them.
+pub async fn authenticate_request(req: &ServiceRequest) -> Result<Principal, (StatusCode, String)> {
+    if let Some(principal) = req.extensions().get::<Principal>() {
+        return Ok(principal.clone());
+    }
+    if let Some(AuthFailure(status, message)) = req.extensions().get::<AuthFailure>() {
+        return Err((*status, message.clone()));
+    }
+
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let authenticated = authenticate(
+        req.app_data::<web::Data<Pool>>().map(|pool| pool.get_ref()),
+        req.app_data::<web::Data<JwtVerifier>>()
+            .map(|verifier| verifier.get_ref()),
+        bearer,
+        api_key,
+    )
+    .await;
+    match &authenticated {
+        Ok(principal) => {
+            req.extensions_mut().insert(principal.clone());
+        }
+        Err((status, message)) => {
+            req.extensions_mut()
+                .insert(AuthFailure(*status, message.clone()));
+        }
+    }
+    authenticated
+}
+
+// authenticate resolves the caller from a JWT bearer token, when a verifier
+// is configured, or else from an API key. It is shared by the HTTP middleware
+// and the gRPC services; errors carry the HTTP status to answer with.
+pub async fn authenticate(
+    pool: Option<&Pool>,
+    verifier: Option<&JwtVerifier>,
+    bearer: Option<&str>,
+    api_key: Option<&str>,
+) -> Result<Principal, (StatusCode, String)> {
+    if let (Some(token), Some(verifier)) = (bearer, verifier) {
+        return verifier.verify(token.trim()).map_err(|err| {
+            (
+                StatusCode::UNAUTHORIZED,
+                format!("Invalid bearer token: {}", err),
+            )
+        });
+    }
+
+    let key = match api_key {
+        Some(key) if !key.is_empty() => key,
+        _ => {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "Missing API key or bearer token".to_string(),
+            ))
+        }
+    };
+
+    let pool = pool.ok_or((
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Database pool not configured".to_string(),
+    ))?;
+    let client = pool
+        .get()
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err.to_string()))?;
+
+    match db::authenticate_api_key(&client, &hash_api_key(key)).await {
+        Ok(Some(api_key)) => Ok(api_key.into()),
+        Ok(None) => Err((
+            StatusCode::UNAUTHORIZED,
+            "Invalid or revoked API key".to_string(),
+        )),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+// reject short-circuits the request with an error in the standard Status format.
+fn reject<B>(
+    req: ServiceRequest,
+    status: StatusCode,
+    message: &str,
+) -> ServiceResponse<EitherBody<B>> {
+    let response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: message.to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(req.request()),
+    };
+    let mut builder = HttpResponse::build(status);
+    if status == StatusCode::UNAUTHORIZED {
+        builder.insert_header((
+            header::WWW_AUTHENTICATE,
+            "Bearer, ApiKey header=\"X-Api-Key\"",
+        ));
+    }
+    req.into_response(builder.json(response))
+        .map_into_right_body()
+}
@@ -1,4 +1,9 @@
-use clap::{Parser, Subcommand};
+use crate::auth::Scope;
+use crate::config::LoadOptions;
+use crate::ledger_io::DataFormat;
+use crate::statement::StatementFormat;
+use chrono::{DateTime, Utc};
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Parser)]
 #[command(
@@ -21,16 +26,268 @@ pub enum Commands {
     /// Start the Actix Web server, connect to Postgres
     Run(RunArgs),
 
+    /// Inspect the service configuration
+    #[command(subcommand)]
+    Config(ConfigCommands),
+
+    /// Manage API keys
+    #[command(subcommand)]
+    Keys(KeysCommands),
+
+    /// Inspect the audit log
+    #[command(subcommand)]
+    Audit(AuditCommands),
+
+    /// Recompute account balances from the transaction history and report drift
+    Reconcile(ReconcileArgs),
+
+    /// Print the statement of an account for a period
+    Statement(StatementArgs),
+
+    /// Export all accounts and transactions to CSV or NDJSON files
+    Export(ExportArgs),
+
+    /// Import accounts and transactions written by export
+    Import(ImportArgs),
+
+    /// Work with webhook deliveries
+    #[command(subcommand)]
+    Webhooks(WebhooksCommands),
+
+    /// Print the OpenAPI specification of the HTTP API
+    Openapi,
+
     /// Print full version details
     Version,
 }
 
+#[derive(Subcommand)]
+pub enum KeysCommands {
+    /// Create an API key. The key is printed once and cannot be retrieved later
+    Create(KeysCreateArgs),
+
+    /// List API keys (without the keys themselves)
+    List(KeysListArgs),
+
+    /// Revoke an API key
+    Revoke(KeysRevokeArgs),
+}
+
 #[derive(Parser)]
-pub struct RunArgs {
+pub struct KeysCreateArgs {
+    #[arg(long, help = "Name identifying the key owner")]
+    pub name: String,
+
+    #[arg(
+        long = "scope",
+        required = true,
+        value_delimiter = ',',
+        help = "Scopes granted to the key: read, create_accounts, transfer, admin"
+    )]
+    pub scopes: Vec<Scope>,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Parser)]
+pub struct KeysListArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Parser)]
+pub struct KeysRevokeArgs {
+    #[arg(help = "Id of the key to revoke")]
+    pub id: i64,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Subcommand)]
+pub enum AuditCommands {
+    /// Check the audit log hash chain, failing if any entry was tampered with
+    Verify(AuditVerifyArgs),
+}
+
+#[derive(Parser)]
+pub struct AuditVerifyArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Subcommand)]
+pub enum WebhooksCommands {
+    /// Run a local receiver that verifies and prints webhook deliveries
+    Receive(WebhooksReceiveArgs),
+}
+
+#[derive(Parser)]
+pub struct WebhooksReceiveArgs {
+    #[arg(long, default_value = "127.0.0.1:9000", help = "Address to listen on")]
+    pub addr: String,
+
+    #[arg(long, help = "Signing secret of the subscription")]
+    pub secret: String,
+
+    #[arg(
+        long,
+        default_value_t = 200,
+        help = "Status to answer verified deliveries with, to exercise retries"
+    )]
+    pub status: u16,
+}
+
+#[derive(Parser)]
+pub struct ReconcileArgs {
+    #[arg(long, help = "Overwrite drifted balances with the computed value")]
+    pub repair: bool,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Parser)]
+pub struct StatementArgs {
+    #[arg(help = "Id of the account")]
+    pub account_id: i64,
+
+    #[arg(
+        long,
+        help = "Start of the period, RFC 3339 (default: start of the current month)"
+    )]
+    pub from: Option<DateTime<Utc>>,
+
+    #[arg(long, help = "End of the period, exclusive, RFC 3339 (default: now)")]
+    pub to: Option<DateTime<Utc>>,
+
+    #[arg(
+        long,
+        default_value = "text",
+        help = "Output format: json, csv or text"
+    )]
+    pub format: StatementFormat,
+
+    #[arg(long, help = "Write the statement to this file instead of stdout")]
+    pub output: Option<String>,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Parser)]
+pub struct ExportArgs {
+    #[arg(long, help = "Directory to write the data files and manifest to")]
+    pub dir: String,
+
+    #[arg(long, default_value = "csv", help = "Data format: csv or ndjson")]
+    pub format: DataFormat,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Parser)]
+pub struct ImportArgs {
+    #[arg(long, help = "Directory holding the data files and manifest")]
+    pub dir: String,
+
+    #[arg(long, help = "Data format: csv or ndjson (default: from the manifest)")]
+    pub format: Option<DataFormat>,
+
+    #[arg(
+        long,
+        default_value_t = 10000,
+        value_parser = clap::value_parser!(i64).range(1..),
+        help = "Rows inserted per committed batch"
+    )]
+    pub batch_size: i64,
+
     #[arg(
         long,
+        help = "Do not require balances to match the imported transactions"
+    )]
+    pub skip_balance_check: bool,
+
+    #[arg(long, help = "Validate the data files without importing them")]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print the effective configuration with secrets redacted
+    Print(ConfigArgs),
+}
+
+#[derive(Parser)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+// ConfigArgs selects the configuration file and holds the CLI flags that
+// take precedence over the file and PSQL_LEDGER_* environment variables.
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[arg(
+        long = "config",
         default_value = "config.json",
-        help = "Path to the configuration file"
+        help = "Path to the configuration file (.json, .toml or .yaml)"
     )]
-    pub config: String,
+    pub file: String,
+
+    #[arg(long, help = "Fail on a missing or invalid configuration file")]
+    pub strict: bool,
+
+    #[arg(long, help = "Log level filter (overrides config)")]
+    pub log_level: Option<String>,
+
+    #[arg(long, help = "Log format: text or json (overrides config)")]
+    pub log_format: Option<String>,
+
+    #[arg(long, help = "Address to bind the HTTP server to (overrides config)")]
+    pub server_addr: Option<String>,
+
+    #[arg(long, help = "Address to bind the gRPC server to (overrides config)")]
+    pub grpc_addr: Option<String>,
+
+    #[arg(long, help = "PostgreSQL host (overrides config)")]
+    pub pg_host: Option<String>,
+
+    #[arg(long, help = "PostgreSQL port (overrides config)")]
+    pub pg_port: Option<u16>,
+
+    #[arg(long, help = "PostgreSQL database name (overrides config)")]
+    pub pg_dbname: Option<String>,
+
+    #[arg(long, help = "PostgreSQL user (overrides config)")]
+    pub pg_user: Option<String>,
+}
+
+impl ConfigArgs {
+    pub fn load_options(&self) -> LoadOptions {
+        let flags = [
+            ("log_level", self.log_level.clone()),
+            ("log_format", self.log_format.clone()),
+            ("server_addr", self.server_addr.clone()),
+            ("grpc_addr", self.grpc_addr.clone()),
+            ("pg.host", self.pg_host.clone()),
+            ("pg.port", self.pg_port.map(|port| port.to_string())),
+            ("pg.dbname", self.pg_dbname.clone()),
+            ("pg.user", self.pg_user.clone()),
+        ];
+
+        LoadOptions {
+            file: Some(self.file.clone()),
+            strict: self.strict,
+            overrides: flags
+                .into_iter()
+                .filter_map(|(key, value)| value.map(|v| (key.to_string(), v)))
+                .collect(),
+        }
+    }
 }
@@ -1,7 +1,19 @@
 // client wrappers using Atix Web Client (awc)
-use crate::model::{Account, Health, Status, Transaction};
+use crate::auth::API_KEY_HEADER;
+use crate::model::{
+    Account, AccountBatchParams, AccountParams, BatchMode, BatchResult, Health, Status,
+    Transaction, TransactionBatchParams, TransactionParams,
+};
 use actix_web::Error;
-use awc::Client;
+use awc::{Client, ClientRequest};
+
+// with_api_key attaches the API key header when a key is supplied.
+fn with_api_key(request: ClientRequest, api_key: &Option<String>) -> ClientRequest {
+    match api_key {
+        Some(key) => request.insert_header((API_KEY_HEADER, key.as_str())),
+        None => request,
+    }
+}
 
 pub async fn status(server_addr: String) -> Result<Status, Error> {
     // server_addr string must be of the form <ip>:<port>
@@ -60,6 +72,7 @@ pub async fn health(server_addr: String) -> Result<Health, Error> {
 pub async fn create_account(
     server_addr: String,
     account_params: Account,
+    api_key: Option<String>,
 ) -> Result<Account, Error> {
     // server_addr string must be of the form <ip>:<port>
     let url = format!("http://{}/create-account", server_addr);
@@ -71,6 +84,8 @@ pub async fn create_account(
         email: account_params.email,
         balance: Default::default(),
         created_at: Default::default(),
+        metadata: account_params.metadata,
+        tags: account_params.tags,
     };
 
     let body_json = serde_json::to_string(&acc_pars).map_err(|e| {
@@ -82,8 +97,7 @@ pub async fn create_account(
 
     let client = Client::default();
 
-    let mut response = client
-        .post(&url)
+    let mut response = with_api_key(client.post(&url), &api_key)
         .send_json(&body_json) // Send JSON body
         .await
         .map_err(|e| {
@@ -111,6 +125,7 @@ pub async fn create_account(
 pub async fn create_transaction(
     server_addr: String,
     tx_params: Transaction,
+    api_key: Option<String>,
 ) -> Result<Transaction, Error> {
     // server_addr string must be of the form <ip>:<port>
     let url = format!("http://{}/create-tx", server_addr);
@@ -122,6 +137,11 @@ pub async fn create_transaction(
         to_account: tx_params.to_account,
         amount: tx_params.amount,
         created_at: Default::default(),
+        description: tx_params.description,
+        reference: tx_params.reference,
+        category: tx_params.category,
+        external_id: tx_params.external_id,
+        created_by: Default::default(),
     };
 
     let body_json = serde_json::to_string(&t_pars).map_err(|e| {
@@ -133,8 +153,7 @@ pub async fn create_transaction(
 
     let client = Client::default();
 
-    let mut response = client
-        .post(&url)
+    let mut response = with_api_key(client.post(&url), &api_key)
         .send_json(&body_json) // Send JSON body
         .await
         .map_err(|e| {
@@ -159,14 +178,122 @@ pub async fn create_transaction(
     }
 }
 
-pub async fn get_accounts(server_addr: String) -> Result<Vec<Account>, Error> {
+// create_accounts posts a batch of accounts. A rejected atomic batch is also
+// returned as a BatchResult, so that callers can see which items failed.
+pub async fn create_accounts(
+    server_addr: String,
+    accounts: Vec<Account>,
+    mode: BatchMode,
+    api_key: Option<String>,
+) -> Result<BatchResult<Account>, Error> {
+    // server_addr string must be of the form <ip>:<port>
+    let url = format!("http://{}/accounts/batch", server_addr);
+
+    // sanitize before sending
+    let batch = AccountBatchParams {
+        mode,
+        accounts: accounts
+            .into_iter()
+            .map(|account| AccountParams {
+                id: Default::default(),
+                username: account.username,
+                email: account.email,
+                balance: Default::default(),
+                metadata: account.metadata,
+                tags: account.tags,
+            })
+            .collect(),
+    };
+
+    let client = Client::default();
+
+    let mut response = with_api_key(client.post(&url), &api_key)
+        .send_json(&batch)
+        .await
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Server response error: {}", e))
+        })?;
+
+    // atomic batches that were rolled back answer with a client error status
+    // and the per item results
+    let status = response.status();
+    if status.is_success() || status.is_client_error() {
+        if let Ok(result) = response.json::<BatchResult<Account>>().await {
+            return Ok(result);
+        }
+    }
+    Err(actix_web::error::ErrorInternalServerError(format!(
+        "Got error response code: {}",
+        status.as_str(),
+    )))
+}
+
+// create_transactions posts a batch of transfers. A rejected atomic batch is
+// also returned as a BatchResult, so that callers can see which items failed.
+pub async fn create_transactions(
+    server_addr: String,
+    transfers: Vec<Transaction>,
+    mode: BatchMode,
+    api_key: Option<String>,
+) -> Result<BatchResult<Transaction>, Error> {
+    // server_addr string must be of the form <ip>:<port>
+    let url = format!("http://{}/transactions/batch", server_addr);
+
+    // sanitize before sending
+    let batch = TransactionBatchParams {
+        mode,
+        transfers: transfers
+            .into_iter()
+            .map(|tx| TransactionParams {
+                id: Default::default(),
+                from_account: tx.from_account,
+                to_account: tx.to_account,
+                amount: tx.amount,
+                description: tx.description,
+                reference: tx.reference,
+                category: tx.category,
+                external_id: tx.external_id,
+            })
+            .collect(),
+    };
+
+    let client = Client::default();
+
+    let mut response = with_api_key(client.post(&url), &api_key)
+        .send_json(&batch)
+        .await
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Server response error: {}", e))
+        })?;
+
+    // atomic batches that were rolled back answer with a client error status
+    // and the per item results
+    let status = response.status();
+    if status.is_success() || status.is_client_error() {
+        if let Ok(result) = response.json::<BatchResult<Transaction>>().await {
+            return Ok(result);
+        }
+    }
+    Err(actix_web::error::ErrorInternalServerError(format!(
+        "Got error response code: {}",
+        status.as_str(),
+    )))
+}
+
+pub async fn get_accounts(
+    server_addr: String,
+    api_key: Option<String>,
+) -> Result<Vec<Account>, Error> {
     // server_addr string must be of the form <ip>:<port>
     let url = format!("http://{}/accounts", server_addr);
 
     let client = Client::default();
-    let mut response = client.get(&url).send().await.map_err(|e| {
-        actix_web::error::ErrorInternalServerError(format!("Server response error: {}", e))
-    })?;
+    let mut response = with_api_key(client.get(&url), &api_key)
+        .send()
+        .await
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Server response error: {}", e))
+        })?;
 
     // Check if the request was successful
     if response.status().is_success() {
@@ -186,14 +313,20 @@ pub async fn get_accounts(server_addr: String) -> Result<Vec<Account>, Error> {
     }
 }
 
-pub async fn get_transactions(server_addr: String) -> Result<Vec<Transaction>, Error> {
+pub async fn get_transactions(
+    server_addr: String,
+    api_key: Option<String>,
+) -> Result<Vec<Transaction>, Error> {
     // server_addr string must be of the form <ip>:<port>
     let url = format!("http://{}/transactions", server_addr);
 
     let client = Client::default();
-    let mut response = client.get(&url).send().await.map_err(|e| {
-        actix_web::error::ErrorInternalServerError(format!("Server response error: {}", e))
-    })?;
+    let mut response = with_api_key(client.get(&url), &api_key)
+        .send()
+        .await
+        .map_err(|e| {
+            actix_web::error::ErrorInternalServerError(format!("Server response error: {}", e))
+        })?;
 
     // Check if the request was successful
     if response.status().is_success() {
@@ -211,4 +344,4 @@ pub async fn get_transactions(server_addr: String) -> Result<Vec<Transaction>, E
             response.status().as_str(),
         )))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,331 @@
+// Implementations of the CLI subcommands that do not start the server.
+use crate::audit::{self, AuditContext};
+use crate::auth::{generate_api_key, hash_api_key};
+use crate::cli::{
+    AuditCommands, ConfigArgs, ExportArgs, ImportArgs, KeysCommands, ReconcileArgs, StatementArgs,
+};
+use crate::config::{Config, LoadedConfig};
+use crate::db;
+use crate::errors::MyError;
+use crate::ledger_io;
+use crate::model::ApiKey;
+use crate::reconcile;
+use crate::server::create_pool;
+use crate::statement;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use tokio_postgres::error::SqlState;
+
+// load_config loads the effective configuration, reporting warnings on stderr.
+fn load_config(args: &ConfigArgs) -> std::io::Result<Config> {
+    let LoadedConfig {
+        config, warnings, ..
+    } = Config::load(&args.load_options()).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid configuration: {}", err),
+        )
+    })?;
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(config)
+}
+
+async fn connect(args: &ConfigArgs) -> std::io::Result<deadpool_postgres::Client> {
+    let config = load_config(args)?;
+    create_pool(&config)?
+        .get()
+        .await
+        .map_err(|err| Error::new(ErrorKind::ConnectionRefused, err))
+}
+
+fn db_error(err: impl std::fmt::Display) -> Error {
+    Error::other(err.to_string())
+}
+
+pub fn print_config(args: &ConfigArgs) -> std::io::Result<()> {
+    let loaded = Config::load(&args.load_options()).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid configuration: {}", err),
+        )
+    })?;
+    for warning in &loaded.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    eprintln!("Configuration sources: {}", loaded.sources.join(" < "));
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&loaded.config.redacted())?
+    );
+    Ok(())
+}
+
+pub async fn keys(command: KeysCommands) -> std::io::Result<()> {
+    match command {
+        KeysCommands::Create(args) => {
+            let mut client = connect(&args.config).await?;
+            let db_tx = client.transaction().await.map_err(db_error)?;
+            let (key, prefix) = generate_api_key();
+            let scopes: Vec<String> = args.scopes.iter().map(|s| s.to_string()).collect();
+            let api_key =
+                db::create_api_key(&db_tx, &args.name, &prefix, &hash_api_key(&key), &scopes)
+                    .await
+                    .map_err(|err| match &err {
+                        MyError::PGError(pg_err)
+                            if pg_err.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+                        {
+                            Error::new(
+                                ErrorKind::AlreadyExists,
+                                format!("an active API key is already named {}", args.name),
+                            )
+                        }
+                        _ => db_error(err),
+                    })?;
+            audit::record(
+                &db_tx,
+                &AuditContext::cli(),
+                "create_api_key",
+                "api_key",
+                Some(api_key.id),
+                None,
+                Some(&api_key),
+            )
+            .await
+            .map_err(db_error)?;
+            db_tx.commit().await.map_err(db_error)?;
+            println!("Created API key {} ({})", api_key.id, api_key.name);
+            println!("Scopes: {}", api_key.scopes.join(", "));
+            println!("Key: {}", key);
+            eprintln!("Store this key now, it cannot be shown again.");
+        }
+        KeysCommands::List(args) => {
+            let client = connect(&args.config).await?;
+            let keys = db::get_api_keys(&client).await.map_err(db_error)?;
+            println!(
+                "{:<6} {:<20} {:<12} {:<36} {:<10} LAST USED",
+                "ID", "NAME", "PREFIX", "SCOPES", "STATUS"
+            );
+            for key in keys {
+                println!(
+                    "{:<6} {:<20} {:<12} {:<36} {:<10} {}",
+                    key.id,
+                    key.name,
+                    key.key_prefix,
+                    key.scopes.join(","),
+                    if key.revoked_at.is_some() {
+                        "revoked"
+                    } else {
+                        "active"
+                    },
+                    key.last_used_at
+                        .map(|t| t.to_rfc3339())
+                        .unwrap_or_else(|| "never".to_string())
+                );
+            }
+        }
+        KeysCommands::Revoke(args) => {
+            let mut client = connect(&args.config).await?;
+            let db_tx = client.transaction().await.map_err(db_error)?;
+            let key = db::revoke_api_key(&db_tx, args.id).await.map_err(|err| {
+                if err.to_string() == "NotFound" {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("no active API key with id {}", args.id),
+                    )
+                } else {
+                    db_error(err)
+                }
+            })?;
+            // only active keys can be revoked
+            let before = ApiKey {
+                revoked_at: None,
+                ..key.clone()
+            };
+            audit::record(
+                &db_tx,
+                &AuditContext::cli(),
+                "revoke_api_key",
+                "api_key",
+                Some(key.id),
+                Some(&before),
+                Some(&key),
+            )
+            .await
+            .map_err(db_error)?;
+            db_tx.commit().await.map_err(db_error)?;
+            println!("Revoked API key {} ({})", key.id, key.name);
+        }
+    }
+    Ok(())
+}
+
+pub async fn audit(command: AuditCommands) -> std::io::Result<()> {
+    match command {
+        AuditCommands::Verify(args) => {
+            let client = connect(&args.config).await?;
+            let report = audit::verify(&client).await.map_err(db_error)?;
+            if let Some((id, reason)) = report.broken_at {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "audit chain broken at entry {} after {} valid entries: {}",
+                        id, report.entries, reason
+                    ),
+                ));
+            }
+            println!("Audit chain intact ({} entries verified)", report.entries);
+        }
+    }
+    Ok(())
+}
+
+pub async fn reconcile(args: &ReconcileArgs) -> std::io::Result<()> {
+    let mut client = connect(&args.config).await?;
+    let report = reconcile::run(&mut client, args.repair, &AuditContext::cli())
+        .await
+        .map_err(db_error)?;
+
+    if !report.discrepancies.is_empty() {
+        println!(
+            "{:<10} {:>16} {:>16} {:>16}",
+            "ACCOUNT", "RECORDED", "EXPECTED", "DIFFERENCE"
+        );
+        for d in &report.discrepancies {
+            println!(
+                "{:<10} {:>16} {:>16} {:>16}",
+                d.account_id, d.recorded, d.expected, d.difference
+            );
+        }
+    }
+    println!(
+        "Checked {} accounts in {:.1}ms: {} discrepancies, {} repaired",
+        report.accounts_checked,
+        report.duration_ms,
+        report.discrepancies.len(),
+        report.repaired
+    );
+
+    // a non-zero exit lets scheduled runs alert on drift that was left in place
+    let unrepaired = report.discrepancies.len() as i64 - report.repaired;
+    if unrepaired > 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} account balances do not match their transactions",
+                unrepaired
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn statement(args: &StatementArgs) -> std::io::Result<()> {
+    let (from, to) = statement::period(args.from, args.to)
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    let client = connect(&args.config).await?;
+    let statement = statement::build(&client, args.account_id, from, to)
+        .await
+        .map_err(|err| {
+            if err.to_string() == "NotFound" {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("no account with id {}", args.account_id),
+                )
+            } else {
+                db_error(err)
+            }
+        })?;
+
+    let rendered = statement::render(&statement, args.format);
+    match &args.output {
+        Some(file) => {
+            std::fs::write(file, rendered)?;
+            eprintln!("Statement written to {}", file);
+        }
+        None => print!("{}", rendered),
+    }
+    Ok(())
+}
+
+pub async fn export(args: &ExportArgs) -> std::io::Result<()> {
+    let mut client = connect(&args.config).await?;
+    let manifest = ledger_io::export(&mut client, Path::new(&args.dir), args.format).await?;
+    println!(
+        "Exported {} accounts and {} transactions to {}",
+        manifest.accounts, manifest.transactions, args.dir
+    );
+    Ok(())
+}
+
+pub async fn import(args: &ImportArgs) -> std::io::Result<()> {
+    let dir = Path::new(&args.dir);
+    let manifest = ledger_io::read_manifest(dir)?;
+    let format = match (args.format, &manifest) {
+        (Some(format), _) => format,
+        (None, Some(manifest)) => manifest.format,
+        (None, None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "no {} in {}, pass --format",
+                    ledger_io::MANIFEST_FILE,
+                    args.dir
+                ),
+            ))
+        }
+    };
+    if let Some(manifest) = &manifest {
+        if manifest.schema_version != env!("LATEST_MIGRATION") {
+            eprintln!(
+                "warning: export was taken at schema version {}, this build expects {}",
+                manifest.schema_version,
+                env!("LATEST_MIGRATION")
+            );
+        }
+    }
+
+    let mut client = connect(&args.config).await?;
+    let (accounts, transactions) = ledger_io::stage(&client, dir, format).await?;
+    if let Some(manifest) = &manifest {
+        if manifest.accounts as u64 != accounts || manifest.transactions as u64 != transactions {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "manifest lists {} accounts and {} transactions but the files hold {} and {}",
+                    manifest.accounts, manifest.transactions, accounts, transactions
+                ),
+            ));
+        }
+    }
+    eprintln!(
+        "Staged {} accounts and {} transactions from {}",
+        accounts, transactions, args.dir
+    );
+
+    let problems = ledger_io::validate(&client, !args.skip_balance_check).await?;
+    if !problems.is_empty() {
+        for problem in &problems {
+            eprintln!("{}", problem);
+        }
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("validation failed, {} problems found", problems.len()),
+        ));
+    }
+    if args.dry_run {
+        println!("Validation passed, nothing imported (dry run)");
+        return Ok(());
+    }
+
+    let report = ledger_io::load(&mut client, &args.dir, args.batch_size).await?;
+    println!(
+        "Imported {} accounts and {} transactions ({} and {} already present)",
+        report.accounts_inserted,
+        report.transactions_inserted,
+        report.accounts_skipped,
+        report.transactions_skipped
+    );
+    Ok(())
+}
@@ -1,24 +1,500 @@
-// Service configuration definition
-// with methods to read from file or environment
-// variables
+// Service configuration definition. The effective configuration is
+// layered, with later layers taking precedence:
+//
+//   defaults < config file (.json/.toml/.yaml) < PSQL_LEDGER_* env vars < CLI flags
+//
+// Nested keys are separated by a double underscore in environment variables,
+// e.g. PSQL_LEDGER_PG__HOST or PSQL_LEDGER_HEALTH__DB_TIMEOUT_MS.
+use ::config::{ConfigError, Environment, File};
 use deadpool_postgres::Config as PgConfig;
 use deadpool_postgres::SslMode;
-use serde::Deserialize;
-use std::fs::File;
-use std::io::{BufReader, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
 
-#[derive(Debug, Default, Deserialize)]
+pub const ENV_PREFIX: &str = "PSQL_LEDGER";
+
+// keys (dotted paths) whose values are hidden when the config is printed
+const SECRET_KEYS: &[&str] = &["pg.password", "auth.jwt.secret"];
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Config {
     pub log_level: String,
+    #[serde(default)]
+    pub log_format: LogFormat,
     pub server_addr: String,
+    // serve the gRPC API on this address when set
+    #[serde(default)]
+    pub grpc_addr: Option<String>,
+    // seconds to wait for in-flight requests and background tasks on shutdown
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    // seconds to keep serving with a failing health check before the
+    // listener is closed, giving load balancers time to stop routing traffic
+    #[serde(default)]
+    pub shutdown_grace_secs: u64,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub reconcile: ReconcileConfig,
+    #[serde(default)]
+    pub snapshots: SnapshotConfig,
+    #[serde(default)]
+    pub batch: BatchConfig,
+    #[serde(default)]
+    pub accounts: AccountsConfig,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+    #[serde(default)]
+    pub webhooks: WebhookConfig,
+    #[serde(default)]
+    pub outbox: OutboxConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub graphql: GraphqlConfig,
+    // serve HTTPS instead of plain HTTP when set
+    #[serde(default)]
+    pub tls: Option<ServerTlsConfig>,
     pub pg: PgConfig,
+    // certificate verification for PostgreSQL connections (used unless pg.ssl_mode is Disable)
+    #[serde(default)]
+    pub pg_tls: PgTlsConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    // require credentials (X-Api-Key header or JWT bearer token) on every ledger route
+    pub enabled: bool,
+    // accept JWT bearer tokens when set
+    pub jwt: Option<JwtConfig>,
+    // serve /metrics without credentials, e.g. to a scraper on a private network
+    pub public_metrics: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JwtConfig {
+    pub algorithm: JwtAlgorithm,
+    // shared secret for HS256
+    #[serde(default)]
+    pub secret: Option<String>,
+    // local JWKS file holding the RS256 public keys
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    // allowed clock skew when checking exp/nbf
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
+    // claim listing the ledger account ids owned by the caller
+    #[serde(default = "default_accounts_claim")]
+    pub accounts_claim: String,
+    // claim holding the caller role(s)
+    #[serde(default = "default_role_claim")]
+    pub role_claim: String,
+    // tokens with this role are service tokens with access to every account
+    #[serde(default = "default_service_role")]
+    pub service_role: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum JwtAlgorithm {
+    #[serde(rename = "HS256")]
+    Hs256,
+    #[serde(rename = "RS256")]
+    Rs256,
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+fn default_accounts_claim() -> String {
+    "accounts".to_string()
+}
+
+fn default_role_claim() -> String {
+    "role".to_string()
+}
+
+fn default_service_role() -> String {
+    "service".to_string()
+}
+
+// RateLimitConfig holds the per client request limits and the per account
+// transfer velocity limits.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // apply token bucket limits to ledger routes
+    pub enabled: bool,
+    // bucket used for routes without their own entry in routes
+    pub default: BucketConfig,
+    // per route buckets keyed by route path without the leading slash, e.g.
+    // "create-tx" (the config crate does not accept "/" in keys)
+    pub routes: HashMap<String, BucketConfig>,
+    // limits on transfers out of a single account, enforced when set
+    pub velocity: Option<VelocityConfig>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            default: BucketConfig {
+                burst: 100,
+                per_second: 50.0,
+            },
+            routes: HashMap::from([(
+                "create-tx".to_string(),
+                BucketConfig {
+                    burst: 20,
+                    per_second: 5.0,
+                },
+            )]),
+            velocity: None,
+        }
+    }
+}
+
+// BucketConfig describes a token bucket: up to burst requests at once,
+// refilled at per_second requests per second.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct BucketConfig {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VelocityConfig {
+    // length of the rolling window
+    pub window_secs: u64,
+    // maximum number of transfers out of an account within the window
+    #[serde(default)]
+    pub max_transfers: Option<i64>,
+    // maximum total amount transferred out of an account within the window
+    #[serde(default)]
+    pub max_amount: Option<i64>,
+}
+
+// ReconcileConfig schedules the balance reconciliation job in the server.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ReconcileConfig {
+    // seconds between runs, 0 disables the scheduled job
+    pub interval_secs: u64,
+    // overwrite drifted balances with the value computed from the transactions
+    pub repair: bool,
+}
+
+// SnapshotConfig schedules the balance snapshots used to speed up point-in-time
+// balance queries.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SnapshotConfig {
+    // seconds between snapshots, 0 disables them
+    pub interval_secs: u64,
+    // snapshots are taken this far in the past so that transfers still being
+    // committed are not missed
+    pub settle_secs: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        SnapshotConfig {
+            interval_secs: 3600,
+            settle_secs: 60,
+        }
+    }
+}
+
+// BatchConfig limits the size of batch requests.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BatchConfig {
+    // largest number of items accepted in a single batch request
+    pub max_items: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig { max_items: 1000 }
+    }
+}
+
+// AccountsConfig limits the metadata and tags callers attach to accounts.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AccountsConfig {
+    // largest metadata object accepted, in bytes of compact JSON
+    pub max_metadata_bytes: usize,
+    // most tags on one account
+    pub max_tags: usize,
+    // longest tag accepted, in characters
+    pub max_tag_len: usize,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        AccountsConfig {
+            max_metadata_bytes: 4096,
+            max_tags: 32,
+            max_tag_len: 64,
+        }
+    }
+}
+
+// SchedulerConfig controls the worker executing scheduled transfers.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    // seconds between polls for due transfers, 0 disables the worker
+    pub interval_secs: u64,
+    // most transfers executed per poll
+    pub batch_size: u32,
+    // failed runs are retried until this many attempts were made, then the
+    // schedule is marked failed
+    pub max_attempts: i32,
+    // delay before the first retry, doubled after every further failure
+    pub retry_delay_secs: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            interval_secs: 10,
+            batch_size: 100,
+            max_attempts: 5,
+            retry_delay_secs: 60,
+        }
+    }
+}
+
+// WebhookConfig controls the delivery of webhook events.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    // seconds between polls for pending deliveries, 0 disables delivery
+    pub interval_secs: u64,
+    // most deliveries sent concurrently per poll
+    pub batch_size: i64,
+    // a delivery is marked failed after this many attempts
+    pub max_attempts: i32,
+    // delay before the first retry, doubled after every further failure
+    pub retry_delay_secs: u64,
+    // seconds to wait for the receiver to respond
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            interval_secs: 5,
+            batch_size: 50,
+            max_attempts: 8,
+            retry_delay_secs: 30,
+            timeout_secs: 10,
+        }
+    }
+}
+
+// OutboxConfig controls the relay publishing outbox events. Events are kept
+// until a sink is configured.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct OutboxConfig {
+    // seconds between polls for undelivered events, 0 disables the relay and
+    // events are kept until it is enabled
+    pub interval_secs: u64,
+    // most events read per poll
+    pub batch_size: i64,
+    // every event is published to all sinks, in order
+    pub sinks: Vec<OutboxSinkConfig>,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            interval_secs: 1,
+            batch_size: 100,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutboxSinkConfig {
+    // one JSON line per event on standard output
+    Stdout,
+    // one JSON line per event appended to path
+    File {
+        path: String,
+    },
+    // one POST per event, any 2xx answer acknowledges it
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+        #[serde(default = "default_sink_timeout_secs")]
+        timeout_secs: u64,
+    },
+}
+
+fn default_sink_timeout_secs() -> u64 {
+    10
+}
+
+// EventsConfig controls the live event streams of /events and /events/ws.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct EventsConfig {
+    // seconds between keepalives sent on idle streams
+    pub keepalive_secs: u64,
+    // events buffered per stream; a client falling further behind is
+    // disconnected and resumes from its last event id
+    pub buffer: usize,
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            keepalive_secs: 15,
+            buffer: 1024,
+        }
+    }
+}
+
+// GraphqlConfig bounds the queries accepted by /graphql.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct GraphqlConfig {
+    // items returned by a connection when first is not given
+    pub default_page_size: i32,
+    // largest first accepted on a connection
+    pub max_page_size: i32,
+    // deepest nesting of fields in a query
+    pub max_depth: usize,
+    // most fields resolved by a query, counting each field once
+    pub max_complexity: usize,
+}
+
+impl Default for GraphqlConfig {
+    fn default() -> Self {
+        GraphqlConfig {
+            default_page_size: 20,
+            max_page_size: 100,
+            max_depth: 10,
+            max_complexity: 500,
+        }
+    }
+}
+
+// ServerTlsConfig holds the PEM files used by the HTTPS listener.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerTlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    // CA bundle used to verify client certificates, enables mutual TLS
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+    // reject clients that do not present a certificate (only with client_ca_file)
+    #[serde(default = "default_require_client_cert")]
+    pub require_client_cert: bool,
+}
+
+fn default_require_client_cert() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PgTlsConfig {
+    // CA bundle trusted for the PostgreSQL server certificate, defaults to the system roots
+    pub root_cert_file: Option<String>,
+    pub verify: PgTlsVerify,
+}
+
+// PgTlsVerify mirrors libpq: `none` only encrypts, `ca` checks the certificate
+// chain (verify-ca) and `full` also checks the host name (verify-full).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PgTlsVerify {
+    None,
+    Ca,
+    #[default]
+    Full,
+}
+
+// HealthConfig holds the thresholds used by the readiness probe.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    // maximum time allowed to acquire a connection and ping the database
+    pub db_timeout_ms: u64,
+    // a ping slower than this fails the readiness check
+    pub db_latency_threshold_ms: u64,
+    // fraction of the pool in use (0.0 - 1.0) at which readiness fails
+    pub pool_saturation_threshold: f64,
+    // fail readiness when the database schema is behind the shipped migrations
+    pub check_migrations: bool,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            db_timeout_ms: 2000,
+            db_latency_threshold_ms: 500,
+            pool_saturation_threshold: 0.9,
+            check_migrations: true,
+        }
+    }
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+// LogFormat selects how log records are rendered: human readable
+// text lines (default) or one JSON object per line.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
 }
 
 pub fn default_config() -> Config {
     let mut cfg = Config {
         log_level: "info".to_string(),
+        log_format: LogFormat::Text,
         server_addr: "0.0.0.0:8080".to_string(),
+        grpc_addr: None,
+        shutdown_timeout_secs: default_shutdown_timeout_secs(),
+        shutdown_grace_secs: 0,
+        health: HealthConfig::default(),
+        auth: AuthConfig::default(),
+        rate_limit: RateLimitConfig::default(),
+        reconcile: ReconcileConfig::default(),
+        snapshots: SnapshotConfig::default(),
+        batch: BatchConfig::default(),
+        accounts: AccountsConfig::default(),
+        scheduler: SchedulerConfig::default(),
+        webhooks: WebhookConfig::default(),
+        outbox: OutboxConfig::default(),
+        events: EventsConfig::default(),
+        graphql: GraphqlConfig::default(),
+        tls: None,
         pg: PgConfig::default(),
+        pg_tls: PgTlsConfig::default(),
     };
 
     let default_host = "0.0.0.0".to_string();
@@ -39,20 +515,254 @@ pub fn default_config() -> Config {
     cfg
 }
 
-#[allow(dead_code)]
+// LoadOptions describes where the configuration is read from.
+#[derive(Debug, Default)]
+pub struct LoadOptions {
+    // path to the config file, the format is taken from the file extension
+    pub file: Option<String>,
+    // fail instead of falling back to defaults when the file is missing or invalid
+    pub strict: bool,
+    // highest precedence overrides (dotted key, value), typically from CLI flags
+    pub overrides: Vec<(String, String)>,
+}
+
+// LoadedConfig is the effective configuration together with the layers it was
+// built from and any problems that were tolerated while loading it. Warnings
+// are returned rather than logged as the logger is configured from the result.
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub sources: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
 impl Config {
-    // read config from .json file
-    pub fn from_file(filename: &str) -> Result<Self, Error> {
-        let file = File::open(filename)?;
-        let reader = BufReader::new(file);
+    // load builds the effective configuration from all layers.
+    pub fn load(options: &LoadOptions) -> Result<LoadedConfig, ConfigError> {
+        let mut sources = vec!["defaults".to_string()];
+        let mut warnings = Vec::new();
+
+        let mut builder =
+            ::config::Config::builder().add_source(::config::Config::try_from(&default_config())?);
+
+        if let Some(file) = &options.file {
+            match check_file(file) {
+                Ok(()) => {
+                    builder = builder.add_source(File::from(Path::new(file)));
+                    sources.push(format!("file: {}", file));
+                }
+                Err(err) if options.strict => return Err(err),
+                Err(err) => warnings.push(format!("Ignoring configuration file: {}", err)),
+            }
+        }
+
+        builder = builder.add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator("__")
+                .try_parsing(true),
+        );
+        sources.push(format!("environment: {}_*", ENV_PREFIX));
+
+        if !options.overrides.is_empty() {
+            for (key, value) in &options.overrides {
+                builder = builder.set_override(key.as_str(), value.as_str())?;
+            }
+            sources.push("command line flags".to_string());
+        }
+
+        let config: Config = builder.build()?.try_deserialize()?;
+
+        Ok(LoadedConfig {
+            config,
+            sources,
+            warnings,
+        })
+    }
+
+    // redacted returns the configuration as JSON with secret values hidden.
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        for key in SECRET_KEYS {
+            let pointer = format!("/{}", key.replace('.', "/"));
+            if let Some(secret) = value.pointer_mut(&pointer) {
+                if !secret.is_null() {
+                    *secret = serde_json::Value::from("<redacted>");
+                }
+            }
+        }
+        // HTTP sink headers and URLs usually carry credentials
+        if let Some(sinks) = value
+            .pointer_mut("/outbox/sinks")
+            .and_then(|v| v.as_array_mut())
+        {
+            for sink in sinks.iter_mut() {
+                if let Some(headers) = sink.get_mut("headers").and_then(|v| v.as_object_mut()) {
+                    headers
+                        .values_mut()
+                        .for_each(|v| *v = serde_json::Value::from("<redacted>"));
+                }
+                if let Some(url) = sink.get_mut("url") {
+                    *url = serde_json::Value::from(redact_url(url.as_str().unwrap_or_default()));
+                }
+            }
+        }
+        value
+    }
+}
+
+// redact_url hides the credentials a URL may carry in its user info or its
+// query string.
+pub fn redact_url(url: &str) -> String {
+    let mut redacted = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let authority = &rest[..rest.find(['/', '?', '#']).unwrap_or(rest.len())];
+            match authority.rfind('@') {
+                Some(at) => format!("{}://<redacted>@{}", scheme, &rest[at + 1..]),
+                None => url.to_string(),
+            }
+        }
+        None => url.to_string(),
+    };
+    if let Some(query) = redacted.find('?') {
+        redacted.truncate(query + 1);
+        redacted.push_str("<redacted>");
+    }
+    redacted
+}
 
-        let config: Config = serde_json::from_reader(reader)?;
+// check_file verifies that a config file exists and parses before it is layered.
+fn check_file(file: &str) -> Result<(), ConfigError> {
+    if !Path::new(file).is_file() {
+        return Err(ConfigError::Message(format!("{} not found", file)));
+    }
+    ::config::Config::builder()
+        .add_source(File::from(Path::new(file)))
+        .build()
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{default_config, redact_url, Config, LoadOptions, OutboxSinkConfig};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    // write_file stores a config file under a name unique to the test.
+    fn write_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "psql-ledger-config-{}-{}",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn layers_apply_in_order_of_precedence() {
+        // every layer sets the keys of the layers below it
+        let file = write_file(
+            "precedence.json",
+            r#"{"log_level": "debug", "server_addr": "127.0.0.1:1000", "shutdown_grace_secs": 1}"#,
+        );
+        std::env::set_var("PSQL_LEDGER_SERVER_ADDR", "127.0.0.1:2000");
+        std::env::set_var("PSQL_LEDGER_SHUTDOWN_GRACE_SECS", "2");
+        let loaded = Config::load(&LoadOptions {
+            file: Some(file),
+            strict: true,
+            overrides: vec![("shutdown_grace_secs".to_string(), "3".to_string())],
+        });
+        std::env::remove_var("PSQL_LEDGER_SERVER_ADDR");
+        std::env::remove_var("PSQL_LEDGER_SHUTDOWN_GRACE_SECS");
+        let loaded = loaded.unwrap();
 
-        Ok(config)
+        let defaults = default_config();
+        assert_eq!(
+            loaded.config.shutdown_timeout_secs,
+            defaults.shutdown_timeout_secs
+        );
+        assert_eq!(loaded.config.log_level, "debug");
+        assert_eq!(loaded.config.server_addr, "127.0.0.1:2000");
+        assert_eq!(loaded.config.shutdown_grace_secs, 3);
+        assert_eq!(loaded.sources.len(), 4);
+        assert!(loaded.warnings.is_empty());
     }
 
-    // read config from ENV
-    pub fn from_env() -> Result<Self, envy::Error> {
-        envy::from_env::<Config>()
+    #[test]
+    fn missing_file_falls_back_unless_strict() {
+        let options = LoadOptions {
+            file: Some("/nonexistent/config.json".to_string()),
+            ..LoadOptions::default()
+        };
+        let loaded = Config::load(&options).unwrap();
+        assert_eq!(loaded.config.log_level, default_config().log_level);
+        assert_eq!(loaded.warnings.len(), 1);
+        assert!(!loaded.sources.iter().any(|s| s.starts_with("file")));
+
+        let strict = LoadOptions {
+            strict: true,
+            ..options
+        };
+        assert!(Config::load(&strict).is_err());
+    }
+
+    #[test]
+    fn redacted_hides_secrets() {
+        let mut config = default_config();
+        config.auth.jwt = Some(
+            serde_json::from_value(json!({"algorithm": "HS256", "secret": "s3cret"})).unwrap(),
+        );
+        let redacted = config.redacted();
+        assert_eq!(redacted["pg"]["password"], "<redacted>");
+        assert_eq!(redacted["auth"]["jwt"]["secret"], "<redacted>");
+        assert_eq!(redacted["pg"]["user"], "root");
+        assert!(!redacted.to_string().contains("s3cret"));
+
+        // unset secrets stay unset
+        config.pg.password = None;
+        config.auth.jwt = None;
+        let redacted = config.redacted();
+        assert!(redacted["pg"]["password"].is_null());
+        assert!(redacted["auth"]["jwt"].is_null());
+    }
+
+    #[test]
+    fn redact_url_hides_user_info_and_query() {
+        assert_eq!(
+            redact_url("https://user:pw@hooks.example.com/in?token=abc"),
+            "https://<redacted>@hooks.example.com/in?<redacted>"
+        );
+        assert_eq!(
+            redact_url("http://hooks.example.com?key=a@b"),
+            "http://hooks.example.com?<redacted>"
+        );
+        assert_eq!(
+            redact_url("https://hooks.example.com/in/a@b"),
+            "https://hooks.example.com/in/a@b"
+        );
+    }
+
+    #[test]
+    fn redacted_hides_outbox_sink_credentials() {
+        let mut config = default_config();
+        config.outbox.sinks = vec![
+            OutboxSinkConfig::Stdout,
+            OutboxSinkConfig::Http {
+                url: "https://user:pw@hooks.example.com/in".to_string(),
+                headers: HashMap::from([
+                    ("Authorization".to_string(), "Bearer s3cret".to_string()),
+                    ("X-Tenant".to_string(), "acme".to_string()),
+                ]),
+                timeout_secs: 10,
+            },
+        ];
+
+        let redacted = config.redacted();
+        let sink = &redacted["outbox"]["sinks"][1];
+        assert_eq!(sink["headers"]["Authorization"], "<redacted>");
+        assert_eq!(sink["headers"]["X-Tenant"], "<redacted>");
+        assert_eq!(sink["url"], "https://<redacted>@hooks.example.com/in");
+        assert_eq!(redacted["outbox"]["sinks"][0]["type"], "stdout");
     }
 }
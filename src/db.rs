@@ -1,9 +1,37 @@
 use crate::{
     errors::MyError,
-    model::{Account, Transaction},
+    model::{
+        Account, AccountBalance, AccountFilter, ApiKey, AuditEntry, AuditQuery, Discrepancy,
+        OutboxEvent, ScheduledTransfer, StatementLine, Transaction, TransactionFilter,
+        WebhookDelivery, WebhookSubscription,
+    },
 };
-use deadpool_postgres::Client;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, GenericClient};
 use tokio_pg_mapper::FromTokioPostgresRow;
+use tokio_postgres::types::ToSql;
+
+// with_outbox wraps a statement returning the rows it changed so that it also
+// writes one outbox event per row. The event commits or rolls back with the
+// change itself, whatever transaction the caller runs it in. key is the column
+// holding the id of the changed entity; secrets and key hashes are left out of
+// the payload. Bookkeeping writes (API key use, audit entries, snapshots and
+// delivery state) are not ledger events and are not wrapped. Imports are the
+// one exception to an event per changed row: ledger_io writes one event per
+// batch of imported rows.
+fn with_outbox(statement: &str, event_type: &str, aggregate: &str, key: &str) -> String {
+    format!(
+        "WITH changed AS ({}),
+        events AS (
+            INSERT INTO outbox (event_type, aggregate, aggregate_id, payload)
+            SELECT '{}', '{}', {}, to_jsonb(changed) - 'secret' - 'key_hash'
+            FROM changed
+            ORDER BY {}
+        )
+        SELECT * FROM changed",
+        statement, event_type, aggregate, key, key
+    )
+}
 
 pub async fn ping_db(client: &Client) -> Result<(), MyError> {
     let _ = client.query_one("SELECT NOW()", &[]).await?;
@@ -11,22 +39,20 @@ pub async fn ping_db(client: &Client) -> Result<(), MyError> {
     Ok(())
 }
 
-pub async fn get_accounts(client: &Client) -> Result<Vec<Account>, MyError> {
-    let stmt = "SELECT * FROM accounts ORDER BY id";
-    let stmt = stmt.replace("$table_fields", &Account::sql_table_fields());
-    let stmt = client.prepare(&stmt).await.unwrap();
-
-    let results = client
-        .query(&stmt, &[])
-        .await?
-        .iter()
-        .map(|row| Account::from_row_ref(row).unwrap())
-        .collect::<Vec<Account>>();
+// schema_version returns the current (version, dirty) migration state recorded by
+// golang-migrate, or None if no migration has been applied.
+pub async fn schema_version(client: &Client) -> Result<Option<(i64, bool)>, MyError> {
+    let row = client
+        .query_opt("SELECT version, dirty FROM schema_migrations LIMIT 1", &[])
+        .await?;
 
-    Ok(results)
+    Ok(row.map(|row| (row.get(0), row.get(1))))
 }
 
-pub async fn get_account_by_id(client: &Client, account_id: i64) -> Result<Account, MyError> {
+pub async fn get_account_by_id(
+    client: &impl GenericClient,
+    account_id: i64,
+) -> Result<Account, MyError> {
     let stmt = "SELECT * FROM accounts WHERE id = $1 LIMIT 1";
     let stmt = stmt.replace("$table_fields", &Account::sql_table_fields());
     let stmt = client.prepare(&stmt).await.unwrap();
@@ -59,14 +85,18 @@ pub async fn get_transaction_by_id(
         .ok_or(MyError::NotFound)
 }
 
-pub async fn create_account(client: &Client, account_info: Account) -> Result<Account, MyError> {
+pub async fn create_account(
+    client: &impl GenericClient,
+    account_info: Account,
+) -> Result<Account, MyError> {
     let _stmt = "INSERT INTO accounts (
-            username, balance, email
+            username, balance, email, metadata, tags
         ) VALUES (
-            $1, $2, $3
+            $1, $2, $3, COALESCE($4::jsonb, '{}'), COALESCE($5::text[], '{}')
         )
         RETURNING *";
     let _stmt = _stmt.replace("$table_fields", &Account::sql_table_fields());
+    let _stmt = with_outbox(&_stmt, "account.created", "account", "id");
     let stmt = client.prepare(&_stmt).await.unwrap();
 
     client
@@ -76,6 +106,8 @@ pub async fn create_account(client: &Client, account_info: Account) -> Result<Ac
                 &account_info.username,
                 &account_info.balance,
                 &account_info.email,
+                &account_info.metadata,
+                &account_info.tags,
             ],
         )
         .await?
@@ -86,41 +118,421 @@ pub async fn create_account(client: &Client, account_info: Account) -> Result<Ac
         .ok_or(MyError::NotFound) // more applicable for SELECTs
 }
 
-pub async fn get_transactions(client: &Client) -> Result<Vec<Transaction>, MyError> {
-    let stmt = "SELECT * FROM transactions ORDER BY id";
-    let stmt = stmt.replace("$table_fields", &Transaction::sql_table_fields());
-    let stmt = client.prepare(&stmt).await.unwrap();
+// create_accounts inserts many accounts in one statement. Accounts whose
+// username is already taken are skipped and missing from the result.
+pub async fn create_accounts(
+    client: &impl GenericClient,
+    accounts: &[Account],
+) -> Result<Vec<Account>, MyError> {
+    let stmt = with_outbox(
+        "INSERT INTO accounts (username, balance, email, created_at, metadata, tags)
+        SELECT username, balance, email, created_at, COALESCE(metadata, '{}'),
+            ARRAY(SELECT jsonb_array_elements_text(tags))
+        FROM unnest(
+            $1::varchar[], $2::bigint[], $3::varchar[], $4::timestamptz[], $5::jsonb[], $6::jsonb[]
+        ) AS a(username, balance, email, created_at, metadata, tags)
+        ON CONFLICT (username) DO NOTHING
+        RETURNING *",
+        "account.created",
+        "account",
+        "id",
+    );
+    let stmt = client.prepare(&stmt).await?;
+    let usernames: Vec<&Option<String>> = accounts.iter().map(|a| &a.username).collect();
+    let balances: Vec<Option<i64>> = accounts.iter().map(|a| a.balance).collect();
+    let emails: Vec<&Option<String>> = accounts.iter().map(|a| &a.email).collect();
+    let created: Vec<Option<DateTime<Utc>>> = accounts.iter().map(|a| a.created_at).collect();
+    let metadata: Vec<&Option<serde_json::Value>> = accounts.iter().map(|a| &a.metadata).collect();
+    // tag lists differ in length, so they are passed as JSON arrays
+    let tags: Vec<Option<serde_json::Value>> = accounts
+        .iter()
+        .map(|a| a.tags.as_ref().map(|tags| serde_json::json!(tags)))
+        .collect();
 
     let results = client
-        .query(&stmt, &[])
+        .query(
+            &stmt,
+            &[&usernames, &balances, &emails, &created, &metadata, &tags],
+        )
         .await?
         .iter()
-        .map(|row| Transaction::from_row_ref(row).unwrap())
-        .collect::<Vec<Transaction>>();
+        .map(|row| Account::from_row_ref(row).unwrap())
+        .collect::<Vec<Account>>();
 
     Ok(results)
 }
 
-pub async fn create_transaction(
+// like_pattern returns a LIKE pattern matching text anywhere in a value, with
+// the wildcards in text escaped.
+fn like_pattern(text: &str) -> String {
+    let text = text
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", text)
+}
+
+// where_clause joins the conditions whose value is set, numbering their
+// parameters in order. Each $? in a condition stands for its own value.
+fn where_clause<'a>(
+    filters: &[(&str, Option<&'a (dyn ToSql + Sync)>)],
+    params: &mut Vec<&'a (dyn ToSql + Sync)>,
+) -> String {
+    let mut conditions: Vec<String> = Vec::new();
+    for (condition, value) in filters {
+        if let Some(value) = value {
+            params.push(*value);
+            conditions.push(condition.replace("$?", &format!("${}", params.len())));
+        }
+    }
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+// find_accounts returns up to limit (all when None) accounts matching the
+// filter with an id above after_id, ordered by id. owned restricts the result
+// to those accounts.
+pub async fn find_accounts(
+    client: &Client,
+    filter: &AccountFilter,
+    owned: Option<&Vec<i64>>,
+    after_id: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Vec<Account>, MyError> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let filters: [(&str, Option<&(dyn ToSql + Sync)>); 11] = [
+        ("id = ANY($?)", owned.map(|v| v as _)),
+        ("id = ANY($?)", filter.ids.as_ref().map(|v| v as _)),
+        ("username = $?", filter.username.as_ref().map(|v| v as _)),
+        ("email = $?", filter.email.as_ref().map(|v| v as _)),
+        ("balance >= $?", filter.min_balance.as_ref().map(|v| v as _)),
+        ("balance <= $?", filter.max_balance.as_ref().map(|v| v as _)),
+        (
+            "created_at >= $?",
+            filter.created_after.as_ref().map(|v| v as _),
+        ),
+        (
+            "created_at < $?",
+            filter.created_before.as_ref().map(|v| v as _),
+        ),
+        // containment matches the GIN indexes on tags and metadata
+        ("tags @> $?", filter.tags.as_ref().map(|v| v as _)),
+        ("metadata @> $?", filter.metadata.as_ref().map(|v| v as _)),
+        ("id > $?", after_id.as_ref().map(|v| v as _)),
+    ];
+    let conditions = where_clause(&filters, &mut params);
+    params.push(&limit);
+
+    // LIMIT NULL returns every row
+    let stmt = format!(
+        "SELECT * FROM accounts {} ORDER BY id LIMIT ${}",
+        conditions,
+        params.len()
+    );
+    let stmt = client.prepare(&stmt).await?;
+
+    client
+        .query(&stmt, &params)
+        .await?
+        .iter()
+        .map(|row| Account::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+// find_transactions returns up to limit (all when None) transactions matching
+// the filter with an id above after_id, ordered by id. owned restricts the
+// result to transfers from or to those accounts.
+pub async fn find_transactions(
     client: &Client,
+    filter: &TransactionFilter,
+    owned: Option<&Vec<i64>>,
+    after_id: Option<i64>,
+    limit: Option<i64>,
+) -> Result<Vec<Transaction>, MyError> {
+    let description = filter.description.as_deref().map(like_pattern);
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let filters: [(&str, Option<&(dyn ToSql + Sync)>); 14] = [
+        (
+            "(from_account = ANY($?) OR to_account = ANY($?))",
+            owned.map(|v| v as _),
+        ),
+        ("id = ANY($?)", filter.ids.as_ref().map(|v| v as _)),
+        (
+            "(from_account = $? OR to_account = $?)",
+            filter.account_id.as_ref().map(|v| v as _),
+        ),
+        (
+            "from_account = $?",
+            filter.from_account.as_ref().map(|v| v as _),
+        ),
+        (
+            "to_account = $?",
+            filter.to_account.as_ref().map(|v| v as _),
+        ),
+        ("amount >= $?", filter.min_amount.as_ref().map(|v| v as _)),
+        ("amount <= $?", filter.max_amount.as_ref().map(|v| v as _)),
+        (
+            "created_at >= $?",
+            filter.created_after.as_ref().map(|v| v as _),
+        ),
+        (
+            "created_at < $?",
+            filter.created_before.as_ref().map(|v| v as _),
+        ),
+        ("description ILIKE $?", description.as_ref().map(|v| v as _)),
+        ("reference = $?", filter.reference.as_ref().map(|v| v as _)),
+        ("category = $?", filter.category.as_ref().map(|v| v as _)),
+        (
+            "external_id = $?",
+            filter.external_id.as_ref().map(|v| v as _),
+        ),
+        ("id > $?", after_id.as_ref().map(|v| v as _)),
+    ];
+    let conditions = where_clause(&filters, &mut params);
+    params.push(&limit);
+
+    // LIMIT NULL returns every row
+    let stmt = format!(
+        "SELECT * FROM transactions {} ORDER BY id LIMIT ${}",
+        conditions,
+        params.len()
+    );
+    let stmt = client.prepare(&stmt).await?;
+
+    client
+        .query(&stmt, &params)
+        .await?
+        .iter()
+        .map(|row| Transaction::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+// lock_accounts locks accounts in id order until the end of the transaction
+// and returns how many exist. Locking in a fixed order keeps transfers between
+// the same accounts in opposite directions from deadlocking.
+pub async fn lock_accounts(
+    client: &impl GenericClient,
+    account_ids: &[i64],
+) -> Result<usize, MyError> {
+    let stmt = client
+        .prepare_cached("SELECT id FROM accounts WHERE id = ANY($1) ORDER BY id FOR UPDATE")
+        .await?;
+
+    Ok(client.query(&stmt, &[&account_ids]).await?.len())
+}
+
+// lock_account returns an account, locking it until the end of the
+// transaction.
+pub async fn lock_account(
+    client: &impl GenericClient,
+    account_id: i64,
+) -> Result<Account, MyError> {
+    let stmt = client
+        .prepare_cached("SELECT * FROM accounts WHERE id = $1 FOR UPDATE")
+        .await?;
+
+    client
+        .query_opt(&stmt, &[&account_id])
+        .await?
+        .map(|row| Account::from_row_ref(&row))
+        .transpose()?
+        .ok_or(MyError::NotFound)
+}
+
+// update_account replaces the metadata and tags of an account, keeping the
+// stored value of those that are None.
+pub async fn update_account(
+    client: &impl GenericClient,
+    account_id: i64,
+    metadata: Option<&serde_json::Value>,
+    tags: Option<&Vec<String>>,
+) -> Result<Account, MyError> {
+    let stmt = with_outbox(
+        "UPDATE accounts
+        SET metadata = COALESCE($2, metadata), tags = COALESCE($3, tags)
+        WHERE id = $1
+        RETURNING *",
+        "account.updated",
+        "account",
+        "id",
+    );
+    let stmt = client.prepare_cached(&stmt).await?;
+
+    client
+        .query_opt(&stmt, &[&account_id, &metadata, &tags])
+        .await?
+        .map(|row| Account::from_row_ref(&row))
+        .transpose()?
+        .ok_or(MyError::NotFound)
+}
+
+pub async fn get_accounts_by_ids(
+    client: &Client,
+    account_ids: &[i64],
+) -> Result<Vec<Account>, MyError> {
+    let stmt = client
+        .prepare_cached("SELECT * FROM accounts WHERE id = ANY($1)")
+        .await?;
+
+    client
+        .query(&stmt, &[&account_ids])
+        .await?
+        .iter()
+        .map(|row| Account::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+pub async fn get_transactions_by_ids(
+    client: &Client,
+    tx_ids: &[i64],
+) -> Result<Vec<Transaction>, MyError> {
+    let stmt = client
+        .prepare_cached("SELECT * FROM transactions WHERE id = ANY($1)")
+        .await?;
+
+    client
+        .query(&stmt, &[&tx_ids])
+        .await?
+        .iter()
+        .map(|row| Transaction::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+// search returns a page of the accounts and transactions matching a search as
+// (kind, id, score), best match first. Accounts match text in their username
+// or email, transactions in their description or reference, either as a
+// substring or a similar word (trigram indexes). Transactions also match the
+// full-text query. Words are similar from a word similarity of
+// min_similarity. owned restricts the results to those accounts and the
+// transfers from or to them.
+pub async fn search(
+    client: &mut Client,
+    text: &str,
+    query: Option<&str>,
+    min_similarity: f64,
+    owned: Option<&Vec<i64>>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<(String, i64, f64)>, MyError> {
+    // the threshold of the <% operator applies until the transaction ends
+    let db_tx = client.build_transaction().read_only(true).start().await?;
+    db_tx
+        .execute(
+            "SELECT set_config('pg_trgm.word_similarity_threshold', $1, true)",
+            &[&min_similarity.to_string()],
+        )
+        .await?;
+
+    // the document expression matches the transactions_search_idx index
+    let stmt = db_tx
+        .prepare_cached(
+            "SELECT kind, id, score FROM (
+                SELECT 'account' AS kind, a.id, GREATEST(
+                    word_similarity($1, a.username),
+                    word_similarity($1, a.email)
+                )::float8 AS score
+                FROM accounts a
+                WHERE (a.username ILIKE $3 OR a.email ILIKE $3
+                    OR $1 <% a.username OR $1 <% a.email)
+                AND ($4::bigint[] IS NULL OR a.id = ANY($4))
+                UNION ALL
+                SELECT 'transaction', t.id, GREATEST(
+                    ts_rank(
+                        to_tsvector('simple', COALESCE(t.description, '') || ' ' || COALESCE(t.reference, '')),
+                        to_tsquery('simple', $2)
+                    ),
+                    word_similarity($1, t.description),
+                    word_similarity($1, t.reference)
+                )::float8
+                FROM transactions t
+                WHERE (to_tsvector('simple', COALESCE(t.description, '') || ' ' || COALESCE(t.reference, ''))
+                        @@ to_tsquery('simple', $2)
+                    OR t.description ILIKE $3 OR t.reference ILIKE $3
+                    OR $1 <% t.description OR $1 <% t.reference)
+                AND ($4::bigint[] IS NULL OR t.from_account = ANY($4) OR t.to_account = ANY($4))
+            ) results
+            ORDER BY score DESC, kind, id
+            LIMIT $5 OFFSET $6",
+        )
+        .await?;
+
+    let pattern = like_pattern(text);
+    let hits = db_tx
+        .query(&stmt, &[&text, &query, &pattern, &owned, &limit, &offset])
+        .await?
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    db_tx.commit().await?;
+
+    Ok(hits)
+}
+
+// get_account_transactions returns, for each account, up to limit of its
+// transfers (in either direction) with an id above after_id, ordered by id,
+// paired with the account id.
+pub async fn get_account_transactions(
+    client: &Client,
+    account_ids: &[i64],
+    after_id: Option<i64>,
+    limit: i64,
+) -> Result<Vec<(i64, Transaction)>, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT a.id AS account_id, t.*
+            FROM unnest($1::bigint[]) AS a(id)
+            CROSS JOIN LATERAL (
+                SELECT * FROM transactions
+                WHERE (from_account = a.id OR to_account = a.id)
+                AND ($2::bigint IS NULL OR id > $2)
+                ORDER BY id
+                LIMIT $3
+            ) t
+            ORDER BY a.id, t.id",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&account_ids, &after_id, &limit])
+        .await?
+        .iter()
+        .map(|row| Ok((row.try_get("account_id")?, Transaction::from_row_ref(row)?)))
+        .collect()
+}
+
+// create_transaction records a transfer and applies it to the balances of both
+// accounts. It must run in a transaction, after lock_accounts on both accounts,
+// so that the transfer and the balance changes commit together.
+pub async fn create_transaction(
+    client: &impl GenericClient,
     transaction_info: Transaction,
 ) -> Result<Transaction, MyError> {
     let _stmt = "INSERT INTO transactions (
-            from_account, to_account, amount
+            from_account, to_account, amount, description, reference, category, external_id,
+            created_by
         ) VALUES (
-            $1, $2, $3
+            $1, $2, $3, $4, $5, $6, $7, $8
         )
         RETURNING *";
     let _stmt = _stmt.replace("$table_fields", &Transaction::sql_table_fields());
+    let _stmt = with_outbox(&_stmt, "transfer.created", "transaction", "id");
     let stmt = client.prepare(&_stmt).await.unwrap();
 
-    client
+    let new_tx = client
         .query(
             &stmt,
             &[
                 &transaction_info.from_account,
                 &transaction_info.to_account,
                 &transaction_info.amount,
+                &transaction_info.description,
+                &transaction_info.reference,
+                &transaction_info.category,
+                &transaction_info.external_id,
+                &transaction_info.created_by,
             ],
         )
         .await?
@@ -128,5 +540,994 @@ pub async fn create_transaction(
         .map(|row| Transaction::from_row_ref(row).unwrap())
         .collect::<Vec<Transaction>>()
         .pop()
-        .ok_or(MyError::NotFound) // more applicable for SELECTs
+        .ok_or(MyError::NotFound)?; // more applicable for SELECTs
+
+    let stmt = client
+        .prepare_cached(
+            "UPDATE accounts
+            SET balance = balance + CASE WHEN id = $2 THEN $3 ELSE -$3 END
+            WHERE id IN ($1, $2)",
+        )
+        .await?;
+    client
+        .execute(
+            &stmt,
+            &[&new_tx.from_account, &new_tx.to_account, &new_tx.amount],
+        )
+        .await?;
+
+    Ok(new_tx)
+}
+
+// get_transaction_by_external_id returns the transaction a principal made
+// with the given external id, if any.
+pub async fn get_transaction_by_external_id(
+    client: &impl GenericClient,
+    created_by: &str,
+    external_id: &str,
+) -> Result<Option<Transaction>, MyError> {
+    let stmt = client
+        .prepare_cached("SELECT * FROM transactions WHERE created_by = $1 AND external_id = $2")
+        .await?;
+
+    client
+        .query_opt(&stmt, &[&created_by, &external_id])
+        .await?
+        .map(|row| Transaction::from_row_ref(&row).map_err(MyError::from))
+        .transpose()
+}
+
+const API_KEY_FIELDS: &str = "id, name, key_prefix, scopes, created_at, last_used_at, revoked_at";
+
+// how often the last use of an API key is recorded
+const API_KEY_USE_INTERVAL_SECS: i64 = 60;
+
+pub async fn create_api_key(
+    client: &impl GenericClient,
+    name: &str,
+    key_prefix: &str,
+    key_hash: &str,
+    scopes: &[String],
+) -> Result<ApiKey, MyError> {
+    let stmt = format!(
+        "INSERT INTO api_keys (name, key_prefix, key_hash, scopes)
+        VALUES ($1, $2, $3, $4)
+        RETURNING {}",
+        API_KEY_FIELDS
+    );
+    let stmt = with_outbox(&stmt, "api_key.created", "api_key", "id");
+    let stmt = client.prepare(&stmt).await?;
+
+    let row = client
+        .query_one(&stmt, &[&name, &key_prefix, &key_hash, &scopes])
+        .await?;
+
+    Ok(ApiKey::from_row_ref(&row)?)
+}
+
+pub async fn get_api_keys(client: &Client) -> Result<Vec<ApiKey>, MyError> {
+    let stmt = format!("SELECT {} FROM api_keys ORDER BY id", API_KEY_FIELDS);
+    let stmt = client.prepare(&stmt).await?;
+
+    client
+        .query(&stmt, &[])
+        .await?
+        .iter()
+        .map(|row| ApiKey::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+pub async fn revoke_api_key(client: &impl GenericClient, key_id: i64) -> Result<ApiKey, MyError> {
+    let stmt = format!(
+        "UPDATE api_keys SET revoked_at = now()
+        WHERE id = $1 AND revoked_at IS NULL
+        RETURNING {}",
+        API_KEY_FIELDS
+    );
+    let stmt = with_outbox(&stmt, "api_key.revoked", "api_key", "id");
+    let stmt = client.prepare(&stmt).await?;
+
+    client
+        .query_opt(&stmt, &[&key_id])
+        .await?
+        .map(|row| ApiKey::from_row_ref(&row).map_err(MyError::from))
+        .unwrap_or(Err(MyError::NotFound))
+}
+
+// authenticate_api_key looks up an active key by hash. Its use is recorded at
+// most once a minute, so that requests do not each write and queue on the
+// key's row lock.
+pub async fn authenticate_api_key(
+    client: &Client,
+    key_hash: &str,
+) -> Result<Option<ApiKey>, MyError> {
+    let stmt = format!(
+        "SELECT {} FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL",
+        API_KEY_FIELDS
+    );
+    let stmt = client.prepare_cached(&stmt).await?;
+    let Some(key) = client
+        .query_opt(&stmt, &[&key_hash])
+        .await?
+        .map(|row| ApiKey::from_row_ref(&row))
+        .transpose()?
+    else {
+        return Ok(None);
+    };
+
+    let recently_used = key.last_used_at.is_some_and(|used| {
+        Utc::now() - used < chrono::Duration::seconds(API_KEY_USE_INTERVAL_SECS)
+    });
+    if !recently_used {
+        let stmt = client
+            .prepare_cached(
+                "UPDATE api_keys SET last_used_at = now()
+                WHERE id = $1
+                AND (last_used_at IS NULL OR last_used_at < now() - make_interval(secs => $2))",
+            )
+            .await?;
+        client
+            .execute(&stmt, &[&key.id, &(API_KEY_USE_INTERVAL_SECS as f64)])
+            .await?;
+    }
+    Ok(Some(key))
+}
+
+// transfer_velocity returns the number and total amount of transfers out of an
+// account within the last window_secs, and when the oldest of them was made.
+pub async fn transfer_velocity(
+    client: &impl GenericClient,
+    account_id: i64,
+    window_secs: u64,
+) -> Result<(i64, i64, Option<DateTime<Utc>>), MyError> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT count(*), COALESCE(sum(amount), 0)::bigint, min(created_at)
+            FROM transactions
+            WHERE from_account = $1 AND created_at > now() - make_interval(secs => $2)",
+        )
+        .await?;
+    let row = client
+        .query_one(&stmt, &[&account_id, &(window_secs as f64)])
+        .await?;
+
+    Ok((row.get(0), row.get(1), row.get(2)))
+}
+
+const AUDIT_FIELDS: &str = "id, actor, request_id, operation, entity, entity_id, before, after, \
+    source_ip, created_at, prev_hash, hash";
+
+// arbitrary key of the advisory lock serializing writers of the audit chain
+const AUDIT_LOCK_KEY: i64 = 0x0061_7564_6974;
+
+// lock_audit_chain blocks other writers of the audit log until the current
+// transaction ends, so that each entry links to the latest committed one.
+pub async fn lock_audit_chain(client: &impl GenericClient) -> Result<(), MyError> {
+    client
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&AUDIT_LOCK_KEY])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn last_audit_hash(client: &impl GenericClient) -> Result<Option<String>, MyError> {
+    let row = client
+        .query_opt("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1", &[])
+        .await?;
+
+    Ok(row.map(|row| row.get(0)))
+}
+
+pub async fn insert_audit_entry(
+    client: &impl GenericClient,
+    entry: &AuditEntry,
+) -> Result<AuditEntry, MyError> {
+    let stmt = format!(
+        "INSERT INTO audit_log (
+            actor, request_id, operation, entity, entity_id, before, after,
+            source_ip, created_at, prev_hash, hash
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
+        )
+        RETURNING {}",
+        AUDIT_FIELDS
+    );
+    let stmt = client.prepare_cached(&stmt).await?;
+
+    let row = client
+        .query_one(
+            &stmt,
+            &[
+                &entry.actor,
+                &entry.request_id,
+                &entry.operation,
+                &entry.entity,
+                &entry.entity_id,
+                &entry.before,
+                &entry.after,
+                &entry.source_ip,
+                &entry.created_at,
+                &entry.prev_hash,
+                &entry.hash,
+            ],
+        )
+        .await?;
+
+    Ok(AuditEntry::from_row_ref(&row)?)
+}
+
+// get_audit_entries returns the entries matching the query, oldest first.
+pub async fn get_audit_entries(
+    client: &Client,
+    query: &AuditQuery,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, MyError> {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let filters: [(&str, Option<&(dyn ToSql + Sync)>); 8] = [
+        ("entity =", query.entity.as_ref().map(|v| v as _)),
+        ("entity_id =", query.entity_id.as_ref().map(|v| v as _)),
+        ("actor =", query.actor.as_ref().map(|v| v as _)),
+        ("operation =", query.operation.as_ref().map(|v| v as _)),
+        ("request_id =", query.request_id.as_ref().map(|v| v as _)),
+        ("created_at >=", query.since.as_ref().map(|v| v as _)),
+        ("created_at <", query.until.as_ref().map(|v| v as _)),
+        ("id >", query.after_id.as_ref().map(|v| v as _)),
+    ];
+    for (condition, value) in filters {
+        if let Some(value) = value {
+            params.push(value);
+            conditions.push(format!("{} ${}", condition, params.len()));
+        }
+    }
+    params.push(&limit);
+
+    let stmt = format!(
+        "SELECT {} FROM audit_log {} ORDER BY id LIMIT ${}",
+        AUDIT_FIELDS,
+        if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        },
+        params.len()
+    );
+    let stmt = client.prepare(&stmt).await?;
+
+    client
+        .query(&stmt, &params)
+        .await?
+        .iter()
+        .map(|row| AuditEntry::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+pub async fn count_accounts(client: &impl GenericClient) -> Result<i64, MyError> {
+    let row = client
+        .query_one("SELECT count(*) FROM accounts", &[])
+        .await?;
+
+    Ok(row.get(0))
+}
+
+// balance_discrepancies compares every stored balance with the sum of the
+// transfers into the account minus the transfers out of it.
+pub async fn balance_discrepancies(
+    client: &impl GenericClient,
+) -> Result<Vec<Discrepancy>, MyError> {
+    let stmt = "WITH flows AS (
+            SELECT account, sum(delta)::bigint AS total FROM (
+                SELECT to_account AS account, COALESCE(amount, 0) AS delta FROM transactions
+                UNION ALL
+                SELECT from_account, -COALESCE(amount, 0) FROM transactions
+            ) t
+            GROUP BY account
+        )
+        SELECT a.id, a.balance, COALESCE(f.total, 0) AS expected
+        FROM accounts a
+        LEFT JOIN flows f ON f.account = a.id
+        WHERE a.balance <> COALESCE(f.total, 0)
+        ORDER BY a.id";
+    let stmt = client.prepare(stmt).await?;
+
+    let results = client
+        .query(&stmt, &[])
+        .await?
+        .iter()
+        .map(|row| {
+            let recorded: i64 = row.get(1);
+            let expected: i64 = row.get(2);
+            Discrepancy {
+                account_id: row.get(0),
+                recorded,
+                expected,
+                difference: expected - recorded,
+            }
+        })
+        .collect();
+
+    Ok(results)
+}
+
+// lock_transactions blocks new transfers until the current transaction ends.
+pub async fn lock_transactions(client: &impl GenericClient) -> Result<(), MyError> {
+    client
+        .execute("LOCK TABLE transactions IN SHARE MODE", &[])
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_account_balance(
+    client: &impl GenericClient,
+    account_id: i64,
+    balance: i64,
+) -> Result<Account, MyError> {
+    let stmt = with_outbox(
+        "UPDATE accounts SET balance = $2 WHERE id = $1 RETURNING *",
+        "account.balance_set",
+        "account",
+        "id",
+    );
+    let stmt = client.prepare(&stmt).await?;
+
+    client
+        .query_opt(&stmt, &[&account_id, &balance])
+        .await?
+        .map(|row| Account::from_row_ref(&row).map_err(MyError::from))
+        .unwrap_or(Err(MyError::NotFound))
+}
+
+// account_balance_at computes the balance of an account at as_of from the
+// latest snapshot taken at or before as_of plus the transfers made since. The
+// account must have existed at as_of.
+pub async fn account_balance_at(
+    client: &Client,
+    account_id: i64,
+    as_of: DateTime<Utc>,
+) -> Result<AccountBalance, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "WITH snap AS (
+                SELECT as_of, balance FROM balance_snapshots
+                WHERE account_id = $1 AND as_of <= $2
+                ORDER BY as_of DESC LIMIT 1
+            ), since AS (
+                SELECT COALESCE((SELECT as_of FROM snap), '-infinity') AS as_of
+            )
+            SELECT a.id, (
+                COALESCE((SELECT balance FROM snap), 0)
+                + COALESCE((
+                    SELECT sum(amount) FROM transactions
+                    WHERE to_account = $1 AND created_at > (SELECT as_of FROM since)
+                        AND created_at <= $2
+                ), 0)
+                - COALESCE((
+                    SELECT sum(amount) FROM transactions
+                    WHERE from_account = $1 AND created_at > (SELECT as_of FROM since)
+                        AND created_at <= $2
+                ), 0)
+            )::bigint
+            FROM accounts a
+            WHERE a.id = $1 AND COALESCE(a.created_at, '-infinity') <= $2",
+        )
+        .await?;
+
+    client
+        .query_opt(&stmt, &[&account_id, &as_of])
+        .await?
+        .map(|row| AccountBalance {
+            account_id: row.get(0),
+            balance: row.get(1),
+            as_of: Some(as_of),
+        })
+        .ok_or(MyError::NotFound)
+}
+
+// BALANCES_AT computes the balance at $1 of every account that existed then.
+// Transfers older than the oldest snapshot in use are skipped: every account
+// is snapshotted from the first run after its creation, so an account without
+// a snapshot has no earlier transfers.
+const BALANCES_AT: &str = "WITH snap AS (
+        SELECT DISTINCT ON (account_id) account_id, as_of, balance
+        FROM balance_snapshots
+        WHERE as_of <= $1
+        ORDER BY account_id, as_of DESC
+    ), flows AS (
+        SELECT t.account, sum(t.delta)::bigint AS total FROM (
+            SELECT to_account AS account, COALESCE(amount, 0) AS delta, created_at
+            FROM transactions
+            WHERE created_at <= $1
+                AND created_at > (SELECT COALESCE(min(as_of), '-infinity') FROM snap)
+            UNION ALL
+            SELECT from_account, -COALESCE(amount, 0), created_at
+            FROM transactions
+            WHERE created_at <= $1
+                AND created_at > (SELECT COALESCE(min(as_of), '-infinity') FROM snap)
+        ) t
+        LEFT JOIN snap s ON s.account_id = t.account
+        WHERE s.as_of IS NULL OR t.created_at > s.as_of
+        GROUP BY t.account
+    )
+    SELECT a.id, (COALESCE(s.balance, 0) + COALESCE(f.total, 0))::bigint AS balance
+    FROM accounts a
+    LEFT JOIN snap s ON s.account_id = a.id
+    LEFT JOIN flows f ON f.account = a.id
+    WHERE COALESCE(a.created_at, '-infinity') <= $1";
+
+// balances_at returns the balance of every account that existed at as_of.
+pub async fn balances_at(
+    client: &Client,
+    as_of: DateTime<Utc>,
+) -> Result<Vec<AccountBalance>, MyError> {
+    let stmt = format!("{} ORDER BY a.id", BALANCES_AT);
+    let stmt = client.prepare_cached(&stmt).await?;
+
+    let results = client
+        .query(&stmt, &[&as_of])
+        .await?
+        .iter()
+        .map(|row| AccountBalance {
+            account_id: row.get(0),
+            balance: row.get(1),
+            as_of: Some(as_of),
+        })
+        .collect();
+
+    Ok(results)
+}
+
+// create_balance_snapshots records the balance of every account at as_of,
+// returning the number of snapshots written.
+pub async fn create_balance_snapshots(
+    client: &Client,
+    as_of: DateTime<Utc>,
+) -> Result<u64, MyError> {
+    let stmt = format!(
+        "INSERT INTO balance_snapshots (account_id, as_of, balance)
+        SELECT id, $1, balance FROM ({}) b
+        ON CONFLICT DO NOTHING",
+        BALANCES_AT
+    );
+
+    Ok(client.execute(&stmt, &[&as_of]).await?)
+}
+
+// get_statement_lines returns the transfers into or out of an account made in
+// [from, to), oldest first. Running balances are left for the caller to fill in.
+pub async fn get_statement_lines(
+    client: &Client,
+    account_id: i64,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<StatementLine>, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT t.id, t.created_at, c.id, c.username,
+                (CASE WHEN t.to_account = $1 THEN COALESCE(t.amount, 0) ELSE 0 END
+                - CASE WHEN t.from_account = $1 THEN COALESCE(t.amount, 0) ELSE 0 END)::bigint,
+                t.description, t.reference
+            FROM transactions t
+            LEFT JOIN accounts c ON c.id = CASE
+                WHEN t.from_account = $1 THEN t.to_account ELSE t.from_account END
+            WHERE (t.from_account = $1 OR t.to_account = $1)
+                AND t.created_at >= $2 AND t.created_at < $3
+            ORDER BY t.created_at, t.id",
+        )
+        .await?;
+
+    let results = client
+        .query(&stmt, &[&account_id, &from, &to])
+        .await?
+        .iter()
+        .map(|row| StatementLine {
+            transaction_id: row.get(0),
+            created_at: row.get(1),
+            counterparty_id: row.get(2),
+            counterparty: row.get(3),
+            amount: row.get(4),
+            balance: 0,
+            description: row.get(5),
+            reference: row.get(6),
+        })
+        .collect();
+
+    Ok(results)
+}
+
+pub async fn create_scheduled_transfer(
+    client: &impl GenericClient,
+    schedule: &ScheduledTransfer,
+) -> Result<ScheduledTransfer, MyError> {
+    let stmt = with_outbox(
+        "INSERT INTO scheduled_transfers (
+            from_account, to_account, amount, recurrence, starts_at, next_run_at, created_by
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7
+        )
+        RETURNING *",
+        "scheduled_transfer.created",
+        "scheduled_transfer",
+        "id",
+    );
+    let stmt = client.prepare(&stmt).await?;
+    let row = client
+        .query_one(
+            &stmt,
+            &[
+                &schedule.from_account,
+                &schedule.to_account,
+                &schedule.amount,
+                &schedule.recurrence,
+                &schedule.starts_at,
+                &schedule.next_run_at,
+                &schedule.created_by,
+            ],
+        )
+        .await?;
+
+    Ok(ScheduledTransfer::from_row_ref(&row)?)
+}
+
+pub async fn get_scheduled_transfers(
+    client: &Client,
+    status: Option<&str>,
+) -> Result<Vec<ScheduledTransfer>, MyError> {
+    let stmt = client
+        .prepare(
+            "SELECT * FROM scheduled_transfers
+            WHERE $1::varchar IS NULL OR status = $1
+            ORDER BY id",
+        )
+        .await?;
+
+    let results = client
+        .query(&stmt, &[&status])
+        .await?
+        .iter()
+        .map(|row| ScheduledTransfer::from_row_ref(row).unwrap())
+        .collect::<Vec<ScheduledTransfer>>();
+
+    Ok(results)
+}
+
+// get_scheduled_transfer_by_id returns a scheduled transfer, locking it until
+// the current transaction ends when lock is set.
+pub async fn get_scheduled_transfer_by_id(
+    client: &impl GenericClient,
+    id: i64,
+    lock: bool,
+) -> Result<ScheduledTransfer, MyError> {
+    let query = if lock {
+        "SELECT * FROM scheduled_transfers WHERE id = $1 FOR UPDATE"
+    } else {
+        "SELECT * FROM scheduled_transfers WHERE id = $1"
+    };
+    let stmt = client.prepare(query).await?;
+
+    client
+        .query_opt(&stmt, &[&id])
+        .await?
+        .map(|row| ScheduledTransfer::from_row_ref(&row))
+        .transpose()?
+        .ok_or(MyError::NotFound)
+}
+
+// claim_due_scheduled_transfer locks the scheduled transfer that has been due
+// the longest. Transfers locked by another worker are skipped.
+pub async fn claim_due_scheduled_transfer(
+    client: &impl GenericClient,
+) -> Result<Option<ScheduledTransfer>, MyError> {
+    let stmt = client
+        .prepare(
+            "SELECT * FROM scheduled_transfers
+            WHERE status = 'active' AND COALESCE(retry_at, next_run_at) <= now()
+            ORDER BY COALESCE(retry_at, next_run_at)
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED",
+        )
+        .await?;
+
+    Ok(client
+        .query_opt(&stmt, &[])
+        .await?
+        .map(|row| ScheduledTransfer::from_row_ref(&row))
+        .transpose()?)
+}
+
+// record_scheduled_run marks an occurrence of a scheduled transfer as executed.
+// It fails if the occurrence was already executed.
+pub async fn record_scheduled_run(
+    client: &impl GenericClient,
+    schedule_id: i64,
+    scheduled_for: DateTime<Utc>,
+    transaction_id: i64,
+) -> Result<(), MyError> {
+    let stmt = with_outbox(
+        "INSERT INTO scheduled_transfer_runs (schedule_id, scheduled_for, transaction_id)
+        VALUES ($1, $2, $3)
+        RETURNING *",
+        "scheduled_transfer.run",
+        "scheduled_transfer",
+        "schedule_id",
+    );
+    client
+        .execute(&stmt, &[&schedule_id, &scheduled_for, &transaction_id])
+        .await?;
+    Ok(())
+}
+
+// update_scheduled_transfer stores the state of a scheduled transfer.
+pub async fn update_scheduled_transfer(
+    client: &impl GenericClient,
+    schedule: &ScheduledTransfer,
+) -> Result<ScheduledTransfer, MyError> {
+    let stmt = with_outbox(
+        "UPDATE scheduled_transfers SET
+            status = $2, next_run_at = $3, retry_at = $4, attempts = $5,
+            last_error = $6, last_run_at = $7, runs = $8, updated_at = now()
+        WHERE id = $1
+        RETURNING *",
+        "scheduled_transfer.updated",
+        "scheduled_transfer",
+        "id",
+    );
+    let stmt = client.prepare(&stmt).await?;
+    let row = client
+        .query_one(
+            &stmt,
+            &[
+                &schedule.id,
+                &schedule.status,
+                &schedule.next_run_at,
+                &schedule.retry_at,
+                &schedule.attempts,
+                &schedule.last_error,
+                &schedule.last_run_at,
+                &schedule.runs,
+            ],
+        )
+        .await?;
+
+    Ok(ScheduledTransfer::from_row_ref(&row)?)
+}
+
+pub async fn create_webhook(
+    client: &impl GenericClient,
+    webhook: &WebhookSubscription,
+) -> Result<WebhookSubscription, MyError> {
+    let stmt = with_outbox(
+        "INSERT INTO webhook_subscriptions (url, event_types, secret, description)
+        VALUES ($1, $2, $3, $4)
+        RETURNING *",
+        "webhook.created",
+        "webhook",
+        "id",
+    );
+    let stmt = client.prepare(&stmt).await?;
+    let row = client
+        .query_one(
+            &stmt,
+            &[
+                &webhook.url,
+                &webhook.event_types,
+                &webhook.secret,
+                &webhook.description,
+            ],
+        )
+        .await?;
+
+    Ok(WebhookSubscription::from_row_ref(&row)?)
+}
+
+// get_webhooks lists the webhook subscriptions without their secrets.
+pub async fn get_webhooks(client: &Client) -> Result<Vec<WebhookSubscription>, MyError> {
+    let stmt = client
+        .prepare(
+            "SELECT id, url, event_types, NULL::varchar AS secret, description, active, created_at
+            FROM webhook_subscriptions
+            ORDER BY id",
+        )
+        .await?;
+
+    let results = client
+        .query(&stmt, &[])
+        .await?
+        .iter()
+        .map(|row| WebhookSubscription::from_row_ref(row).unwrap())
+        .collect::<Vec<WebhookSubscription>>();
+
+    Ok(results)
+}
+
+// get_webhook_by_id returns a webhook subscription without its secret.
+pub async fn get_webhook_by_id(client: &Client, id: i64) -> Result<WebhookSubscription, MyError> {
+    let stmt = client
+        .prepare(
+            "SELECT id, url, event_types, NULL::varchar AS secret, description, active, created_at
+            FROM webhook_subscriptions
+            WHERE id = $1",
+        )
+        .await?;
+
+    client
+        .query_opt(&stmt, &[&id])
+        .await?
+        .map(|row| WebhookSubscription::from_row_ref(&row))
+        .transpose()?
+        .ok_or(MyError::NotFound)
+}
+
+// deactivate_webhook stops a subscription and cancels its pending deliveries.
+pub async fn deactivate_webhook(
+    client: &impl GenericClient,
+    id: i64,
+) -> Result<WebhookSubscription, MyError> {
+    let stmt = with_outbox(
+        "UPDATE webhook_subscriptions SET active = false
+        WHERE id = $1 AND active
+        RETURNING id, url, event_types, NULL::varchar AS secret, description, active, created_at",
+        "webhook.deactivated",
+        "webhook",
+        "id",
+    );
+    let row = client
+        .query_opt(&stmt, &[&id])
+        .await?
+        .ok_or(MyError::NotFound)?;
+    client
+        .execute(
+            "UPDATE webhook_deliveries SET status = 'cancelled'
+            WHERE subscription_id = $1 AND status = 'pending'",
+            &[&id],
+        )
+        .await?;
+
+    Ok(WebhookSubscription::from_row_ref(&row)?)
+}
+
+// get_webhook_deliveries returns the latest deliveries of a subscription.
+pub async fn get_webhook_deliveries(
+    client: &Client,
+    subscription_id: i64,
+    status: Option<&str>,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>, MyError> {
+    let stmt = client
+        .prepare(
+            "SELECT * FROM webhook_deliveries
+            WHERE subscription_id = $1 AND ($2::varchar IS NULL OR status = $2)
+            ORDER BY id DESC
+            LIMIT $3",
+        )
+        .await?;
+
+    let results = client
+        .query(&stmt, &[&subscription_id, &status, &limit])
+        .await?
+        .iter()
+        .map(|row| WebhookDelivery::from_row_ref(row).unwrap())
+        .collect::<Vec<WebhookDelivery>>();
+
+    Ok(results)
+}
+
+// enqueue_webhook_deliveries queues an event for every active subscription to
+// its type and returns the number of deliveries queued.
+pub async fn enqueue_webhook_deliveries(
+    client: &impl GenericClient,
+    event_id: &str,
+    event_type: &str,
+    payload: &serde_json::Value,
+) -> Result<u64, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "INSERT INTO webhook_deliveries (subscription_id, event_id, event_type, payload)
+            SELECT id, $1, $2::varchar, $3 FROM webhook_subscriptions
+            WHERE active AND $2::varchar = ANY(event_types)",
+        )
+        .await?;
+
+    Ok(client
+        .execute(&stmt, &[&event_id, &event_type, payload])
+        .await?)
+}
+
+// claim_webhook_deliveries leases up to limit due deliveries for lease_secs and
+// returns them with the URL and secret of their subscription. A delivery whose
+// attempt is interrupted becomes due again once the lease expires.
+pub async fn claim_webhook_deliveries(
+    client: &Client,
+    limit: i64,
+    lease_secs: u64,
+) -> Result<Vec<(WebhookDelivery, String, String)>, MyError> {
+    let stmt = client
+        .prepare(
+            "WITH due AS (
+                SELECT id FROM webhook_deliveries
+                WHERE status = 'pending' AND next_attempt_at <= now()
+                ORDER BY next_attempt_at, id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            UPDATE webhook_deliveries d
+            SET attempts = d.attempts + 1,
+                next_attempt_at = now() + make_interval(secs => $2)
+            FROM due, webhook_subscriptions s
+            WHERE d.id = due.id AND s.id = d.subscription_id
+            RETURNING d.*, s.url, s.secret",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&limit, &(lease_secs as f64)])
+        .await?
+        .iter()
+        .map(|row| {
+            Ok((
+                WebhookDelivery::from_row_ref(row)?,
+                row.get("url"),
+                row.get("secret"),
+            ))
+        })
+        .collect()
+}
+
+// finish_webhook_delivery records the outcome of a delivery attempt. Failed
+// attempts are retried at retry_at, or marked failed when it is None.
+pub async fn finish_webhook_delivery(
+    client: &Client,
+    id: i64,
+    delivered: bool,
+    response_status: Option<i32>,
+    error: Option<&str>,
+    retry_at: Option<DateTime<Utc>>,
+) -> Result<(), MyError> {
+    client
+        .execute(
+            "UPDATE webhook_deliveries SET
+                status = CASE WHEN $2 THEN 'delivered' WHEN $5::timestamptz IS NULL THEN 'failed' ELSE 'pending' END,
+                delivered_at = CASE WHEN $2 THEN now() END,
+                response_status = $3,
+                last_error = $4,
+                next_attempt_at = COALESCE($5, next_attempt_at)
+            WHERE id = $1",
+            &[&id, &delivered, &response_status, &error, &retry_at],
+        )
+        .await?;
+    Ok(())
+}
+
+const OUTBOX_LOCK_KEY: i64 = 0x6f75_7462_6f78;
+
+// lock_outbox takes the outbox relay lock until the current transaction ends
+// and returns false when another relay holds it. A single relay at a time
+// keeps the events in order.
+pub async fn lock_outbox(client: &impl GenericClient) -> Result<bool, MyError> {
+    let row = client
+        .query_one("SELECT pg_try_advisory_xact_lock($1)", &[&OUTBOX_LOCK_KEY])
+        .await?;
+
+    Ok(row.get(0))
+}
+
+// get_pending_outbox_events returns the oldest undelivered events in id order,
+// stopping before the first event written while a transaction that is still
+// running was in flight. Ids are taken before commit, so that transaction may
+// hold a lower id that has not committed yet.
+pub async fn get_pending_outbox_events(
+    client: &impl GenericClient,
+    limit: i64,
+) -> Result<Vec<OutboxEvent>, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT * FROM outbox
+            WHERE delivered_at IS NULL
+              AND id < COALESCE((
+                SELECT min(h.id) FROM outbox h
+                WHERE h.delivered_at IS NULL
+                  AND EXISTS (
+                    SELECT 1 FROM pg_snapshot_xip(h.snapshot) AS x(xid)
+                    WHERE pg_xact_status(x.xid) = 'in progress'
+                  )
+              ), 9223372036854775807)
+            ORDER BY id
+            LIMIT $1",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&limit])
+        .await?
+        .iter()
+        .map(|row| OutboxEvent::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+pub async fn mark_outbox_delivered(
+    client: &impl GenericClient,
+    ids: &[i64],
+) -> Result<u64, MyError> {
+    Ok(client
+        .execute(
+            "UPDATE outbox SET delivered_at = now() WHERE id = ANY($1)",
+            &[&ids],
+        )
+        .await?)
+}
+
+pub async fn get_outbox_event(
+    client: &impl GenericClient,
+    id: i64,
+) -> Result<Option<OutboxEvent>, MyError> {
+    let stmt = client
+        .prepare_cached("SELECT * FROM outbox WHERE id = $1")
+        .await?;
+
+    Ok(client
+        .query_opt(&stmt, &[&id])
+        .await?
+        .map(|row| OutboxEvent::from_row_ref(&row))
+        .transpose()?)
+}
+
+// get_outbox_events_after returns up to limit events of the given types with
+// an id above after_id, in id order.
+pub async fn get_outbox_events_after(
+    client: &impl GenericClient,
+    after_id: i64,
+    event_types: &[&str],
+    limit: i64,
+) -> Result<Vec<OutboxEvent>, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT * FROM outbox
+            WHERE id > $1 AND event_type = ANY($2)
+            ORDER BY id
+            LIMIT $3",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&after_id, &event_types, &limit])
+        .await?
+        .iter()
+        .map(|row| OutboxEvent::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+// get_outbox_events_committed_after returns the events of the given types with
+// an id below id that had not committed when the event id was written. They
+// committed after it, so a reader resuming after id has not read them. Events
+// of the transaction that wrote id committed with it and are left out.
+pub async fn get_outbox_events_committed_after(
+    client: &impl GenericClient,
+    id: i64,
+    event_types: &[&str],
+) -> Result<Vec<OutboxEvent>, MyError> {
+    let stmt = client
+        .prepare_cached(
+            "SELECT o.* FROM outbox o, outbox a
+            WHERE a.id = $1 AND o.id < a.id AND o.xid <> a.xid
+              AND o.xid >= pg_snapshot_xmin(a.snapshot)
+              AND NOT pg_visible_in_snapshot(o.xid, a.snapshot)
+              AND o.event_type = ANY($2)
+            ORDER BY o.id",
+        )
+        .await?;
+
+    client
+        .query(&stmt, &[&id, &event_types])
+        .await?
+        .iter()
+        .map(|row| OutboxEvent::from_row_ref(row).map_err(MyError::from))
+        .collect()
+}
+
+pub async fn last_outbox_id(client: &impl GenericClient) -> Result<i64, MyError> {
+    let row = client
+        .query_one("SELECT COALESCE(max(id), 0) FROM outbox", &[])
+        .await?;
+
+    Ok(row.get(0))
 }
@@ -0,0 +1,496 @@
+// Live ledger events for GET /events (Server-Sent Events) and /events/ws
+// (WebSocket). Outbox inserts notify the ledger_events channel when their
+// transaction commits; every server instance listens on it, turns the outbox
+// events into stream events and broadcasts them to its subscribers. Stream
+// events carry the outbox id, so a client resumes after the last id it saw and
+// the missed events are replayed from the outbox. Outbox ids are taken before
+// commit, so a lower id can commit after a higher one: a resume also replays
+// the lower ids that were in flight when the last seen event was written.
+use crate::{
+    config::{EventsConfig, PgTlsConfig},
+    db,
+    errors::MyError,
+    model::{OutboxEvent, Transaction},
+    shutdown::stopped,
+    tls,
+};
+use actix_web::rt;
+use deadpool_postgres::{Config as PgConfig, Pool, SslMode};
+use futures_util::{stream::poll_fn, StreamExt};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_postgres::{AsyncMessage, Client, Connection, NoTls, Notification};
+
+pub const CHANNEL: &str = "ledger_events";
+
+pub const TRANSACTION: &str = "transaction";
+pub const BALANCE: &str = "balance";
+
+// outbox events turned into stream events
+const STREAMED: [&str; 2] = ["transfer.created", "account.created"];
+
+// events read per query when replaying
+const REPLAY_PAGE: i64 = 500;
+
+// broadcast ids remembered to skip events found again when catching up
+const SEEN_IDS: usize = 10_000;
+
+// StreamEvent is one message of the event streams. A transfer produces a
+// transaction event followed by a balance event for each account, all with the
+// id of the outbox event.
+#[derive(Debug, Serialize)]
+pub struct StreamEvent {
+    pub id: i64,
+    pub event: &'static str,
+    #[serde(skip)]
+    pub accounts: Vec<i64>,
+    pub data: Value,
+}
+
+// EventFilter selects the events of a stream. None matches everything.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    pub accounts: Option<Vec<i64>>,
+    pub types: Option<Vec<String>>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &StreamEvent) -> bool {
+        self.types
+            .as_ref()
+            .is_none_or(|types| types.iter().any(|t| t == event.event))
+            && self
+                .accounts
+                .as_ref()
+                .is_none_or(|accounts| event.accounts.iter().any(|a| accounts.contains(a)))
+    }
+}
+
+// Frame is what a subscriber receives.
+pub enum Frame {
+    Event(Arc<StreamEvent>),
+    Keepalive,
+    // the stream ended, the client should resume from its last event id
+    Closed(String),
+}
+
+// EventHub fans the events received by the listener out to the subscribers of
+// this server instance.
+pub struct EventHub {
+    sender: broadcast::Sender<Arc<StreamEvent>>,
+    config: EventsConfig,
+}
+
+impl EventHub {
+    pub fn new(config: EventsConfig) -> Self {
+        let (sender, _) = broadcast::channel(config.buffer.max(1));
+        EventHub { sender, config }
+    }
+
+    // subscribe streams the events matching filter. With after_id the events
+    // after it are replayed first. The stream ends on shutdown, or with a
+    // Closed frame when the subscriber falls behind or the replay fails.
+    pub fn subscribe(
+        &self,
+        pool: Pool,
+        filter: EventFilter,
+        after_id: Option<i64>,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> mpsc::Receiver<Frame> {
+        let (tx, rx) = mpsc::channel(16);
+        // subscribe before replaying so that no event falls in between
+        let mut live = self.sender.subscribe();
+        let keepalive = Duration::from_secs(self.config.keepalive_secs.max(1));
+
+        rt::spawn(async move {
+            let mut replayed = HashSet::new();
+            if let Some(after_id) = after_id {
+                match replay(&pool, &filter, after_id, &tx, &mut replayed).await {
+                    Ok(true) => {}
+                    Ok(false) => return,
+                    Err(err) => {
+                        let _ = tx.send(Frame::Closed(err.to_string())).await;
+                        return;
+                    }
+                }
+            }
+
+            let mut ticker = tokio::time::interval(keepalive);
+            ticker.tick().await;
+            loop {
+                let frame = tokio::select! {
+                    _ = stopped(&mut shutdown) => return,
+                    _ = tx.closed() => return,
+                    _ = ticker.tick() => Frame::Keepalive,
+                    event = live.recv() => match event {
+                        Ok(event) if !replayed.contains(&event.id) && filter.matches(&event) => {
+                            Frame::Event(event)
+                        }
+                        Ok(_) => continue,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => Frame::Closed(format!(
+                            "stream fell {} events behind, resume from the last event id",
+                            missed
+                        )),
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                };
+                let closed = matches!(frame, Frame::Closed(_));
+                if tx.send(frame).await.is_err() || closed {
+                    return;
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+// replay sends the stored events after after_id, starting with the lower ids
+// that committed after it, recording their ids so that the live stream skips
+// them. It returns false if the subscriber went away.
+async fn replay(
+    pool: &Pool,
+    filter: &EventFilter,
+    mut after_id: i64,
+    tx: &mpsc::Sender<Frame>,
+    replayed: &mut HashSet<i64>,
+) -> Result<bool, MyError> {
+    let client = pool.get().await?;
+    let late = db::get_outbox_events_committed_after(&client, after_id, &STREAMED).await?;
+    if !send_replayed(&client, filter, &late, tx, replayed).await? {
+        return Ok(false);
+    }
+    loop {
+        let events = db::get_outbox_events_after(&client, after_id, &STREAMED, REPLAY_PAGE).await?;
+        if !send_replayed(&client, filter, &events, tx, replayed).await? {
+            return Ok(false);
+        }
+        match events.last() {
+            Some(event) if events.len() as i64 == REPLAY_PAGE => after_id = event.id,
+            _ => return Ok(true),
+        }
+    }
+}
+
+async fn send_replayed(
+    client: &deadpool_postgres::Client,
+    filter: &EventFilter,
+    events: &[OutboxEvent],
+    tx: &mpsc::Sender<Frame>,
+    replayed: &mut HashSet<i64>,
+) -> Result<bool, MyError> {
+    for event in events {
+        if !replayed.insert(event.id) {
+            continue;
+        }
+        for stream_event in stream_events(client, event).await? {
+            if filter.matches(&stream_event)
+                && tx.send(Frame::Event(Arc::new(stream_event))).await.is_err()
+            {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+// stream_events turns an outbox event into stream events. Balances are
+// computed as of the change, so replayed events report the balances of the
+// time rather than the current ones.
+async fn stream_events(
+    client: &deadpool_postgres::Client,
+    event: &OutboxEvent,
+) -> Result<Vec<StreamEvent>, MyError> {
+    let (accounts, as_of) = match event.event_type.as_str() {
+        "transfer.created" => {
+            let Ok(tx) = serde_json::from_value::<Transaction>(event.payload.clone()) else {
+                return Ok(Vec::new());
+            };
+            (
+                tx.from_account.into_iter().chain(tx.to_account).collect(),
+                tx.created_at,
+            )
+        }
+        "account.created" => (
+            event.payload["id"]
+                .as_i64()
+                .into_iter()
+                .collect::<Vec<i64>>(),
+            event.created_at,
+        ),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut events = Vec::new();
+    if event.event_type == "transfer.created" {
+        events.push(StreamEvent {
+            id: event.id,
+            event: TRANSACTION,
+            accounts: accounts.clone(),
+            data: event.payload.clone(),
+        });
+    }
+    if let Some(as_of) = as_of {
+        for account_id in accounts {
+            match db::account_balance_at(client, account_id, as_of).await {
+                Ok(balance) => events.push(StreamEvent {
+                    id: event.id,
+                    event: BALANCE,
+                    accounts: vec![account_id],
+                    data: serde_json::to_value(balance).unwrap_or(Value::Null),
+                }),
+                Err(MyError::NotFound) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(events)
+}
+
+// listen receives the ledger event notifications until shutdown is triggered
+// and broadcasts them to the hub. The connection is re-established when lost,
+// and the events committed in the meantime are broadcast on reconnection.
+pub async fn listen(
+    pool: Pool,
+    pg: PgConfig,
+    pg_tls: PgTlsConfig,
+    hub: Arc<EventHub>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut cursor = Cursor::default();
+
+    loop {
+        match connect(&pg, &pg_tls).await {
+            Ok((_client, mut notifications)) => {
+                log::info!("Listening for ledger events");
+                if let Err(err) = catch_up(&pool, &hub, &mut cursor).await {
+                    log::error!("Failed to catch up on ledger events: {}", err);
+                }
+                loop {
+                    tokio::select! {
+                        _ = stopped(&mut shutdown) => return,
+                        notification = notifications.recv() => match notification {
+                            Some(notification) => {
+                                if let Err(err) = broadcast(&pool, &hub, &notification, &mut cursor).await {
+                                    log::error!("Failed to stream ledger event {}: {}", notification.payload(), err);
+                                }
+                            }
+                            None => break,
+                        },
+                    }
+                }
+                log::warn!("Ledger event listener disconnected, reconnecting");
+            }
+            Err(err) => log::error!("Ledger event listener failed to connect: {}", err),
+        }
+
+        tokio::select! {
+            _ = stopped(&mut shutdown) => return,
+            _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+        }
+    }
+}
+
+// Cursor tracks what the listener broadcast: the highest event id, and the
+// recent ids so that events found again when catching up are not repeated.
+#[derive(Debug, Default)]
+struct Cursor {
+    last_id: Option<i64>,
+    seen: BTreeSet<i64>,
+}
+
+impl Cursor {
+    // record notes an event id and returns false if it was already recorded.
+    fn record(&mut self, id: i64) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+        self.last_id = Some(self.last_id.map_or(id, |last| last.max(id)));
+        while self.seen.len() > SEEN_IDS {
+            self.seen.pop_first();
+        }
+        true
+    }
+}
+
+// catch_up broadcasts the events committed since the last broadcast one: the
+// lower ids that were in flight when it was written and the ids after it. On
+// the first connection it only records the latest id.
+async fn catch_up(pool: &Pool, hub: &EventHub, cursor: &mut Cursor) -> Result<(), MyError> {
+    let client = pool.get().await?;
+    let Some(mut after_id) = cursor.last_id else {
+        cursor.last_id = Some(db::last_outbox_id(&client).await?);
+        return Ok(());
+    };
+    for event in db::get_outbox_events_committed_after(&client, after_id, &STREAMED).await? {
+        publish(&client, hub, cursor, &event).await?;
+    }
+    loop {
+        let events = db::get_outbox_events_after(&client, after_id, &STREAMED, REPLAY_PAGE).await?;
+        for event in &events {
+            after_id = event.id;
+            publish(&client, hub, cursor, event).await?;
+        }
+        if (events.len() as i64) < REPLAY_PAGE {
+            return Ok(());
+        }
+    }
+}
+
+async fn broadcast(
+    pool: &Pool,
+    hub: &EventHub,
+    notification: &Notification,
+    cursor: &mut Cursor,
+) -> Result<(), MyError> {
+    let Ok(id) = notification.payload().parse::<i64>() else {
+        return Ok(());
+    };
+    let client = pool.get().await?;
+    let Some(event) = db::get_outbox_event(&client, id).await? else {
+        return Ok(());
+    };
+    if !STREAMED.contains(&event.event_type.as_str()) {
+        cursor.record(id);
+        return Ok(());
+    }
+    publish(&client, hub, cursor, &event).await
+}
+
+// publish broadcasts the stream events of an outbox event not broadcast yet.
+async fn publish(
+    client: &deadpool_postgres::Client,
+    hub: &EventHub,
+    cursor: &mut Cursor,
+    event: &OutboxEvent,
+) -> Result<(), MyError> {
+    if cursor.seen.contains(&event.id) {
+        return Ok(());
+    }
+    let stream_events = stream_events(client, event).await?;
+    cursor.record(event.id);
+    for stream_event in stream_events {
+        // no receivers is not an error
+        let _ = hub.sender.send(Arc::new(stream_event));
+    }
+    Ok(())
+}
+
+// connect opens a dedicated connection listening on the ledger events channel.
+// Pooled connections cannot be used as they drop notifications.
+async fn connect(
+    pg: &PgConfig,
+    pg_tls: &PgTlsConfig,
+) -> Result<(Client, mpsc::UnboundedReceiver<Notification>), Box<dyn std::error::Error>> {
+    let config = pg.get_pg_config()?;
+    let (client, notifications) = match pg.ssl_mode {
+        Some(SslMode::Disable) => drive(config.connect(NoTls).await?),
+        _ => drive(config.connect(tls::pg_connector(pg_tls)?).await?),
+    };
+    client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+    Ok((client, notifications))
+}
+
+// drive runs the connection, forwarding its notifications until it closes.
+fn drive<S, T>(
+    (client, mut connection): (Client, Connection<S, T>),
+) -> (Client, mpsc::UnboundedReceiver<Notification>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + 'static,
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    rt::spawn(async move {
+        let mut messages = poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    if tx.send(notification).is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    log::warn!("Ledger event connection error: {}", err);
+                    return;
+                }
+            }
+        }
+    });
+    (client, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cursor, SEEN_IDS};
+    use crate::{
+        config::{Config, LoadOptions},
+        db,
+        server::create_pool,
+    };
+
+    #[test]
+    fn cursor_skips_recorded_ids() {
+        let mut cursor = Cursor::default();
+        assert!(cursor.record(7));
+        assert!(cursor.record(5));
+        assert!(!cursor.record(7));
+        assert_eq!(cursor.last_id, Some(7));
+    }
+
+    #[test]
+    fn cursor_forgets_oldest_ids() {
+        let mut cursor = Cursor::default();
+        for id in 0..=SEEN_IDS as i64 {
+            cursor.record(id);
+        }
+        assert_eq!(cursor.seen.len(), SEEN_IDS);
+        assert!(!cursor.seen.contains(&0));
+        assert_eq!(cursor.last_id, Some(SEEN_IDS as i64));
+    }
+
+    // A transaction takes an outbox id, another one takes a higher id and
+    // commits first: a reader resuming after the higher id must still get the
+    // lower one once it commits.
+    #[actix_web::test]
+    #[ignore = "needs a PostgreSQL database with the migrations applied"]
+    async fn late_committing_lower_id_is_replayed() {
+        let config = Config::load(&LoadOptions::default()).unwrap().config;
+        let pool = create_pool(&config).unwrap();
+        let early = pool.get().await.unwrap();
+        let late = pool.get().await.unwrap();
+        let insert = "INSERT INTO outbox (event_type, aggregate, aggregate_id, payload)
+            VALUES ('test.late_commit', 'test', 0, '{}') RETURNING id";
+
+        early.batch_execute("BEGIN").await.unwrap();
+        let lower: i64 = early.query_one(insert, &[]).await.unwrap().get(0);
+        let higher: i64 = late.query_one(insert, &[]).await.unwrap().get(0);
+        early.batch_execute("COMMIT").await.unwrap();
+
+        let types = ["test.late_commit"];
+        let after = db::get_outbox_events_after(&late, higher, &types, 10)
+            .await
+            .unwrap();
+        let committed_after = db::get_outbox_events_committed_after(&late, higher, &types)
+            .await
+            .unwrap();
+        let before_lower = db::get_outbox_events_committed_after(&late, lower, &types)
+            .await
+            .unwrap();
+        late.execute("DELETE FROM outbox WHERE event_type = $1", &[&types[0]])
+            .await
+            .unwrap();
+
+        assert!(after.is_empty());
+        assert_eq!(
+            committed_after.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![lower]
+        );
+        assert!(before_lower.iter().all(|e| e.id != lower));
+    }
+}
@@ -0,0 +1,531 @@
+// GraphQL API served at /graphql, with GraphiQL on GET. Accounts and
+// transactions are exposed with their relationships (account -> transactions,
+// transaction -> from/to account), filters and cursor pagination, plus a
+// transfer mutation running the same ledger operation as PUT /create-tx, and
+// charged to its rate limit bucket.
+// Related rows are fetched through per-request data loaders, which batch the
+// lookups of every node of a page into one query.
+use crate::{
+    audit::AuditContext,
+    auth::{Principal, Scope},
+    config::GraphqlConfig,
+    db,
+    ledger::{self, LedgerError},
+    model::{Account, AccountFilter, Transaction, TransactionFilter, TransactionParams},
+    ratelimit::RateLimiter,
+};
+use async_graphql::{
+    connection::{Connection, Edge},
+    dataloader::{DataLoader, Loader},
+    Context, EmptySubscription, Error, ErrorExtensions, InputObject, Object, OutputType, Request,
+    Result, Schema,
+};
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub type LedgerSchema = Schema<QueryRoot, MutationRoot, EmptySubscription>;
+
+// schema builds the schema shared by every request. The pool, the limits and
+// the rate limiter are available to every resolver.
+pub fn schema(pool: Pool, config: &GraphqlConfig, limiter: Arc<RateLimiter>) -> LedgerSchema {
+    Schema::build(QueryRoot, MutationRoot, EmptySubscription)
+        .data(pool)
+        .data(config.clone())
+        .data(limiter)
+        .limit_depth(config.max_depth)
+        .limit_complexity(config.max_complexity)
+        .finish()
+}
+
+// Caller is the authenticated caller of a request, None when authentication is
+// disabled, with the context recorded in the audit log.
+struct Caller {
+    principal: Option<Principal>,
+    context: AuditContext,
+}
+
+impl Caller {
+    fn owns(&self, account_id: i64) -> bool {
+        self.principal.as_ref().is_none_or(|p| p.owns(account_id))
+    }
+
+    // owned lists the accounts the caller is restricted to, if any.
+    fn owned(&self) -> Option<&Vec<i64>> {
+        self.principal.as_ref().and_then(|p| p.accounts.as_ref())
+    }
+}
+
+// prepare attaches the caller and fresh data loaders to a request, so that
+// loaded rows are never shared between callers.
+pub fn prepare(
+    request: Request,
+    pool: &Pool,
+    principal: Option<Principal>,
+    context: AuditContext,
+) -> Request {
+    request
+        .data(Caller { principal, context })
+        .data(DataLoader::new(
+            AccountLoader { pool: pool.clone() },
+            tokio::spawn,
+        ))
+        .data(DataLoader::new(
+            AccountTransactionsLoader { pool: pool.clone() },
+            tokio::spawn,
+        ))
+}
+
+// error converts a ledger error to a GraphQL error, with a code extension
+// naming the HTTP status the JSON API would have returned.
+fn error(err: &LedgerError) -> Error {
+    let code = match err {
+        LedgerError::Invalid(_) => "BAD_REQUEST",
+        LedgerError::Forbidden(_) => "FORBIDDEN",
+        LedgerError::NotFound => "NOT_FOUND",
+        LedgerError::Conflict(_) | LedgerError::Duplicate(_) => "CONFLICT",
+        LedgerError::Limited(..) => "TOO_MANY_REQUESTS",
+        LedgerError::Internal(_) => "INTERNAL_SERVER_ERROR",
+    };
+    Error::new(err.to_string()).extend_with(|_, extensions| {
+        extensions.set("code", code);
+        if let LedgerError::Limited(_, retry_after) = err {
+            // whole seconds, rounded up as in the HTTP Retry-After header
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            extensions.set("retry_after_secs", secs);
+        }
+        if let LedgerError::Duplicate(tx) = err {
+            extensions.set("transaction_id", tx.id.unwrap_or_default());
+        }
+    })
+}
+
+async fn client(ctx: &Context<'_>) -> Result<Client> {
+    ctx.data_unchecked::<Pool>()
+        .get()
+        .await
+        .map_err(|err| error(&LedgerError::Internal(err.to_string())))
+}
+
+// page reads the first and after arguments of a connection. after is the
+// cursor of the last edge seen, the id of its node.
+fn page(
+    ctx: &Context<'_>,
+    first: Option<i32>,
+    after: Option<String>,
+) -> Result<(i64, Option<i64>)> {
+    let config = ctx.data_unchecked::<GraphqlConfig>();
+    let limit = match first {
+        None => config.default_page_size,
+        Some(first) if (0..=config.max_page_size).contains(&first) => first,
+        Some(_) => {
+            return Err(error(&LedgerError::Invalid(format!(
+                "first must be between 0 and {}",
+                config.max_page_size
+            ))))
+        }
+    };
+    let after = match after {
+        Some(after) => Some(after.parse::<i64>().map_err(|_| {
+            error(&LedgerError::Invalid(
+                "after is not a valid cursor".to_string(),
+            ))
+        })?),
+        None => None,
+    };
+    Ok((i64::from(limit), after))
+}
+
+// connection builds a page from up to limit + 1 rows ordered by id, the extra
+// row telling whether there is a next page.
+fn connection<T, N: OutputType>(
+    mut rows: Vec<T>,
+    after: Option<i64>,
+    limit: i64,
+    id: fn(&T) -> i64,
+    node: fn(T) -> N,
+) -> Connection<i64, N> {
+    let has_next_page = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    let mut connection = Connection::new(after.is_some(), has_next_page);
+    connection
+        .edges
+        .extend(rows.into_iter().map(|row| Edge::new(id(&row), node(row))));
+    connection
+}
+
+// load_account resolves a relationship to an account. Callers restricted to
+// some accounts may not see the others, as on GET /account-by-id.
+async fn load_account(ctx: &Context<'_>, account_id: Option<i64>) -> Result<Option<AccountNode>> {
+    let Some(account_id) = account_id else {
+        return Ok(None);
+    };
+    if !ctx.data_unchecked::<Caller>().owns(account_id) {
+        return Err(error(&LedgerError::Forbidden(
+            "Account not owned by caller".to_string(),
+        )));
+    }
+    let account = ctx
+        .data_unchecked::<DataLoader<AccountLoader>>()
+        .load_one(account_id)
+        .await
+        .map_err(|err| error(&err))?;
+    Ok(account.map(AccountNode))
+}
+
+pub struct AccountNode(Account);
+
+#[Object(name = "Account")]
+impl AccountNode {
+    async fn id(&self) -> i64 {
+        self.0.id.unwrap_or_default()
+    }
+
+    async fn username(&self) -> &str {
+        self.0.username.as_deref().unwrap_or_default()
+    }
+
+    async fn email(&self) -> Option<&str> {
+        self.0.email.as_deref()
+    }
+
+    async fn balance(&self) -> i64 {
+        self.0.balance.unwrap_or_default()
+    }
+
+    async fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.0.created_at
+    }
+
+    async fn metadata(&self) -> Option<&Value> {
+        self.0.metadata.as_ref()
+    }
+
+    async fn tags(&self) -> &[String] {
+        self.0.tags.as_deref().unwrap_or_default()
+    }
+
+    // transfers from or to the account, oldest first
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<i64, TransactionNode>> {
+        let (limit, after) = page(ctx, first, after)?;
+        let key = AccountTransactionsKey {
+            account_id: self.0.id.unwrap_or_default(),
+            after,
+            limit: limit + 1,
+        };
+        let txs = ctx
+            .data_unchecked::<DataLoader<AccountTransactionsLoader>>()
+            .load_one(key)
+            .await
+            .map_err(|err| error(&err))?
+            .unwrap_or_default();
+        Ok(connection(
+            txs,
+            after,
+            limit,
+            |tx| tx.id.unwrap_or_default(),
+            TransactionNode,
+        ))
+    }
+}
+
+pub struct TransactionNode(Transaction);
+
+#[Object(name = "Transaction")]
+impl TransactionNode {
+    async fn id(&self) -> i64 {
+        self.0.id.unwrap_or_default()
+    }
+
+    async fn from_account_id(&self) -> Option<i64> {
+        self.0.from_account
+    }
+
+    async fn to_account_id(&self) -> Option<i64> {
+        self.0.to_account
+    }
+
+    async fn amount(&self) -> Option<i64> {
+        self.0.amount
+    }
+
+    async fn created_at(&self) -> Option<DateTime<Utc>> {
+        self.0.created_at
+    }
+
+    async fn description(&self) -> Option<&str> {
+        self.0.description.as_deref()
+    }
+
+    async fn reference(&self) -> Option<&str> {
+        self.0.reference.as_deref()
+    }
+
+    async fn category(&self) -> Option<&str> {
+        self.0.category.as_deref()
+    }
+
+    async fn external_id(&self) -> Option<&str> {
+        self.0.external_id.as_deref()
+    }
+
+    // the debited account
+    #[graphql(name = "fromAccount")]
+    async fn debited_account(&self, ctx: &Context<'_>) -> Result<Option<AccountNode>> {
+        load_account(ctx, self.0.from_account).await
+    }
+
+    // the credited account
+    async fn to_account(&self, ctx: &Context<'_>) -> Result<Option<AccountNode>> {
+        load_account(ctx, self.0.to_account).await
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn account(&self, ctx: &Context<'_>, id: i64) -> Result<Option<AccountNode>> {
+        let caller = ctx.data_unchecked::<Caller>();
+        let client = client(ctx).await?;
+        match ledger::get_account(&client, caller.principal.as_ref(), id).await {
+            Ok(account) => Ok(Some(AccountNode(account))),
+            Err(LedgerError::NotFound) => Ok(None),
+            Err(err) => Err(error(&err)),
+        }
+    }
+
+    // accounts matching the filter, ordered by id. Callers restricted to some
+    // accounts only see those.
+    async fn accounts(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<AccountFilter>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<i64, AccountNode>> {
+        let (limit, after) = page(ctx, first, after)?;
+        let caller = ctx.data_unchecked::<Caller>();
+        let client = client(ctx).await?;
+        let accounts = db::find_accounts(
+            &client,
+            &filter.unwrap_or_default(),
+            caller.owned(),
+            after,
+            Some(limit + 1),
+        )
+        .await
+        .map_err(|err| error(&err.into()))?;
+        Ok(connection(
+            accounts,
+            after,
+            limit,
+            |account| account.id.unwrap_or_default(),
+            AccountNode,
+        ))
+    }
+
+    async fn transaction(&self, ctx: &Context<'_>, id: i64) -> Result<Option<TransactionNode>> {
+        let caller = ctx.data_unchecked::<Caller>();
+        let client = client(ctx).await?;
+        match ledger::get_transaction(&client, caller.principal.as_ref(), id).await {
+            Ok(tx) => Ok(Some(TransactionNode(tx))),
+            Err(LedgerError::NotFound) => Ok(None),
+            Err(err) => Err(error(&err)),
+        }
+    }
+
+    // transactions matching the filter, ordered by id. Callers restricted to
+    // some accounts only see the transfers involving them.
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<TransactionFilter>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<i64, TransactionNode>> {
+        let (limit, after) = page(ctx, first, after)?;
+        let caller = ctx.data_unchecked::<Caller>();
+        let client = client(ctx).await?;
+        let txs = db::find_transactions(
+            &client,
+            &filter.unwrap_or_default(),
+            caller.owned(),
+            after,
+            Some(limit + 1),
+        )
+        .await
+        .map_err(|err| error(&err.into()))?;
+        Ok(connection(
+            txs,
+            after,
+            limit,
+            |tx| tx.id.unwrap_or_default(),
+            TransactionNode,
+        ))
+    }
+}
+
+pub struct MutationRoot;
+
+// TransferDetails holds the optional details of a transfer.
+#[derive(Default, InputObject)]
+pub struct TransferDetails {
+    pub description: Option<String>,
+    pub reference: Option<String>,
+    pub category: Option<String>,
+    // the caller's own id of the transfer, unique per caller
+    pub external_id: Option<String>,
+}
+
+#[Object]
+impl MutationRoot {
+    // transfer moves amount between two accounts, as PUT /create-tx. It needs
+    // the transfer scope and a token from the caller's /create-tx bucket, so
+    // that a query with many transfers is limited as many requests.
+    // Repeating an external id fails with a CONFLICT error whose
+    // transaction_id extension is the id of the existing transaction.
+    async fn transfer(
+        &self,
+        ctx: &Context<'_>,
+        from_account: i64,
+        to_account: i64,
+        amount: i64,
+        details: Option<TransferDetails>,
+    ) -> Result<TransactionNode> {
+        let caller = ctx.data_unchecked::<Caller>();
+        if caller
+            .principal
+            .as_ref()
+            .is_some_and(|p| !p.has_scope(Scope::Transfer))
+        {
+            return Err(error(&LedgerError::Forbidden(format!(
+                "Credentials are missing the {} scope",
+                Scope::Transfer
+            ))));
+        }
+        let limiter = ctx.data_unchecked::<Arc<RateLimiter>>();
+        limiter
+            .charge(
+                "/create-tx",
+                caller.principal.as_ref(),
+                caller.context.source_ip.as_deref(),
+            )
+            .map_err(|retry_after| {
+                error(&LedgerError::Limited(
+                    "Rate limit exceeded".to_string(),
+                    retry_after,
+                ))
+            })?;
+        let details = details.unwrap_or_default();
+        let mut client = client(ctx).await?;
+        let params = TransactionParams {
+            id: None,
+            from_account: Some(from_account),
+            to_account: Some(to_account),
+            amount: Some(amount),
+            description: details.description,
+            reference: details.reference,
+            category: details.category,
+            external_id: details.external_id,
+        };
+        let tx = ledger::create_transaction(
+            &mut client,
+            caller.principal.as_ref(),
+            &caller.context,
+            limiter.velocity(),
+            params,
+        )
+        .await
+        .map_err(|err| error(&err))?;
+        Ok(TransactionNode(tx))
+    }
+}
+
+// AccountLoader batches account lookups by id.
+pub struct AccountLoader {
+    pool: Pool,
+}
+
+impl Loader<i64> for AccountLoader {
+    type Value = Account;
+    type Error = Arc<LedgerError>;
+
+    async fn load(&self, keys: &[i64]) -> Result<HashMap<i64, Account>, Self::Error> {
+        let client = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| Arc::new(LedgerError::Internal(err.to_string())))?;
+        let accounts = db::get_accounts_by_ids(&client, keys)
+            .await
+            .map_err(|err| Arc::new(err.into()))?;
+        Ok(accounts
+            .into_iter()
+            .map(|account| (account.id.unwrap_or_default(), account))
+            .collect())
+    }
+}
+
+// AccountTransactionsKey selects a page of the transactions of an account.
+// limit includes the extra row used to detect a next page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AccountTransactionsKey {
+    account_id: i64,
+    after: Option<i64>,
+    limit: i64,
+}
+
+// AccountTransactionsLoader batches the transaction pages of many accounts,
+// with one query per distinct page (usually the first page of every account).
+pub struct AccountTransactionsLoader {
+    pool: Pool,
+}
+
+impl Loader<AccountTransactionsKey> for AccountTransactionsLoader {
+    type Value = Vec<Transaction>;
+    type Error = Arc<LedgerError>;
+
+    async fn load(
+        &self,
+        keys: &[AccountTransactionsKey],
+    ) -> Result<HashMap<AccountTransactionsKey, Vec<Transaction>>, Self::Error> {
+        let client = self
+            .pool
+            .get()
+            .await
+            .map_err(|err| Arc::new(LedgerError::Internal(err.to_string())))?;
+
+        let mut pages: HashMap<(Option<i64>, i64), Vec<i64>> = HashMap::new();
+        for key in keys {
+            pages
+                .entry((key.after, key.limit))
+                .or_default()
+                .push(key.account_id);
+        }
+
+        let mut results: HashMap<AccountTransactionsKey, Vec<Transaction>> =
+            keys.iter().map(|key| (*key, Vec::new())).collect();
+        for ((after, limit), account_ids) in pages {
+            let rows = db::get_account_transactions(&client, &account_ids, after, limit)
+                .await
+                .map_err(|err| Arc::new(err.into()))?;
+            for (account_id, tx) in rows {
+                let key = AccountTransactionsKey {
+                    account_id,
+                    after,
+                    limit,
+                };
+                results.entry(key).or_default().push(tx);
+            }
+        }
+        Ok(results)
+    }
+}
@@ -0,0 +1,508 @@
+// gRPC API served alongside the HTTP JSON API on grpc_addr (see
+// proto/ledger.proto). The services share the connection pool, the
+// authentication and the ledger operations of the HTTP handlers. Clients send
+// credentials in the x-api-key or authorization metadata and may tag calls
+// with x-request-id, which is recorded in the audit log.
+use crate::{
+    audit::AuditContext,
+    auth::{authenticate, Principal, Scope, API_KEY_HEADER},
+    config::{AccountsConfig, AuthConfig, HealthConfig, ServerTlsConfig},
+    health,
+    jwt::JwtVerifier,
+    ledger::{self, LedgerError},
+    middleware::{MAX_REQUEST_ID_LEN, REQUEST_ID_HEADER},
+    model::{
+        self, AccountFilter, AccountParams, CheckStatus, TransactionFilter, TransactionParams,
+    },
+    ratelimit::{ip_client, principal_client, RateLimiter},
+    shutdown::{stopped, Shutdown},
+    tls,
+};
+use actix_web::http::StatusCode;
+use chrono::{DateTime, Utc};
+use deadpool_postgres::{Client, Pool};
+use serde_json::Value;
+use std::future::Future;
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tonic::{
+    metadata::MetadataValue,
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+use uuid::Uuid;
+
+pub mod proto {
+    tonic::include_proto!("ledger.v1");
+}
+
+use proto::{
+    account_service_server::{AccountService, AccountServiceServer},
+    health_service_server::{HealthService, HealthServiceServer},
+    transaction_service_server::{TransactionService, TransactionServiceServer},
+};
+
+// LedgerService implements every gRPC service.
+#[derive(Clone)]
+pub struct LedgerService {
+    pub pool: Pool,
+    pub auth: AuthConfig,
+    pub verifier: Option<Arc<JwtVerifier>>,
+    pub limiter: Arc<RateLimiter>,
+    pub accounts: AccountsConfig,
+    pub health: HealthConfig,
+    pub shutdown: Arc<Shutdown>,
+}
+
+// Caller is the authenticated caller of a call, None when authentication is
+// disabled, with the context recorded in the audit log.
+struct Caller {
+    principal: Option<Principal>,
+    context: AuditContext,
+}
+
+impl LedgerService {
+    // authorize authenticates the caller, when authentication is enabled, and
+    // checks that it holds scope. The call is charged to the rate limit bucket
+    // of the matching HTTP route, so that switching protocols does not escape
+    // the limits: to the peer IP's bucket before the credentials are checked,
+    // then to the verified principal's instead.
+    async fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        route: &str,
+        scope: Scope,
+    ) -> Result<Caller, Status> {
+        let metadata = request.metadata();
+        let source_ip = request.remote_addr().map(|addr| addr.ip().to_string());
+        let ip = ip_client(source_ip.as_deref());
+        let limited = self.limiter.enabled();
+        if limited {
+            self.limiter.check(route, &ip).map_err(rate_limited)?;
+        }
+
+        let request_id = metadata
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+            .map(String::from)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        let principal = if self.auth.enabled {
+            let bearer = metadata
+                .get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "));
+            let api_key = metadata.get(API_KEY_HEADER).and_then(|v| v.to_str().ok());
+            let principal =
+                authenticate(Some(&self.pool), self.verifier.as_deref(), bearer, api_key)
+                    .await
+                    .map_err(|(status, message)| match status {
+                        StatusCode::UNAUTHORIZED => Status::unauthenticated(message),
+                        StatusCode::SERVICE_UNAVAILABLE => Status::unavailable(message),
+                        _ => Status::internal(message),
+                    })?;
+            if limited {
+                self.limiter.refund(route, &ip);
+                self.limiter
+                    .check(route, &principal_client(&principal))
+                    .map_err(rate_limited)?;
+            }
+            if !principal.has_scope(scope) {
+                return Err(Status::permission_denied(format!(
+                    "Credentials are missing the {} scope",
+                    scope
+                )));
+            }
+            Some(principal)
+        } else {
+            None
+        };
+
+        Ok(Caller {
+            context: AuditContext {
+                actor: principal
+                    .as_ref()
+                    .map(|p| p.name.clone())
+                    .unwrap_or_else(|| "anonymous".to_string()),
+                request_id: Some(request_id),
+                source_ip,
+            },
+            principal,
+        })
+    }
+
+    async fn client(&self) -> Result<Client, Status> {
+        self.pool
+            .get()
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))
+    }
+}
+
+// rate_limited answers a call arriving on an empty rate limit bucket.
+fn rate_limited(retry_after: Duration) -> Status {
+    LedgerError::Limited("Rate limit exceeded".to_string(), retry_after).into()
+}
+
+impl From<LedgerError> for Status {
+    fn from(err: LedgerError) -> Self {
+        match err {
+            LedgerError::Invalid(message) => Status::invalid_argument(message),
+            LedgerError::Forbidden(message) => Status::permission_denied(message),
+            LedgerError::NotFound => Status::not_found("NotFound"),
+            LedgerError::Conflict(message) => Status::already_exists(message),
+            LedgerError::Limited(message, retry_after) => {
+                let mut status = Status::resource_exhausted(message);
+                // whole seconds, rounded up as in the HTTP Retry-After header
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                status
+                    .metadata_mut()
+                    .insert("retry-after", MetadataValue::from(secs));
+                status
+            }
+            LedgerError::Duplicate(ref tx) => {
+                let mut status = Status::already_exists(err.to_string());
+                status.metadata_mut().insert(
+                    "transaction-id",
+                    MetadataValue::from(tx.id.unwrap_or_default()),
+                );
+                status
+            }
+            LedgerError::Internal(message) => Status::internal(message),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AccountService for LedgerService {
+    async fn create_account(
+        &self,
+        request: Request<proto::CreateAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let caller = self
+            .authorize(&request, "/create-account", Scope::CreateAccounts)
+            .await?;
+        let params = request.into_inner();
+        let mut client = self.client().await?;
+
+        let account = ledger::create_account(
+            &mut client,
+            &caller.context,
+            &self.accounts,
+            AccountParams {
+                id: None,
+                username: params.username,
+                email: params.email,
+                balance: None,
+                metadata: parse_metadata(params.metadata)?,
+                tags: Some(params.tags).filter(|tags| !tags.is_empty()),
+            },
+        )
+        .await?;
+        Ok(Response::new(account.into()))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let caller = self
+            .authorize(&request, "/account-by-id", Scope::Read)
+            .await?;
+        let client = self.client().await?;
+
+        let account =
+            ledger::get_account(&client, caller.principal.as_ref(), request.get_ref().id).await?;
+        Ok(Response::new(account.into()))
+    }
+
+    async fn list_accounts(
+        &self,
+        request: Request<proto::ListAccountsRequest>,
+    ) -> Result<Response<proto::ListAccountsResponse>, Status> {
+        let caller = self.authorize(&request, "/accounts", Scope::Read).await?;
+        let params = request.into_inner();
+        let filter = AccountFilter {
+            tags: Some(params.tags).filter(|tags| !tags.is_empty()),
+            metadata: parse_metadata(params.metadata)?,
+            ..Default::default()
+        };
+        let client = self.client().await?;
+
+        let accounts = ledger::get_accounts(&client, caller.principal.as_ref(), &filter).await?;
+        Ok(Response::new(proto::ListAccountsResponse {
+            accounts: accounts.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_balance(
+        &self,
+        request: Request<proto::GetBalanceRequest>,
+    ) -> Result<Response<proto::AccountBalance>, Status> {
+        let caller = self
+            .authorize(&request, "/accounts/{id}/balance", Scope::Read)
+            .await?;
+        let params = request.into_inner();
+        let as_of = parse_time("as_of", params.as_of)?;
+        let client = self.client().await?;
+
+        let balance =
+            ledger::get_balance(&client, caller.principal.as_ref(), params.account_id, as_of)
+                .await?;
+        Ok(Response::new(balance.into()))
+    }
+
+    async fn list_balances(
+        &self,
+        request: Request<proto::ListBalancesRequest>,
+    ) -> Result<Response<proto::ListBalancesResponse>, Status> {
+        let caller = self
+            .authorize(&request, "/accounts/balances", Scope::Read)
+            .await?;
+        let as_of = parse_time("as_of", request.into_inner().as_of)?;
+        let client = self.client().await?;
+
+        let balances = ledger::get_balances(&client, caller.principal.as_ref(), as_of).await?;
+        Ok(Response::new(proto::ListBalancesResponse {
+            balances: balances.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl TransactionService for LedgerService {
+    async fn create_transaction(
+        &self,
+        request: Request<proto::CreateTransactionRequest>,
+    ) -> Result<Response<proto::Transaction>, Status> {
+        let caller = self
+            .authorize(&request, "/create-tx", Scope::Transfer)
+            .await?;
+        let params = request.into_inner();
+        let mut client = self.client().await?;
+
+        let tx = ledger::create_transaction(
+            &mut client,
+            caller.principal.as_ref(),
+            &caller.context,
+            self.limiter.velocity(),
+            TransactionParams {
+                id: None,
+                from_account: params.from_account,
+                to_account: params.to_account,
+                amount: params.amount,
+                description: params.description,
+                reference: params.reference,
+                category: params.category,
+                external_id: params.external_id,
+            },
+        )
+        .await?;
+        Ok(Response::new(tx.into()))
+    }
+
+    async fn get_transaction(
+        &self,
+        request: Request<proto::GetTransactionRequest>,
+    ) -> Result<Response<proto::Transaction>, Status> {
+        let caller = self
+            .authorize(&request, "/transaction-by-id", Scope::Read)
+            .await?;
+        let client = self.client().await?;
+
+        let tx = ledger::get_transaction(&client, caller.principal.as_ref(), request.get_ref().id)
+            .await?;
+        Ok(Response::new(tx.into()))
+    }
+
+    async fn list_transactions(
+        &self,
+        request: Request<proto::ListTransactionsRequest>,
+    ) -> Result<Response<proto::ListTransactionsResponse>, Status> {
+        let caller = self
+            .authorize(&request, "/transactions", Scope::Read)
+            .await?;
+        let params = request.into_inner();
+        let filter = TransactionFilter {
+            description: params.description,
+            reference: params.reference,
+            category: params.category,
+            external_id: params.external_id,
+            ..Default::default()
+        };
+        let client = self.client().await?;
+
+        let txs = ledger::get_transactions(&client, caller.principal.as_ref(), &filter).await?;
+        Ok(Response::new(proto::ListTransactionsResponse {
+            transactions: txs.into_iter().map(Into::into).collect(),
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl HealthService for LedgerService {
+    async fn live(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        Ok(Response::new(health::new_health(Vec::new()).into()))
+    }
+
+    async fn ready(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> Result<Response<proto::HealthResponse>, Status> {
+        let health = health::readiness(&self.pool, &self.health, &self.shutdown).await;
+        if health.status == CheckStatus::Fail {
+            let failed: Vec<&str> = health
+                .checks
+                .iter()
+                .filter(|check| check.status == CheckStatus::Fail)
+                .map(|check| check.name.as_str())
+                .collect();
+            return Err(Status::unavailable(format!(
+                "Failed checks: {}",
+                failed.join(", ")
+            )));
+        }
+        Ok(Response::new(health.into()))
+    }
+}
+
+// serve binds the gRPC listener and returns the server, which runs until
+// shutdown is triggered and then keeps serving for grace before draining the
+// in-flight calls. Binding and TLS errors are returned before serving starts.
+pub async fn serve(
+    addr: &str,
+    tls_config: Option<&ServerTlsConfig>,
+    service: LedgerService,
+    mut shutdown: watch::Receiver<bool>,
+    grace: Duration,
+) -> std::io::Result<impl Future<Output = ()>> {
+    let listener = TcpListener::bind(addr).await?;
+    let incoming = TcpIncoming::from_listener(listener, true, None).map_err(Error::other)?;
+
+    let mut builder = Server::builder();
+    if let Some(tls_config) = tls_config {
+        builder = builder
+            .tls_config(tls::grpc_server_config(tls_config)?)
+            .map_err(Error::other)?;
+    }
+    let router = builder
+        .add_service(AccountServiceServer::new(service.clone()))
+        .add_service(TransactionServiceServer::new(service.clone()))
+        .add_service(HealthServiceServer::new(service));
+
+    Ok(async move {
+        let stop = async move {
+            stopped(&mut shutdown).await;
+            tokio::time::sleep(grace).await;
+        };
+        if let Err(err) = router.serve_with_incoming_shutdown(incoming, stop).await {
+            log::error!("gRPC server failed: {}", err);
+        }
+    })
+}
+
+// parse_time reads an optional RFC 3339 timestamp parameter.
+fn parse_time(name: &str, value: Option<String>) -> Result<Option<DateTime<Utc>>, LedgerError> {
+    value
+        .map(|value| {
+            value.parse::<DateTime<Utc>>().map_err(|err| {
+                LedgerError::Invalid(format!("{} is not an RFC 3339 timestamp: {}", name, err))
+            })
+        })
+        .transpose()
+}
+
+// parse_metadata reads an optional JSON object parameter.
+fn parse_metadata(value: Option<String>) -> Result<Option<Value>, LedgerError> {
+    value
+        .map(|value| match serde_json::from_str::<Value>(&value) {
+            Ok(metadata) if metadata.is_object() => Ok(metadata),
+            _ => Err(LedgerError::Invalid(
+                "metadata must be a JSON object".to_string(),
+            )),
+        })
+        .transpose()
+}
+
+fn format_time(time: Option<DateTime<Utc>>) -> String {
+    time.map(|time| time.to_rfc3339()).unwrap_or_default()
+}
+
+impl From<model::Account> for proto::Account {
+    fn from(account: model::Account) -> Self {
+        proto::Account {
+            id: account.id.unwrap_or_default(),
+            username: account.username.unwrap_or_default(),
+            email: account.email.unwrap_or_default(),
+            balance: account.balance.unwrap_or_default(),
+            created_at: format_time(account.created_at),
+            metadata: account
+                .metadata
+                .map(|metadata| metadata.to_string())
+                .unwrap_or_default(),
+            tags: account.tags.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<model::Transaction> for proto::Transaction {
+    fn from(tx: model::Transaction) -> Self {
+        proto::Transaction {
+            id: tx.id.unwrap_or_default(),
+            from_account: tx.from_account.unwrap_or_default(),
+            to_account: tx.to_account.unwrap_or_default(),
+            amount: tx.amount.unwrap_or_default(),
+            created_at: format_time(tx.created_at),
+            description: tx.description,
+            reference: tx.reference,
+            category: tx.category,
+            external_id: tx.external_id,
+        }
+    }
+}
+
+impl From<model::AccountBalance> for proto::AccountBalance {
+    fn from(balance: model::AccountBalance) -> Self {
+        proto::AccountBalance {
+            account_id: balance.account_id,
+            balance: balance.balance,
+            as_of: format_time(balance.as_of),
+        }
+    }
+}
+
+impl From<CheckStatus> for proto::CheckStatus {
+    fn from(status: CheckStatus) -> Self {
+        match status {
+            CheckStatus::Pass => proto::CheckStatus::Pass,
+            CheckStatus::Fail => proto::CheckStatus::Fail,
+        }
+    }
+}
+
+impl From<model::Health> for proto::HealthResponse {
+    fn from(health: model::Health) -> Self {
+        proto::HealthResponse {
+            service: health.service,
+            version: health.version,
+            status: proto::CheckStatus::from(health.status).into(),
+            checks: health
+                .checks
+                .into_iter()
+                .map(|check| proto::HealthCheck {
+                    name: check.name,
+                    status: proto::CheckStatus::from(check.status).into(),
+                    duration_ms: check.duration_ms,
+                    message: check.message.unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
@@ -1,95 +1,390 @@
 use crate::{
+    audit::{self, AuditContext},
+    auth::{owns_account, principal},
+    config::{AccountsConfig, BatchConfig, HealthConfig},
     db,
-    model::{Account, AccountParams, Health, Status, Transaction, TransactionParams},
+    events::{self, EventFilter, EventHub, Frame},
+    graphql::{self, LedgerSchema},
+    health,
+    ledger::{self, BatchOutcome, LedgerError, ScheduleAction},
+    metrics::Metrics,
+    middleware::{record_accounts, request_id},
+    model::{
+        Account, AccountBalance, AccountBatchParams, AccountFilter, AccountParams, AccountUpdate,
+        AccountsQuery, AuditEntry, AuditQuery, BalanceParams, BatchItemResult, BatchMode,
+        BatchResult, CheckStatus, EventsQuery, Health, ReconcileParams, ReconcileReport,
+        ScheduledTransfer, ScheduledTransferParams, ScheduledTransferQuery, SearchQuery,
+        SearchResults, Statement, StatementParams, Status, Transaction, TransactionBatchParams,
+        TransactionFilter, TransactionParams, TransactionsQuery, WebhookDelivery,
+        WebhookDeliveryQuery, WebhookParams, WebhookSubscription,
+    },
+    ratelimit::{too_many_requests, RateLimiter},
+    reconcile, search,
+    shutdown::Shutdown,
+    statement::{self, StatementFormat},
+    webhooks,
 };
-use actix_web::{web, Error, HttpResponse};
-use chrono::Utc;
-use deadpool_postgres::{Client, Pool};
+use actix_web::{
+    error::{JsonPayloadError, PathError, QueryPayloadError},
+    http::{
+        header::{self, ContentDisposition, DispositionParam, DispositionType},
+        StatusCode,
+    },
+    web, Error, HttpRequest, HttpResponse,
+};
+use async_graphql::http::GraphiQLSource;
+use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse};
+use deadpool_postgres::{Client, GenericClient, Pool};
+use futures_util::StreamExt;
+
+// page size of GET /audit when no limit is given, and the largest allowed
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+// page size of the webhook delivery log when no limit is given, and the
+// largest allowed
+const DEFAULT_DELIVERY_LIMIT: i64 = 50;
+const MAX_DELIVERY_LIMIT: i64 = 500;
+
+// page size of GET /search when no limit is given, and the largest allowed
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 100;
 
 // status always responds ok if the service is live and listening for requests
-pub async fn status() -> Result<HttpResponse, Error> {
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "status",
+    responses(
+        (status = 200, description = "Service is up", body = Status),
+    ),
+)]
+pub async fn status(req: HttpRequest) -> Result<HttpResponse, Error> {
     let status_response: Status = Status {
         service: env!("SERVICE_NAME").to_string(),
         message: "OK".to_string(),
         version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
     };
     Ok(HttpResponse::Ok().json(status_response))
 }
 
-// health pings the postgres database, returning a 503 status code if the postgres ping fails.
-pub async fn health(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
-    let mut health_response: Health = Health {
+// livez reports that the process is up and able to serve requests. It does not
+// check any dependency so that a database outage does not cause restarts.
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "status",
+    responses(
+        (status = 200, description = "Process is live", body = Health),
+    ),
+)]
+pub async fn livez(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let mut health_response: Health = health::new_health(Vec::new());
+    health_response.request_id = request_id(&req);
+
+    Ok(HttpResponse::Ok().json(health_response))
+}
+
+// readyz runs the dependency checks (shutdown state, pool saturation, database
+// connectivity and latency, pending migrations), returning a 503 status code if
+// any of them fail. Also served at /health.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "status",
+    responses(
+        (status = 200, description = "All dependency checks pass", body = Health),
+        (status = 503, description = "A dependency check failed", body = Health),
+    ),
+)]
+pub async fn readyz(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+    health_config: web::Data<HealthConfig>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, Error> {
+    let mut health_response: Health = health::readiness(&db_pool, &health_config, &shutdown).await;
+    health_response.request_id = request_id(&req);
+
+    if health_response.status == CheckStatus::Pass {
+        Ok(HttpResponse::Ok().json(health_response))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(health_response))
+    }
+}
+
+// get accounts returns the full (non-paginated) list of user accounts from the
+// postgres DB, optionally filtered on tags and metadata.
+#[utoipa::path(
+    get,
+    path = "/accounts",
+    tag = "accounts",
+    params(AccountsQuery),
+    responses(
+        (status = 200, description = "Accounts visible to the caller", body = Vec<Account>),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_accounts(
+    req: HttpRequest,
+    query: web::Query<AccountsQuery>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let mut response: Status = Status {
         service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
         version: env!("VERSION").to_string(),
-        failures: Vec::new(),
+        request_id: request_id(&req),
+    };
+
+    let query = query.into_inner();
+    let metadata = match query
+        .metadata
+        .as_deref()
+        .map(serde_json::from_str::<serde_json::Value>)
+    {
+        Some(Ok(metadata)) if metadata.is_object() => Some(metadata),
+        Some(_) => {
+            response.message = "metadata must be a JSON object".to_string();
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+        None => None,
+    };
+    let filter = AccountFilter {
+        tags: query.tags.map(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().to_string())
+                .filter(|tag| !tag.is_empty())
+                .collect()
+        }),
+        metadata,
+        ..Default::default()
     };
 
     let client: Client = match db_pool.get().await {
         Ok(client) => client,
         Err(err) => {
-            health_response.failures = vec![err.to_string()];
-            return Ok(HttpResponse::ServiceUnavailable().json(health_response));
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
         }
     };
 
-    match db::ping_db(&client).await {
-        Ok(_) => Ok(HttpResponse::Ok().json(health_response)),
+    match ledger::get_accounts(&client, principal(&req).as_ref(), &filter).await {
+        Ok(users) => Ok(HttpResponse::Ok().json(users)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// get_account_by_id returns the account details for the account with specified index.
+#[utoipa::path(
+    post,
+    path = "/account-by-id",
+    tag = "accounts",
+    request_body = AccountParams,
+    responses(
+        (status = 200, description = "Account", body = Account),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_account_by_id(
+    req: HttpRequest,
+    account_params: web::Json<AccountParams>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let account_info: AccountParams = account_params.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    // check user supplied parameters
+    let Some(account_id) = account_info.id else {
+        response.message = "No id supplied".to_string();
+        return Ok(HttpResponse::BadRequest().json(response));
+    };
+    record_accounts(&req, &[account_id]);
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
         Err(err) => {
-            health_response.failures = vec![err.to_string()];
-            Ok(HttpResponse::ServiceUnavailable().json(health_response))
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
         }
+    };
+
+    match ledger::get_account(&client, principal(&req).as_ref(), account_id).await {
+        Ok(acc) => Ok(HttpResponse::Ok().json(acc)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
     }
 }
 
-// get accounts returns the full (non-paginated) list of user accounts from the
-// postgres DB.
-pub async fn get_accounts(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+// get_account_balance returns the balance of an account at the as_of query
+// parameter (RFC 3339, defaults to now), computed from the transaction history.
+#[utoipa::path(
+    get,
+    path = "/accounts/{id}/balance",
+    tag = "accounts",
+    params(("id" = i64, Path, description = "Account id"), BalanceParams),
+    responses(
+        (status = 200, description = "Balance at as_of", body = AccountBalance),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_account_balance(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    params: web::Query<BalanceParams>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let account_id = path.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    record_accounts(&req, &[account_id]);
+
     let client: Client = match db_pool.get().await {
         Ok(client) => client,
         Err(err) => {
-            let response: Status = Status {
-                service: env!("SERVICE_NAME").to_string(),
-                message: err.to_string(),
-                version: env!("VERSION").to_string(),
-            };
+            response.message = err.to_string();
             return Ok(HttpResponse::ServiceUnavailable().json(response));
         }
     };
 
-    let users = match db::get_accounts(&client).await {
-        Ok(users) => users,
+    let principal = principal(&req);
+    match ledger::get_balance(&client, principal.as_ref(), account_id, params.as_of).await {
+        Ok(balance) => Ok(HttpResponse::Ok().json(balance)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// get_balances returns the balance of every account that existed at the as_of
+// query parameter (RFC 3339, defaults to now).
+#[utoipa::path(
+    get,
+    path = "/accounts/balances",
+    tag = "accounts",
+    params(BalanceParams),
+    responses(
+        (status = 200, description = "Balances at as_of", body = Vec<AccountBalance>),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_balances(
+    req: HttpRequest,
+    params: web::Query<BalanceParams>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
         Err(err) => {
-            let response: Status = Status {
-                service: env!("SERVICE_NAME").to_string(),
-                message: err.to_string(),
-                version: env!("VERSION").to_string(),
-            };
-            return Ok(HttpResponse::InternalServerError().json(response));
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
         }
     };
 
-    Ok(HttpResponse::Ok().json(users))
+    match ledger::get_balances(&client, principal(&req).as_ref(), params.as_of).await {
+        Ok(balances) => Ok(HttpResponse::Ok().json(balances)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
 }
 
-// get_account_by_id returns the account details for the account with specified index.
-pub async fn get_account_by_id(
-    account_params: web::Json<AccountParams>,
+// get_statement returns the statement of an account for [from, to) (RFC 3339,
+// defaulting to the current month to date) as json, csv or text.
+#[utoipa::path(
+    get,
+    path = "/accounts/{id}/statement",
+    tag = "accounts",
+    params(("id" = i64, Path, description = "Account id"), StatementParams),
+    responses(
+        (status = 200, description = "Statement in the requested format", content(
+            (Statement = "application/json"),
+            (String = "text/csv"),
+            (String = "text/plain")
+        )),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_statement(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    params: web::Query<StatementParams>,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
-    let account_info: AccountParams = account_params.into_inner();
+    let account_id = path.into_inner();
+    let params: StatementParams = params.into_inner();
 
     let mut response: Status = Status {
         service: env!("SERVICE_NAME").to_string(),
         message: "".to_string(),
         version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
     };
 
-    // check user supplied parameters
-    if account_info.id.is_none() {
-        response.message = "No id supplied".to_string();
-        return Ok(HttpResponse::BadRequest().json(response));
+    record_accounts(&req, &[account_id]);
+    if !owns_account(&req, account_id) {
+        response.message = "Account not owned by caller".to_string();
+        return Ok(HttpResponse::Forbidden().json(response));
     }
 
+    let format: StatementFormat = match params.format.as_deref().map(str::parse).transpose() {
+        Ok(format) => format.unwrap_or_default(),
+        Err(message) => {
+            response.message = message;
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+    };
+    let (from, to) = match statement::period(params.from, params.to) {
+        Ok(period) => period,
+        Err(message) => {
+            response.message = message;
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+    };
+
     let client: Client = match db_pool.get().await {
         Ok(client) => client,
         Err(err) => {
@@ -98,9 +393,8 @@ pub async fn get_account_by_id(
         }
     };
 
-    let acc = match db::get_account_by_id(&client, account_info.id.unwrap()).await {
-        // will panic id id not supplied - fix
-        Ok(acc) => acc,
+    let statement = match statement::build(&client, account_id, from, to).await {
+        Ok(statement) => statement,
         Err(err) => {
             response.message = err.to_string();
             if err.to_string() == "NotFound" {
@@ -110,11 +404,43 @@ pub async fn get_account_by_id(
         }
     };
 
-    Ok(HttpResponse::Ok().json(acc))
+    if format == StatementFormat::Json {
+        return Ok(HttpResponse::Ok().json(statement));
+    }
+    let filename = format!(
+        "statement-{}-{}.{}",
+        account_id,
+        from.format("%Y%m%d"),
+        format.extension()
+    );
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(filename)],
+        })
+        .body(statement::render(&statement, format)))
 }
 
 // get_transaction_by_id returns the transaction details for the transaction with specified index.
+#[utoipa::path(
+    post,
+    path = "/transaction-by-id",
+    tag = "transactions",
+    request_body = TransactionParams,
+    responses(
+        (status = 200, description = "Transaction", body = Transaction),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 pub async fn get_transaction_by_id(
+    req: HttpRequest,
     tx_params: web::Json<TransactionParams>,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
@@ -124,13 +450,14 @@ pub async fn get_transaction_by_id(
         service: env!("SERVICE_NAME").to_string(),
         message: "".to_string(),
         version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
     };
 
     // check user supplied parameters
-    if tx_info.id.is_none() {
+    let Some(tx_id) = tx_info.id else {
         response.message = "No id supplied".to_string();
         return Ok(HttpResponse::BadRequest().json(response));
-    }
+    };
 
     let client: Client = match db_pool.get().await {
         Ok(client) => client,
@@ -140,26 +467,34 @@ pub async fn get_transaction_by_id(
         }
     };
 
-    let acc = match db::get_transaction_by_id(&client, tx_info.id.unwrap()).await {
-        // will panic id id not supplied - fix
-        Ok(acc) => acc,
-        Err(err) => {
-            response.message = err.to_string();
-            if err.to_string() == "NotFound" {
-                return Ok(HttpResponse::NotFound().json(response));
-            }
-            return Ok(HttpResponse::InternalServerError().json(response));
-        }
-    };
-
-    Ok(HttpResponse::Ok().json(acc))
+    match ledger::get_transaction(&client, principal(&req).as_ref(), tx_id).await {
+        Ok(tx) => Ok(HttpResponse::Ok().json(tx)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
 }
 
 // create_account registers a new account to the server. Provided the
 // PostgesDB write is successful it will return the account details back to the request agent.
+#[utoipa::path(
+    put,
+    path = "/create-account",
+    tag = "accounts",
+    request_body = AccountParams,
+    responses(
+        (status = 200, description = "Created account", body = Account),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 409, description = "Username already taken", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 pub async fn create_account(
+    req: HttpRequest,
     account_params: web::Json<AccountParams>,
     db_pool: web::Data<Pool>,
+    accounts_config: web::Data<AccountsConfig>,
 ) -> Result<HttpResponse, Error> {
     let account_info: AccountParams = account_params.into_inner();
 
@@ -167,28 +502,10 @@ pub async fn create_account(
         service: env!("SERVICE_NAME").to_string(),
         message: "".to_string(),
         version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
     };
 
-    // check user supplied values
-    if account_info.email.is_none() {
-        response.message = "No email supplied".to_string();
-        return Ok(HttpResponse::BadRequest().json(response));
-    }
-    if account_info.username.is_none() {
-        response.message = "No username supplied".to_string();
-        return Ok(HttpResponse::BadRequest().json(response));
-    }
-    // Set timestamp server-side
-    let dt = Utc::now();
-    let account: Account = Account {
-        id: None, // To be set by Postgres
-        username: account_info.username,
-        email: account_info.email,
-        balance: Some(0),
-        created_at: Some(dt),
-    };
-
-    let client: Client = match db_pool.get().await {
+    let mut client: Client = match db_pool.get().await {
         Ok(client) => client,
         Err(err) => {
             response.message = err.to_string();
@@ -196,24 +513,110 @@ pub async fn create_account(
         }
     };
 
-    let new_account = match db::create_account(&client, account).await {
-        Ok(new_account) => new_account,
+    let context = AuditContext::from_request(&req);
+    let new_account =
+        match ledger::create_account(&mut client, &context, &accounts_config, account_info).await {
+            Ok(new_account) => new_account,
+            Err(err) => return Ok(ledger_error(&req, response, err)),
+        };
+    if let Some(id) = new_account.id {
+        record_accounts(&req, &[id]);
+    }
+
+    Ok(HttpResponse::Ok().json(new_account))
+}
+
+// update_account replaces the metadata and/or tags of an account.
+#[utoipa::path(
+    patch,
+    path = "/accounts/{id}",
+    tag = "accounts",
+    params(("id" = i64, Path, description = "Account id")),
+    request_body = AccountUpdate,
+    responses(
+        (status = 200, description = "Updated account", body = Account),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn update_account(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    update: web::Json<AccountUpdate>,
+    db_pool: web::Data<Pool>,
+    accounts_config: web::Data<AccountsConfig>,
+) -> Result<HttpResponse, Error> {
+    let account_id = path.into_inner();
+    record_accounts(&req, &[account_id]);
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
         Err(err) => {
             response.message = err.to_string();
             return Ok(HttpResponse::InternalServerError().json(response));
         }
     };
 
-    Ok(HttpResponse::Ok().json(new_account))
+    match ledger::update_account(
+        &mut client,
+        principal(&req).as_ref(),
+        &AuditContext::from_request(&req),
+        &accounts_config,
+        account_id,
+        update.into_inner(),
+    )
+    .await
+    {
+        Ok(account) => Ok(HttpResponse::Ok().json(account)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
 }
 
-// get_transactions queries the full list of transactions from the postgres DB and returns
-// to the request agent.
-pub async fn get_transactions(db_pool: web::Data<Pool>) -> Result<HttpResponse, Error> {
+// get_transactions queries the list of transactions matching the query filters
+// from the postgres DB and returns it to the request agent.
+#[utoipa::path(
+    get,
+    path = "/transactions",
+    tag = "transactions",
+    params(TransactionsQuery),
+    responses(
+        (status = 200, description = "Transactions visible to the caller", body = Vec<Transaction>),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_transactions(
+    req: HttpRequest,
+    query: web::Query<TransactionsQuery>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let filter = TransactionFilter {
+        description: query.description,
+        reference: query.reference,
+        category: query.category,
+        external_id: query.external_id,
+        ..Default::default()
+    };
+
     let mut response: Status = Status {
         service: env!("SERVICE_NAME").to_string(),
         message: "".to_string(),
         version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
     };
     let client: Client = match db_pool.get().await {
         Ok(client) => client,
@@ -223,20 +626,101 @@ pub async fn get_transactions(db_pool: web::Data<Pool>) -> Result<HttpResponse,
         }
     };
 
-    let txs = match db::get_transactions(&client).await {
-        Ok(txs) => txs,
+    match ledger::get_transactions(&client, principal(&req).as_ref(), &filter).await {
+        Ok(txs) => Ok(HttpResponse::Ok().json(txs)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// search finds accounts by username or email and transactions by description
+// or reference, best match first, with the matching text highlighted. Pass
+// next_offset as offset to fetch the next page.
+#[utoipa::path(
+    get,
+    path = "/search",
+    tag = "search",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Matching accounts and transactions, best match first", body = SearchResults),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn search(
+    req: HttpRequest,
+    query: web::Query<SearchQuery>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let query: SearchQuery = query.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let q = match search::query(query.q.as_deref()) {
+        Ok(q) => q,
+        Err(message) => {
+            response.message = message;
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        response.message = format!("limit must be between 1 and {}", MAX_SEARCH_LIMIT);
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+    let offset = query.offset.unwrap_or_default();
+    if offset < 0 {
+        response.message = "offset may not be negative".to_string();
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
         Err(err) => {
             response.message = err.to_string();
-            return Ok(HttpResponse::InternalServerError().json(response));
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
         }
     };
 
-    Ok(HttpResponse::Ok().json(txs))
+    // callers restricted to some accounts only find those and their transfers
+    let owned = principal(&req).and_then(|p| p.accounts);
+    match search::search(&mut client, &q, owned.as_ref(), limit, offset).await {
+        Ok(results) => Ok(HttpResponse::Ok().json(results)),
+        Err(err) => {
+            response.message = err.to_string();
+            Ok(HttpResponse::InternalServerError().json(response))
+        }
+    }
 }
 
 // create_transaction posts a new transaction to the postgres DB and returns
 // the transaction details with unique ID to the request agent.
+#[utoipa::path(
+    put,
+    path = "/create-tx",
+    tag = "transactions",
+    request_body = TransactionParams,
+    responses(
+        (status = 200, description = "Created transaction", body = Transaction),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 409, description = "External id already used, the existing transaction", body = Transaction),
+        (status = 429, description = "Rate or transfer velocity limit exceeded", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
 pub async fn create_transaction(
+    req: HttpRequest,
     tx_params: web::Json<TransactionParams>,
     db_pool: web::Data<Pool>,
 ) -> Result<HttpResponse, Error> {
@@ -246,32 +730,161 @@ pub async fn create_transaction(
         service: env!("SERVICE_NAME").to_string(),
         message: "".to_string(),
         version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
     };
 
-    // check user supplied values
-    if tx_info.from_account.is_none() {
-        response.message = "No from account supplied".to_string();
-        return Ok(HttpResponse::BadRequest().json(response));
+    if let (Some(from_account), Some(to_account)) = (tx_info.from_account, tx_info.to_account) {
+        record_accounts(&req, &[from_account, to_account]);
     }
-    if tx_info.to_account.is_none() {
-        response.message = "No to account supplied".to_string();
-        return Ok(HttpResponse::BadRequest().json(response));
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    // per account velocity limits on transfers out of the debited account
+    let velocity = req
+        .app_data::<web::Data<RateLimiter>>()
+        .and_then(|limiter| limiter.velocity());
+    let context = AuditContext::from_request(&req);
+    let principal = principal(&req);
+    match ledger::create_transaction(&mut client, principal.as_ref(), &context, velocity, tx_info)
+        .await
+    {
+        Ok(new_tx) => Ok(HttpResponse::Ok().json(new_tx)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
     }
-    if tx_info.amount.is_none() {
-        response.message = "No amount supplied".to_string();
+}
+
+// create_accounts creates a batch of accounts with a single insert. Atomic
+// batches create all accounts or none, best effort batches create the valid
+// accounts whose username is free. Results are returned in input order.
+#[utoipa::path(
+    post,
+    path = "/accounts/batch",
+    tag = "accounts",
+    request_body = AccountBatchParams,
+    responses(
+        (status = 200, description = "Outcome of every item", body = BatchResult<Account>),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 413, description = "Batch too large", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn create_accounts(
+    req: HttpRequest,
+    batch: web::Json<AccountBatchParams>,
+    db_pool: web::Data<Pool>,
+    batch_config: web::Data<BatchConfig>,
+    accounts_config: web::Data<AccountsConfig>,
+) -> Result<HttpResponse, Error> {
+    let AccountBatchParams { mode, accounts } = batch.into_inner();
+    let items = accounts.len();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    if accounts.is_empty() {
+        response.message = "No accounts supplied".to_string();
         return Ok(HttpResponse::BadRequest().json(response));
     }
-    // Set timestamp server-side
-    let dt = Utc::now();
-    let tx: Transaction = Transaction {
-        id: None, // To be set by Postgres
-        from_account: tx_info.from_account,
-        to_account: tx_info.to_account,
-        amount: tx_info.amount,
-        created_at: Some(dt),
+    if items > batch_config.max_items {
+        response.message = format!(
+            "Batch exceeds the limit of {} accounts",
+            batch_config.max_items
+        );
+        return Ok(HttpResponse::PayloadTooLarge().json(response));
+    }
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
     };
 
-    let client: Client = match db_pool.get().await {
+    let context = AuditContext::from_request(&req);
+    let outcome =
+        ledger::create_accounts(&mut client, &context, &accounts_config, mode, accounts).await;
+    match outcome {
+        Ok(BatchOutcome::Done(outcomes)) => {
+            let ids: Vec<i64> = outcomes
+                .iter()
+                .filter_map(|outcome| outcome.as_ref().ok()?.id)
+                .collect();
+            record_accounts(&req, &ids);
+            Ok(batch_done(mode, outcomes))
+        }
+        Ok(BatchOutcome::RolledBack(failures)) => {
+            Ok(batch_rejected::<Account>(mode, items, failures))
+        }
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// create_transactions executes a batch of transfers. Atomic batches commit all
+// transfers or none, best effort batches run each transfer in its own
+// savepoint and keep the ones that succeed. Results are returned in input order.
+#[utoipa::path(
+    post,
+    path = "/transactions/batch",
+    tag = "transactions",
+    request_body = TransactionBatchParams,
+    responses(
+        (status = 200, description = "Outcome of every item", body = BatchResult<Transaction>),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 413, description = "Batch too large", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn create_transactions(
+    req: HttpRequest,
+    batch: web::Json<TransactionBatchParams>,
+    db_pool: web::Data<Pool>,
+    batch_config: web::Data<BatchConfig>,
+) -> Result<HttpResponse, Error> {
+    let TransactionBatchParams { mode, transfers } = batch.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    if transfers.is_empty() {
+        response.message = "No transfers supplied".to_string();
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+    if transfers.len() > batch_config.max_items {
+        response.message = format!(
+            "Batch exceeds the limit of {} transfers",
+            batch_config.max_items
+        );
+        return Ok(HttpResponse::PayloadTooLarge().json(response));
+    }
+    let accounts: Vec<i64> = transfers
+        .iter()
+        .flat_map(|t| [t.from_account, t.to_account])
+        .flatten()
+        .collect();
+    record_accounts(&req, &accounts);
+
+    let mut client: Client = match db_pool.get().await {
         Ok(client) => client,
         Err(err) => {
             response.message = err.to_string();
@@ -279,13 +892,1122 @@ pub async fn create_transaction(
         }
     };
 
-    let new_tx = match db::create_transaction(&client, tx).await {
-        Ok(new_tx) => new_tx,
+    let velocity = req
+        .app_data::<web::Data<RateLimiter>>()
+        .and_then(|limiter| limiter.velocity());
+    let context = AuditContext::from_request(&req);
+    let principal = principal(&req);
+    let outcome = ledger::create_transactions(
+        &mut client,
+        principal.as_ref(),
+        &context,
+        velocity,
+        mode,
+        &transfers,
+    )
+    .await;
+    match outcome {
+        Ok(BatchOutcome::Done(outcomes)) => Ok(batch_done(mode, outcomes)),
+        Ok(BatchOutcome::RolledBack(failures)) => Ok(batch_rejected::<Transaction>(
+            mode,
+            transfers.len(),
+            failures,
+        )),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// batch_done answers a batch whose items were all attempted, reporting the
+// outcome of each in input order.
+fn batch_done<T: serde::Serialize>(
+    mode: BatchMode,
+    outcomes: Vec<Result<T, LedgerError>>,
+) -> HttpResponse {
+    let results: Vec<BatchItemResult<T>> = outcomes
+        .into_iter()
+        .enumerate()
+        .map(|(index, outcome)| match outcome {
+            Ok(item) => BatchItemResult {
+                index,
+                status: StatusCode::OK.as_u16(),
+                result: Some(item),
+                error: None,
+            },
+            Err(err) => BatchItemResult {
+                index,
+                status: ledger_status(&err).as_u16(),
+                result: None,
+                error: Some(err.to_string()),
+            },
+        })
+        .collect();
+    let failed = results.iter().filter(|r| r.result.is_none()).count();
+    HttpResponse::Ok().json(BatchResult {
+        mode,
+        succeeded: results.len() - failed,
+        failed,
+        results,
+    })
+}
+
+// batch_rejected answers an atomic batch that was rolled back. The response
+// takes the status of the first failed item; the other items are reported as
+// failed dependencies.
+fn batch_rejected<T: serde::Serialize>(
+    mode: BatchMode,
+    items: usize,
+    failures: Vec<(usize, LedgerError)>,
+) -> HttpResponse {
+    let status = ledger_status(&failures[0].1);
+    let first = failures[0].0;
+    let mut failures = failures.into_iter().peekable();
+    let results: Vec<BatchItemResult<T>> = (0..items)
+        .map(
+            |index| match failures.next_if(|(failed, _)| *failed == index) {
+                Some((_, err)) => BatchItemResult {
+                    index,
+                    status: ledger_status(&err).as_u16(),
+                    result: None,
+                    error: Some(err.to_string()),
+                },
+                None => BatchItemResult {
+                    index,
+                    status: StatusCode::FAILED_DEPENDENCY.as_u16(),
+                    result: None,
+                    error: Some(format!("Batch rolled back, item {} failed", first)),
+                },
+            },
+        )
+        .collect();
+    let failed = results.len();
+    HttpResponse::build(status).json(BatchResult {
+        mode,
+        succeeded: 0,
+        failed,
+        results,
+    })
+}
+
+// create_scheduled_transfer schedules a transfer for a later time or on a
+// recurrence. The scheduler executes it from the from account of the caller.
+#[utoipa::path(
+    post,
+    path = "/scheduled-transfers",
+    tag = "scheduled transfers",
+    request_body = ScheduledTransferParams,
+    responses(
+        (status = 200, description = "Created scheduled transfer", body = ScheduledTransfer),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn create_scheduled_transfer(
+    req: HttpRequest,
+    params: web::Json<ScheduledTransferParams>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let params: ScheduledTransferParams = params.into_inner();
+    if let (Some(from_account), Some(to_account)) = (params.from_account, params.to_account) {
+        record_accounts(&req, &[from_account, to_account]);
+    }
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
         Err(err) => {
             response.message = err.to_string();
             return Ok(HttpResponse::InternalServerError().json(response));
         }
     };
 
-    Ok(HttpResponse::Ok().json(new_tx))
+    match ledger::create_scheduled_transfer(
+        &mut client,
+        principal(&req).as_ref(),
+        &AuditContext::from_request(&req),
+        params,
+    )
+    .await
+    {
+        Ok(schedule) => Ok(HttpResponse::Ok().json(schedule)),
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// get_scheduled_transfers lists scheduled transfers, optionally by status.
+// Restricted callers only see the ones involving their accounts.
+#[utoipa::path(
+    get,
+    path = "/scheduled-transfers",
+    tag = "scheduled transfers",
+    params(ScheduledTransferQuery),
+    responses(
+        (status = 200, description = "Scheduled transfers visible to the caller", body = Vec<ScheduledTransfer>),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_scheduled_transfers(
+    req: HttpRequest,
+    query: web::Query<ScheduledTransferQuery>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
+        }
+    };
+
+    match db::get_scheduled_transfers(&client, query.status.as_deref()).await {
+        Ok(schedules) => match principal(&req).and_then(|p| p.accounts) {
+            Some(owned) => Ok(HttpResponse::Ok().json(
+                schedules
+                    .into_iter()
+                    .filter(|s| owned.contains(&s.from_account) || owned.contains(&s.to_account))
+                    .collect::<Vec<ScheduledTransfer>>(),
+            )),
+            None => Ok(HttpResponse::Ok().json(schedules)),
+        },
+        Err(err) => {
+            response.message = err.to_string();
+            Ok(HttpResponse::InternalServerError().json(response))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/scheduled-transfers/{id}",
+    tag = "scheduled transfers",
+    params(("id" = i64, Path, description = "Scheduled transfer id")),
+    responses(
+        (status = 200, description = "Scheduled transfer", body = ScheduledTransfer),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_scheduled_transfer(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let id = path.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
+        }
+    };
+
+    let schedule = match db::get_scheduled_transfer_by_id(&client, id, false).await {
+        Ok(schedule) => schedule,
+        Err(err) => {
+            response.message = err.to_string();
+            if err.to_string() == "NotFound" {
+                return Ok(HttpResponse::NotFound().json(response));
+            }
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+    record_accounts(&req, &[schedule.from_account, schedule.to_account]);
+    if !owns_account(&req, schedule.from_account) && !owns_account(&req, schedule.to_account) {
+        response.message = "Scheduled transfer not owned by caller".to_string();
+        return Ok(HttpResponse::Forbidden().json(response));
+    }
+
+    Ok(HttpResponse::Ok().json(schedule))
+}
+
+#[utoipa::path(
+    post,
+    path = "/scheduled-transfers/{id}/pause",
+    tag = "scheduled transfers",
+    params(("id" = i64, Path, description = "Scheduled transfer id")),
+    responses(
+        (status = 200, description = "Paused scheduled transfer", body = ScheduledTransfer),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 409, description = "Transfer cannot be paused in its status", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn pause_scheduled_transfer(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    change_scheduled_transfer(req, path.into_inner(), db_pool, ScheduleAction::Pause).await
+}
+
+// resume_scheduled_transfer reactivates a paused or failed scheduled transfer.
+// Occurrences that fell due meanwhile are executed once at the next poll.
+#[utoipa::path(
+    post,
+    path = "/scheduled-transfers/{id}/resume",
+    tag = "scheduled transfers",
+    params(("id" = i64, Path, description = "Scheduled transfer id")),
+    responses(
+        (status = 200, description = "Resumed scheduled transfer", body = ScheduledTransfer),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 409, description = "Transfer cannot be resumed in its status", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn resume_scheduled_transfer(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    change_scheduled_transfer(req, path.into_inner(), db_pool, ScheduleAction::Resume).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/scheduled-transfers/{id}/cancel",
+    tag = "scheduled transfers",
+    params(("id" = i64, Path, description = "Scheduled transfer id")),
+    responses(
+        (status = 200, description = "Cancelled scheduled transfer", body = ScheduledTransfer),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 409, description = "Transfer cannot be cancelled in its status", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn cancel_scheduled_transfer(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    change_scheduled_transfer(req, path.into_inner(), db_pool, ScheduleAction::Cancel).await
+}
+
+// change_scheduled_transfer applies a pause, resume or cancel action.
+async fn change_scheduled_transfer(
+    req: HttpRequest,
+    id: i64,
+    db_pool: web::Data<Pool>,
+    action: ScheduleAction,
+) -> Result<HttpResponse, Error> {
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    match ledger::change_scheduled_transfer(
+        &mut client,
+        principal(&req).as_ref(),
+        &AuditContext::from_request(&req),
+        id,
+        action,
+    )
+    .await
+    {
+        Ok(schedule) => {
+            record_accounts(&req, &[schedule.from_account, schedule.to_account]);
+            Ok(HttpResponse::Ok().json(schedule))
+        }
+        Err(err) => Ok(ledger_error(&req, response, err)),
+    }
+}
+
+// create_webhook subscribes a URL to ledger events. The signing secret is only
+// returned in this response.
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = WebhookParams,
+    responses(
+        (status = 200, description = "Created subscription, including its secret", body = WebhookSubscription),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn create_webhook(
+    req: HttpRequest,
+    params: web::Json<WebhookParams>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let params: WebhookParams = params.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let Some(url) = params.url else {
+        response.message = "No url supplied".to_string();
+        return Ok(HttpResponse::BadRequest().json(response));
+    };
+    if let Err(message) = webhooks::check_url(&url) {
+        response.message = message;
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+    let event_types = match params.event_types {
+        Some(types) if types.is_empty() => {
+            response.message = "event_types must not be empty".to_string();
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+        Some(types) => {
+            if let Some(unknown) = types
+                .iter()
+                .find(|t| !webhooks::EVENT_TYPES.contains(&t.as_str()))
+            {
+                response.message = format!(
+                    "Unknown event type {} (expected one of {})",
+                    unknown,
+                    webhooks::EVENT_TYPES.join(", ")
+                );
+                return Ok(HttpResponse::BadRequest().json(response));
+            }
+            types
+        }
+        None => webhooks::EVENT_TYPES.map(String::from).to_vec(),
+    };
+    if params
+        .secret
+        .as_ref()
+        .is_some_and(|secret| secret.is_empty())
+    {
+        response.message = "secret must not be empty".to_string();
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+
+    let webhook = WebhookSubscription {
+        id: 0, // To be set by Postgres
+        url,
+        event_types,
+        secret: Some(params.secret.unwrap_or_else(webhooks::generate_secret)),
+        description: params.description,
+        active: true,
+        created_at: None,
+    };
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+    let db_tx = match client.transaction().await {
+        Ok(db_tx) => db_tx,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    let webhook = match db::create_webhook(&db_tx, &webhook).await {
+        Ok(webhook) => webhook,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    // the secret is kept out of the audit log
+    let audited = audit::record(
+        &db_tx,
+        &AuditContext::from_request(&req),
+        "create_webhook",
+        "webhook",
+        Some(webhook.id),
+        None,
+        Some(&WebhookSubscription {
+            secret: None,
+            ..webhook.clone()
+        }),
+    )
+    .await;
+    if let Err(err) = audited {
+        response.message = err.to_string();
+        return Ok(HttpResponse::InternalServerError().json(response));
+    }
+    if let Err(err) = db_tx.commit().await {
+        response.message = err.to_string();
+        return Ok(HttpResponse::InternalServerError().json(response));
+    }
+
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "Subscriptions", body = Vec<WebhookSubscription>),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_webhooks(
+    req: HttpRequest,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
+        }
+    };
+
+    match db::get_webhooks(&client).await {
+        Ok(webhooks) => Ok(HttpResponse::Ok().json(webhooks)),
+        Err(err) => {
+            response.message = err.to_string();
+            Ok(HttpResponse::InternalServerError().json(response))
+        }
+    }
+}
+
+// delete_webhook deactivates a subscription. Its pending deliveries are
+// cancelled and the delivery log is kept.
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = i64, Path, description = "Subscription id")),
+    responses(
+        (status = 200, description = "Deactivated subscription", body = WebhookSubscription),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 404, description = "Not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn delete_webhook(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let id = path.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+    let db_tx = match client.transaction().await {
+        Ok(db_tx) => db_tx,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    let webhook = match db::deactivate_webhook(&db_tx, id).await {
+        Ok(webhook) => webhook,
+        Err(err) => {
+            response.message = err.to_string();
+            if err.to_string() == "NotFound" {
+                return Ok(HttpResponse::NotFound().json(response));
+            }
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    let audited = audit::record(
+        &db_tx,
+        &AuditContext::from_request(&req),
+        "delete_webhook",
+        "webhook",
+        Some(webhook.id),
+        None,
+        Some(&webhook),
+    )
+    .await;
+    if let Err(err) = audited {
+        response.message = err.to_string();
+        return Ok(HttpResponse::InternalServerError().json(response));
+    }
+    if let Err(err) = db_tx.commit().await {
+        response.message = err.to_string();
+        return Ok(HttpResponse::InternalServerError().json(response));
+    }
+
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+// get_webhook_deliveries returns the delivery log of a subscription, newest
+// first, optionally filtered by status.
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    params(("id" = i64, Path, description = "Subscription id"), WebhookDeliveryQuery),
+    responses(
+        (status = 200, description = "Deliveries, newest first", body = Vec<WebhookDelivery>),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 404, description = "Subscription not found", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_webhook_deliveries(
+    req: HttpRequest,
+    path: web::Path<i64>,
+    query: web::Query<WebhookDeliveryQuery>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let id = path.into_inner();
+    let query: WebhookDeliveryQuery = query.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_DELIVERY_LIMIT);
+    if !(1..=MAX_DELIVERY_LIMIT).contains(&limit) {
+        response.message = format!("limit must be between 1 and {}", MAX_DELIVERY_LIMIT);
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+    if let Some(status) = &query.status {
+        if !webhooks::DELIVERY_STATUSES.contains(&status.as_str()) {
+            response.message = format!(
+                "Unknown status {} (expected one of {})",
+                status,
+                webhooks::DELIVERY_STATUSES.join(", ")
+            );
+            return Ok(HttpResponse::BadRequest().json(response));
+        }
+    }
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
+        }
+    };
+
+    if let Err(err) = db::get_webhook_by_id(&client, id).await {
+        response.message = err.to_string();
+        if err.to_string() == "NotFound" {
+            return Ok(HttpResponse::NotFound().json(response));
+        }
+        return Ok(HttpResponse::InternalServerError().json(response));
+    }
+
+    match db::get_webhook_deliveries(&client, id, query.status.as_deref(), limit).await {
+        Ok(deliveries) => Ok(HttpResponse::Ok().json(deliveries)),
+        Err(err) => {
+            response.message = err.to_string();
+            Ok(HttpResponse::InternalServerError().json(response))
+        }
+    }
+}
+
+// get_audit returns audit log entries matching the query parameters, oldest
+// first. Pass the id of the last entry received as after_id to fetch the next page.
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "admin",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Audit entries, oldest first", body = Vec<AuditEntry>),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_audit(
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+    db_pool: web::Data<Pool>,
+) -> Result<HttpResponse, Error> {
+    let query: AuditQuery = query.into_inner();
+
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT);
+    if !(1..=MAX_AUDIT_LIMIT).contains(&limit) {
+        response.message = format!("limit must be between 1 and {}", MAX_AUDIT_LIMIT);
+        return Ok(HttpResponse::BadRequest().json(response));
+    }
+
+    let client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
+        }
+    };
+
+    let entries = match db::get_audit_entries(&client, &query, limit).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::InternalServerError().json(response));
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+// reconcile_balances recomputes every account balance from the transaction
+// history and reports the accounts that differ. With repair=true the drifted
+// balances are overwritten with the computed value.
+#[utoipa::path(
+    post,
+    path = "/admin/reconcile",
+    tag = "admin",
+    params(ReconcileParams),
+    responses(
+        (status = 200, description = "Reconciliation report", body = ReconcileReport),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+        (status = 500, description = "Database error", body = Status),
+        (status = 503, description = "Database unavailable", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn reconcile_balances(
+    req: HttpRequest,
+    params: web::Query<ReconcileParams>,
+    db_pool: web::Data<Pool>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(&req),
+    };
+
+    let mut client: Client = match db_pool.get().await {
+        Ok(client) => client,
+        Err(err) => {
+            response.message = err.to_string();
+            return Ok(HttpResponse::ServiceUnavailable().json(response));
+        }
+    };
+
+    let repair = params.repair.unwrap_or(false);
+    let result = reconcile::run(&mut client, repair, &AuditContext::from_request(&req)).await;
+    metrics.record_reconcile(&result);
+
+    match result {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(err) => {
+            response.message = err.to_string();
+            Ok(HttpResponse::InternalServerError().json(response))
+        }
+    }
+}
+
+// get_events streams new transactions and balance changes as Server-Sent
+// Events. A reconnecting client resumes after its Last-Event-ID.
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "events",
+    params(EventsQuery, ("Last-Event-ID" = Option<i64>, Header, description = "Resume after this event id")),
+    responses(
+        (status = 200, description = "Server-Sent Events stream of transaction and balance events", body = String, content_type = "text/event-stream"),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_events(
+    req: HttpRequest,
+    query: web::Query<EventsQuery>,
+    db_pool: web::Data<Pool>,
+    hub: web::Data<EventHub>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, Error> {
+    let (filter, after_id) = match event_filter(&req, query.into_inner()) {
+        Ok(filter) => filter,
+        Err(rejected) => return Ok(rejected),
+    };
+    let frames = hub.subscribe(
+        db_pool.get_ref().clone(),
+        filter,
+        after_id,
+        shutdown.subscribe(),
+    );
+
+    let body = futures_util::stream::unfold(frames, |mut frames| async move {
+        let chunk = match frames.recv().await? {
+            Frame::Event(event) => format!(
+                "id: {}\nevent: {}\ndata: {}\n\n",
+                event.id, event.event, event.data
+            ),
+            Frame::Keepalive => ": keepalive\n\n".to_string(),
+            Frame::Closed(reason) => format!(
+                "event: error\ndata: {}\n\n",
+                serde_json::json!({ "message": reason })
+            ),
+        };
+        Some((Ok::<_, Error>(web::Bytes::from(chunk)), frames))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(body))
+}
+
+// events_ws streams the same events as get_events over a WebSocket, one JSON
+// text message per event. Clients resume with the last_event_id parameter.
+#[utoipa::path(
+    get,
+    path = "/events/ws",
+    tag = "events",
+    params(EventsQuery),
+    responses(
+        (status = 101, description = "WebSocket stream of transaction and balance events, one JSON text message per event"),
+        (status = 400, description = "Invalid parameters", body = Status),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Account not owned by caller or scope missing", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn events_ws(
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<EventsQuery>,
+    db_pool: web::Data<Pool>,
+    hub: web::Data<EventHub>,
+    shutdown: web::Data<Shutdown>,
+) -> Result<HttpResponse, Error> {
+    let (filter, after_id) = match event_filter(&req, query.into_inner()) {
+        Ok(filter) => filter,
+        Err(rejected) => return Ok(rejected),
+    };
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let mut frames = hub.subscribe(
+        db_pool.get_ref().clone(),
+        filter,
+        after_id,
+        shutdown.subscribe(),
+    );
+
+    actix_web::rt::spawn(async move {
+        loop {
+            tokio::select! {
+                frame = frames.recv() => {
+                    let sent = match frame {
+                        Some(Frame::Event(event)) => {
+                            session.text(serde_json::to_string(&*event).unwrap_or_default()).await
+                        }
+                        Some(Frame::Keepalive) => session.ping(b"").await,
+                        Some(Frame::Closed(reason)) => {
+                            let _ = session
+                                .close(Some(actix_ws::CloseReason {
+                                    code: actix_ws::CloseCode::Again,
+                                    description: Some(reason),
+                                }))
+                                .await;
+                            return;
+                        }
+                        None => {
+                            let _ = session.close(Some(actix_ws::CloseCode::Away.into())).await;
+                            return;
+                        }
+                    };
+                    if sent.is_err() {
+                        return;
+                    }
+                }
+                message = messages.next() => match message {
+                    Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                        if session.pong(&bytes).await.is_err() {
+                            return;
+                        }
+                    }
+                    Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    });
+
+    Ok(response)
+}
+
+// event_filter reads the stream filter and resume point of an events request.
+// Callers restricted to some accounts only receive events of those accounts.
+fn event_filter(
+    req: &HttpRequest,
+    query: EventsQuery,
+) -> Result<(EventFilter, Option<i64>), HttpResponse> {
+    let mut response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: "".to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(req),
+    };
+
+    let accounts = match query.accounts.as_deref().map(|accounts| {
+        accounts
+            .split(',')
+            .map(|id| id.trim().parse::<i64>())
+            .collect::<Result<Vec<i64>, _>>()
+    }) {
+        Some(Err(_)) => {
+            response.message = "accounts must be a comma separated list of ids".to_string();
+            return Err(HttpResponse::BadRequest().json(response));
+        }
+        Some(Ok(accounts)) => Some(accounts),
+        None => None,
+    };
+    let types = query.types.map(|types| {
+        types
+            .split(',')
+            .map(|t| t.trim().to_string())
+            .collect::<Vec<String>>()
+    });
+    if let Some(unknown) = types
+        .iter()
+        .flatten()
+        .find(|t| *t != events::TRANSACTION && *t != events::BALANCE)
+    {
+        response.message = format!(
+            "Unknown event type {} (expected {} or {})",
+            unknown,
+            events::TRANSACTION,
+            events::BALANCE
+        );
+        return Err(HttpResponse::BadRequest().json(response));
+    }
+
+    let accounts = match (accounts, principal(req).and_then(|p| p.accounts)) {
+        (Some(accounts), Some(owned)) => {
+            if let Some(id) = accounts.iter().find(|id| !owned.contains(id)) {
+                response.message = format!("Account {} not owned by caller", id);
+                return Err(HttpResponse::Forbidden().json(response));
+            }
+            Some(accounts)
+        }
+        (None, Some(owned)) => Some(owned),
+        (accounts, None) => accounts,
+    };
+    if let Some(accounts) = &accounts {
+        record_accounts(req, accounts);
+    }
+
+    let last_event_id = match req.headers().get("Last-Event-ID") {
+        Some(value) => match value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<i64>().ok())
+        {
+            Some(id) => Some(id),
+            None => {
+                response.message = "Last-Event-ID must be an event id".to_string();
+                return Err(HttpResponse::BadRequest().json(response));
+            }
+        },
+        None => query.last_event_id,
+    };
+
+    Ok((EventFilter { accounts, types }, last_event_id))
+}
+
+// graphql_request executes a GraphQL query or mutation (see graphql.rs) for
+// the authenticated caller.
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "graphql",
+    request_body(content = Object, description = "GraphQL request with query, variables and operationName"),
+    responses(
+        (status = 200, description = "GraphQL response with data and errors", body = Object),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+    ),
+    security(("api_key" = []), ("bearer" = [])),
+)]
+pub async fn graphql_request(
+    req: HttpRequest,
+    request: GraphQLRequest,
+    db_pool: web::Data<Pool>,
+    schema: web::Data<LedgerSchema>,
+) -> GraphQLResponse {
+    let request = graphql::prepare(
+        request.into_inner(),
+        db_pool.get_ref(),
+        principal(&req),
+        AuditContext::from_request(&req),
+    );
+    schema.execute(request).await.into()
+}
+
+// graphiql serves the GraphiQL IDE for /graphql.
+pub async fn graphiql() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(GraphiQLSource::build().endpoint("/graphql").finish())
+}
+
+// ledger_status is the HTTP status of a ledger error.
+fn ledger_status(err: &LedgerError) -> StatusCode {
+    match err {
+        LedgerError::Invalid(_) => StatusCode::BAD_REQUEST,
+        LedgerError::Forbidden(_) => StatusCode::FORBIDDEN,
+        LedgerError::NotFound => StatusCode::NOT_FOUND,
+        LedgerError::Conflict(_) | LedgerError::Duplicate(_) => StatusCode::CONFLICT,
+        LedgerError::Limited(..) => StatusCode::TOO_MANY_REQUESTS,
+        LedgerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// ledger_error answers a failed ledger operation in the standard Status format.
+fn ledger_error(req: &HttpRequest, mut response: Status, err: LedgerError) -> HttpResponse {
+    response.message = err.to_string();
+    match err {
+        LedgerError::Invalid(_) => HttpResponse::BadRequest().json(response),
+        LedgerError::Forbidden(_) => HttpResponse::Forbidden().json(response),
+        LedgerError::NotFound => HttpResponse::NotFound().json(response),
+        LedgerError::Conflict(_) => HttpResponse::Conflict().json(response),
+        LedgerError::Limited(message, retry_after) => too_many_requests(req, &message, retry_after),
+        // the existing transaction lets a retrying client carry on with it
+        LedgerError::Duplicate(tx) => HttpResponse::Conflict().json(tx),
+        LedgerError::Internal(_) => HttpResponse::InternalServerError().json(response),
+    }
+}
+
+// get_metrics renders the service metrics in the Prometheus text format. It
+// needs the read scope unless auth.public_metrics is set.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "status",
+    responses(
+        (status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"),
+        (status = 401, description = "Missing or invalid credentials", body = Status),
+        (status = 403, description = "Scope missing", body = Status),
+    ),
+    security((), ("api_key" = []), ("bearer" = [])),
+)]
+pub async fn get_metrics(metrics: web::Data<Metrics>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render()))
+}
+
+// json_error_handler renders JSON body extraction failures in the standard
+// Status error format (including the request id) instead of plain text.
+pub fn json_error_handler(err: JsonPayloadError, req: &HttpRequest) -> Error {
+    bad_request(err, req)
+}
+
+// query_error_handler does the same for invalid query strings.
+pub fn query_error_handler(err: QueryPayloadError, req: &HttpRequest) -> Error {
+    bad_request(err, req)
+}
+
+// path_error_handler does the same for invalid path parameters.
+pub fn path_error_handler(err: PathError, req: &HttpRequest) -> Error {
+    bad_request(err, req)
+}
+
+fn bad_request<E: std::fmt::Debug + std::fmt::Display + 'static>(
+    err: E,
+    req: &HttpRequest,
+) -> Error {
+    let response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: err.to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(req),
+    };
+    actix_web::error::InternalError::from_response(err, HttpResponse::BadRequest().json(response))
+        .into()
 }
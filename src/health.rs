@@ -0,0 +1,154 @@
+// Dependency checks backing the readiness probe. Each check is timed and
+// reported individually so that operators can see which dependency is
+// failing and how long it took to answer.
+use crate::{
+    config::HealthConfig,
+    db,
+    model::{CheckStatus, Health, HealthCheck},
+    shutdown::Shutdown,
+};
+use deadpool_postgres::Pool;
+use std::time::{Duration, Instant};
+
+// latest migration version shipped with this binary (see build.rs)
+const LATEST_MIGRATION: &str = env!("LATEST_MIGRATION");
+
+pub fn new_health(checks: Vec<HealthCheck>) -> Health {
+    let status = if checks.iter().all(|c| c.status == CheckStatus::Pass) {
+        CheckStatus::Pass
+    } else {
+        CheckStatus::Fail
+    };
+
+    Health {
+        service: env!("SERVICE_NAME").to_string(),
+        version: env!("VERSION").to_string(),
+        status,
+        checks,
+        request_id: None,
+    }
+}
+
+// readiness runs every dependency check and returns the combined result.
+pub async fn readiness(pool: &Pool, config: &HealthConfig, shutdown: &Shutdown) -> Health {
+    let mut checks = vec![check_shutdown(shutdown)];
+
+    // pool saturation is sampled before the probe takes a connection of its own
+    checks.push(check_pool(pool, config));
+
+    let start = Instant::now();
+    let timeout = Duration::from_millis(config.db_timeout_ms);
+    let client = match tokio::time::timeout(timeout, pool.get()).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(err)) => {
+            checks.push(failed("database", start, err.to_string()));
+            return new_health(checks);
+        }
+        Err(_) => {
+            checks.push(failed(
+                "database",
+                start,
+                format!(
+                    "timed out acquiring connection after {}ms",
+                    config.db_timeout_ms
+                ),
+            ));
+            return new_health(checks);
+        }
+    };
+
+    let ping = tokio::time::timeout(timeout, db::ping_db(&client)).await;
+    let elapsed_ms = elapsed_ms(start);
+    checks.push(match ping {
+        Ok(Ok(_)) if elapsed_ms > config.db_latency_threshold_ms as f64 => failed(
+            "database",
+            start,
+            format!(
+                "latency {:.1}ms exceeds threshold {}ms",
+                elapsed_ms, config.db_latency_threshold_ms
+            ),
+        ),
+        Ok(Ok(_)) => passed("database", start),
+        Ok(Err(err)) => failed("database", start, err.to_string()),
+        Err(_) => failed(
+            "database",
+            start,
+            format!("ping timed out after {}ms", config.db_timeout_ms),
+        ),
+    });
+
+    if config.check_migrations {
+        let start = Instant::now();
+        let latest: i64 = LATEST_MIGRATION.parse().unwrap_or(0);
+        checks.push(match db::schema_version(&client).await {
+            Ok(Some((_, true))) => failed("migrations", start, "schema is dirty".to_string()),
+            Ok(Some((version, _))) if version < latest => failed(
+                "migrations",
+                start,
+                format!(
+                    "pending migrations: schema at {}, expected {}",
+                    version, latest
+                ),
+            ),
+            Ok(Some(_)) => passed("migrations", start),
+            Ok(None) => failed("migrations", start, "no migrations applied".to_string()),
+            Err(err) => failed("migrations", start, err.to_string()),
+        });
+    }
+
+    new_health(checks)
+}
+
+fn check_shutdown(shutdown: &Shutdown) -> HealthCheck {
+    let start = Instant::now();
+    if shutdown.is_shutting_down() {
+        failed("shutdown", start, "service is shutting down".to_string())
+    } else {
+        passed("shutdown", start)
+    }
+}
+
+fn check_pool(pool: &Pool, config: &HealthConfig) -> HealthCheck {
+    let start = Instant::now();
+    let status = pool.status();
+    let in_use = status.size.saturating_sub(status.available);
+    let saturation = if status.max_size == 0 {
+        1.0
+    } else {
+        in_use as f64 / status.max_size as f64
+    };
+    let detail = format!(
+        "{} of {} connections in use, {} waiting",
+        in_use, status.max_size, status.waiting
+    );
+
+    if saturation >= config.pool_saturation_threshold || status.waiting > 0 {
+        failed("pool", start, detail)
+    } else {
+        let mut check = passed("pool", start);
+        check.message = Some(detail);
+        check
+    }
+}
+
+fn passed(name: &str, start: Instant) -> HealthCheck {
+    HealthCheck {
+        name: name.to_string(),
+        status: CheckStatus::Pass,
+        duration_ms: elapsed_ms(start),
+        message: None,
+    }
+}
+
+fn failed(name: &str, start: Instant, message: String) -> HealthCheck {
+    HealthCheck {
+        name: name.to_string(),
+        status: CheckStatus::Fail,
+        duration_ms: elapsed_ms(start),
+        message: Some(message),
+    }
+}
+
+fn elapsed_ms(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
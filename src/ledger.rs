@@ -0,0 +1,1125 @@
+// Account, transaction and scheduled transfer operations shared by the HTTP
+// handlers and the gRPC services. Each operation validates its parameters,
+// checks that the caller may act on the accounts involved and writes changes
+// together with their audit entry and webhook deliveries. Failures are
+// returned as LedgerError, which the callers map to an HTTP status or a gRPC
+// code.
+use crate::{
+    audit::{self, AuditContext},
+    auth::Principal,
+    config::{AccountsConfig, VelocityConfig},
+    db,
+    errors::MyError,
+    model::{
+        Account, AccountBalance, AccountFilter, AccountParams, AccountUpdate, BatchMode,
+        ScheduledTransfer, ScheduledTransferParams, Transaction, TransactionFilter,
+        TransactionParams,
+    },
+    scheduler::{self, Recurrence},
+    webhooks,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use deadpool_postgres::{Client, GenericClient};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::Duration;
+use tokio_postgres::error::SqlState;
+
+// longest transfer description, and longest reference, category and external id
+const MAX_DESCRIPTION_LEN: usize = 1000;
+const MAX_LABEL_LEN: usize = 255;
+
+#[derive(Debug)]
+pub enum LedgerError {
+    // missing or invalid parameters
+    Invalid(String),
+    // the caller may not act on an account
+    Forbidden(String),
+    NotFound,
+    Conflict(String),
+    // a transfer velocity limit was reached, retry after the duration
+    Limited(String, Duration),
+    // a transfer repeats the external id of the caller's existing transaction
+    Duplicate(Box<Transaction>),
+    Internal(String),
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::NotFound => f.write_str("NotFound"),
+            LedgerError::Duplicate(tx) => write!(
+                f,
+                "Transaction with external id {:?} already exists as id {}",
+                tx.external_id.as_deref().unwrap_or_default(),
+                tx.id.unwrap_or_default()
+            ),
+            LedgerError::Invalid(message)
+            | LedgerError::Forbidden(message)
+            | LedgerError::Conflict(message)
+            | LedgerError::Limited(message, _)
+            | LedgerError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+impl From<MyError> for LedgerError {
+    fn from(err: MyError) -> Self {
+        match err {
+            MyError::NotFound => LedgerError::NotFound,
+            err => LedgerError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<tokio_postgres::Error> for LedgerError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        LedgerError::Internal(err.to_string())
+    }
+}
+
+// owns reports whether the caller may act on the account. Callers are
+// unrestricted when authentication is disabled.
+fn owns(principal: Option<&Principal>, account_id: i64) -> bool {
+    principal.is_none_or(|p| p.owns(account_id))
+}
+
+// get_accounts returns every account matching the filter, or only the
+// caller's own accounts when the caller is restricted to some.
+pub async fn get_accounts(
+    client: &Client,
+    principal: Option<&Principal>,
+    filter: &AccountFilter,
+) -> Result<Vec<Account>, LedgerError> {
+    let owned = principal.and_then(|p| p.accounts.as_ref());
+    Ok(db::find_accounts(client, filter, owned, None, None).await?)
+}
+
+pub async fn get_account(
+    client: &Client,
+    principal: Option<&Principal>,
+    account_id: i64,
+) -> Result<Account, LedgerError> {
+    if !owns(principal, account_id) {
+        return Err(LedgerError::Forbidden(
+            "Account not owned by caller".to_string(),
+        ));
+    }
+    Ok(db::get_account_by_id(client, account_id).await?)
+}
+
+// get_balance returns the balance of an account at as_of (defaults to now),
+// computed from the transaction history.
+pub async fn get_balance(
+    client: &Client,
+    principal: Option<&Principal>,
+    account_id: i64,
+    as_of: Option<DateTime<Utc>>,
+) -> Result<AccountBalance, LedgerError> {
+    if !owns(principal, account_id) {
+        return Err(LedgerError::Forbidden(
+            "Account not owned by caller".to_string(),
+        ));
+    }
+    let as_of = point_in_time(as_of)?;
+    Ok(db::account_balance_at(client, account_id, as_of).await?)
+}
+
+// get_balances returns the balance at as_of (defaults to now) of every account
+// that existed then and that the caller may see.
+pub async fn get_balances(
+    client: &Client,
+    principal: Option<&Principal>,
+    as_of: Option<DateTime<Utc>>,
+) -> Result<Vec<AccountBalance>, LedgerError> {
+    let as_of = point_in_time(as_of)?;
+    let balances = db::balances_at(client, as_of).await?;
+    Ok(match principal.and_then(|p| p.accounts.as_ref()) {
+        Some(owned) => balances
+            .into_iter()
+            .filter(|b| owned.contains(&b.account_id))
+            .collect(),
+        None => balances,
+    })
+}
+
+// point_in_time defaults a point-in-time parameter to now and rejects future
+// times, whose balance is not known yet.
+fn point_in_time(as_of: Option<DateTime<Utc>>) -> Result<DateTime<Utc>, LedgerError> {
+    let now = Utc::now();
+    match as_of {
+        Some(as_of) if as_of > now => {
+            Err(LedgerError::Invalid("as_of is in the future".to_string()))
+        }
+        Some(as_of) => Ok(as_of),
+        None => Ok(now),
+    }
+}
+
+// check_metadata checks that account metadata is a JSON object within the
+// configured size.
+pub fn check_metadata(limits: &AccountsConfig, metadata: &Value) -> Result<(), LedgerError> {
+    if !metadata.is_object() {
+        return Err(LedgerError::Invalid(
+            "metadata must be a JSON object".to_string(),
+        ));
+    }
+    if metadata.to_string().len() > limits.max_metadata_bytes {
+        return Err(LedgerError::Invalid(format!(
+            "metadata exceeds the limit of {} bytes",
+            limits.max_metadata_bytes
+        )));
+    }
+    Ok(())
+}
+
+// check_tags validates account tags against the configured limits and returns
+// them sorted without duplicates. Tags may not contain commas, which separate
+// them in query parameters.
+pub fn check_tags(limits: &AccountsConfig, tags: Vec<String>) -> Result<Vec<String>, LedgerError> {
+    if let Some(tag) = tags
+        .iter()
+        .find(|tag| tag.is_empty() || tag.contains(',') || tag.chars().count() > limits.max_tag_len)
+    {
+        return Err(LedgerError::Invalid(format!(
+            "Invalid tag {:?}: tags must be 1 to {} characters without commas",
+            tag, limits.max_tag_len
+        )));
+    }
+    let mut tags = tags;
+    tags.sort();
+    tags.dedup();
+    if tags.len() > limits.max_tags {
+        return Err(LedgerError::Invalid(format!(
+            "Accounts may carry at most {} tags",
+            limits.max_tags
+        )));
+    }
+    Ok(tags)
+}
+
+// new_account checks the parameters of an account to create and returns it
+// with a zero balance.
+pub fn new_account(limits: &AccountsConfig, params: AccountParams) -> Result<Account, LedgerError> {
+    if params.email.is_none() {
+        return Err(LedgerError::Invalid("No email supplied".to_string()));
+    }
+    if params.username.is_none() {
+        return Err(LedgerError::Invalid("No username supplied".to_string()));
+    }
+    if let Some(metadata) = &params.metadata {
+        check_metadata(limits, metadata)?;
+    }
+    let tags = params
+        .tags
+        .map(|tags| check_tags(limits, tags))
+        .transpose()?;
+    // Set timestamp server-side
+    Ok(Account {
+        id: None, // To be set by Postgres
+        username: params.username,
+        email: params.email,
+        balance: Some(0),
+        created_at: Some(Utc::now()),
+        metadata: params.metadata,
+        tags,
+    })
+}
+
+// create_account registers a new account with a zero balance.
+pub async fn create_account(
+    client: &mut Client,
+    context: &AuditContext,
+    limits: &AccountsConfig,
+    params: AccountParams,
+) -> Result<Account, LedgerError> {
+    let account = new_account(limits, params)?;
+
+    // the account and its audit entry are committed together
+    let db_tx = client.transaction().await?;
+    let new_account = match db::create_account(&db_tx, account).await {
+        Ok(new_account) => new_account,
+        Err(MyError::PGError(err)) if err.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+            return Err(LedgerError::Conflict("Username already taken".to_string()));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    audit::record(
+        &db_tx,
+        context,
+        "create_account",
+        "account",
+        new_account.id,
+        None,
+        Some(&new_account),
+    )
+    .await?;
+    webhooks::enqueue(&db_tx, webhooks::ACCOUNT_CREATED, &new_account).await?;
+    db_tx.commit().await?;
+
+    Ok(new_account)
+}
+
+// create_accounts registers a batch of accounts with zero balances. Atomic
+// batches create all accounts or none, best effort batches keep the ones that
+// succeed. Usernames repeated within the batch or already taken fail with a
+// Conflict.
+pub async fn create_accounts(
+    client: &mut Client,
+    context: &AuditContext,
+    limits: &AccountsConfig,
+    mode: BatchMode,
+    accounts: Vec<AccountParams>,
+) -> Result<BatchOutcome<Account>, LedgerError> {
+    let items = accounts.len();
+    let mut usernames = HashSet::new();
+    let mut failures = Vec::new();
+    let mut indices = Vec::new();
+    let mut new_accounts = Vec::new();
+    for (index, params) in accounts.into_iter().enumerate() {
+        match check_account(limits, params, &mut usernames) {
+            Ok(account) => {
+                indices.push(index);
+                new_accounts.push(account);
+            }
+            Err(err) => failures.push((index, err)),
+        }
+    }
+    if mode == BatchMode::Atomic && !failures.is_empty() {
+        return Ok(BatchOutcome::RolledBack(failures));
+    }
+
+    // the accounts and their audit entries are committed together
+    let db_tx = client.transaction().await?;
+    let mut created: HashMap<String, Account> = db::create_accounts(&db_tx, &new_accounts)
+        .await?
+        .into_iter()
+        .map(|account| (account.username.clone().unwrap_or_default(), account))
+        .collect();
+
+    // usernames are unique, so they match the created rows to the input
+    let mut outcomes: Vec<Option<Result<Account, LedgerError>>> =
+        (0..items).map(|_| None).collect();
+    let mut conflicts = Vec::new();
+    for (index, account) in indices.into_iter().zip(new_accounts) {
+        match created.remove(account.username.as_deref().unwrap_or_default()) {
+            Some(new_account) => outcomes[index] = Some(Ok(new_account)),
+            None => conflicts.push((
+                index,
+                LedgerError::Conflict("Username already taken".to_string()),
+            )),
+        }
+    }
+    if mode == BatchMode::Atomic && !conflicts.is_empty() {
+        db_tx.rollback().await?;
+        return Ok(BatchOutcome::RolledBack(conflicts));
+    }
+
+    for new_account in outcomes.iter().flatten().flatten() {
+        audit::record(
+            &db_tx,
+            context,
+            "create_account",
+            "account",
+            new_account.id,
+            None,
+            Some(new_account),
+        )
+        .await?;
+        webhooks::enqueue(&db_tx, webhooks::ACCOUNT_CREATED, new_account).await?;
+    }
+    db_tx.commit().await?;
+
+    for (index, err) in failures.into_iter().chain(conflicts) {
+        outcomes[index] = Some(Err(err));
+    }
+    Ok(BatchOutcome::Done(outcomes.into_iter().flatten().collect()))
+}
+
+// check_account applies the checks of create_account to one account of a
+// batch, rejecting usernames repeated within the batch.
+fn check_account(
+    limits: &AccountsConfig,
+    params: AccountParams,
+    usernames: &mut HashSet<String>,
+) -> Result<Account, LedgerError> {
+    let account = new_account(limits, params)?;
+    if !usernames.insert(account.username.clone().unwrap_or_default()) {
+        return Err(LedgerError::Conflict(
+            "Username repeated in batch".to_string(),
+        ));
+    }
+    Ok(account)
+}
+
+// update_account replaces the metadata and/or tags of an account.
+pub async fn update_account(
+    client: &mut Client,
+    principal: Option<&Principal>,
+    context: &AuditContext,
+    limits: &AccountsConfig,
+    account_id: i64,
+    update: AccountUpdate,
+) -> Result<Account, LedgerError> {
+    if update.metadata.is_none() && update.tags.is_none() {
+        return Err(LedgerError::Invalid(
+            "No metadata or tags supplied".to_string(),
+        ));
+    }
+    if !owns(principal, account_id) {
+        return Err(LedgerError::Forbidden(
+            "Account not owned by caller".to_string(),
+        ));
+    }
+    if let Some(metadata) = &update.metadata {
+        check_metadata(limits, metadata)?;
+    }
+    let tags = update
+        .tags
+        .map(|tags| check_tags(limits, tags))
+        .transpose()?;
+
+    // the change and its audit entry are committed together
+    let db_tx = client.transaction().await?;
+    let before = db::lock_account(&db_tx, account_id).await?;
+    let after =
+        db::update_account(&db_tx, account_id, update.metadata.as_ref(), tags.as_ref()).await?;
+    audit::record(
+        &db_tx,
+        context,
+        "update_account",
+        "account",
+        Some(account_id),
+        Some(&before),
+        Some(&after),
+    )
+    .await?;
+    webhooks::enqueue(&db_tx, webhooks::ACCOUNT_UPDATED, &after).await?;
+    db_tx.commit().await?;
+
+    Ok(after)
+}
+
+// get_transactions returns every transaction matching the filter, or only
+// those involving the caller's own accounts when the caller is restricted to
+// some.
+pub async fn get_transactions(
+    client: &Client,
+    principal: Option<&Principal>,
+    filter: &TransactionFilter,
+) -> Result<Vec<Transaction>, LedgerError> {
+    let owned = principal.and_then(|p| p.accounts.as_ref());
+    Ok(db::find_transactions(client, filter, owned, None, None).await?)
+}
+
+pub async fn get_transaction(
+    client: &Client,
+    principal: Option<&Principal>,
+    tx_id: i64,
+) -> Result<Transaction, LedgerError> {
+    let tx = db::get_transaction_by_id(client, tx_id).await?;
+
+    // either side of the transfer may view it
+    let owned = [tx.from_account, tx.to_account]
+        .into_iter()
+        .flatten()
+        .any(|id| owns(principal, id));
+    if !owned {
+        return Err(LedgerError::Forbidden(
+            "Transaction does not involve an account owned by caller".to_string(),
+        ));
+    }
+    Ok(tx)
+}
+
+// check_transfer checks the amount and accounts of a transfer. Amounts must be
+// positive: a negative amount would pull funds out of the credited account,
+// which the caller need not own.
+pub fn check_transfer(from_account: i64, to_account: i64, amount: i64) -> Result<(), LedgerError> {
+    if amount <= 0 {
+        return Err(LedgerError::Invalid("Amount must be positive".to_string()));
+    }
+    if from_account == to_account {
+        return Err(LedgerError::Invalid(
+            "From and to account must differ".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+// check_details checks the caller supplied details of a transfer. They may
+// not contain control characters, which include the NUL Postgres refuses in
+// text, except for line breaks and tabs in descriptions.
+pub fn check_details(params: &TransactionParams) -> Result<(), LedgerError> {
+    let fields = [
+        (
+            "description",
+            &params.description,
+            MAX_DESCRIPTION_LEN,
+            true,
+        ),
+        ("reference", &params.reference, MAX_LABEL_LEN, false),
+        ("category", &params.category, MAX_LABEL_LEN, false),
+        ("external_id", &params.external_id, MAX_LABEL_LEN, false),
+    ];
+    for (name, value, max_len, multiline) in fields {
+        let Some(value) = value else {
+            continue;
+        };
+        if value.trim().is_empty() {
+            return Err(LedgerError::Invalid(format!("{} may not be empty", name)));
+        }
+        if value.chars().count() > max_len {
+            return Err(LedgerError::Invalid(format!(
+                "{} exceeds the limit of {} characters",
+                name, max_len
+            )));
+        }
+        let allowed = |c: char| multiline && matches!(c, '\n' | '\r' | '\t');
+        if value.chars().any(|c| c.is_control() && !allowed(c)) {
+            return Err(LedgerError::Invalid(format!(
+                "{} may not contain control characters",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+// NewTransfer is a transfer whose parameters were checked by new_transfer,
+// ready to be stored by insert_transfer.
+pub struct NewTransfer {
+    from_account: i64,
+    amount: i64,
+    tx: Transaction,
+}
+
+// new_transfer checks the parameters of a transfer made by the caller and
+// returns it timestamped now. Only the owner of the debited account may move
+// funds out of it.
+pub fn new_transfer(
+    principal: Option<&Principal>,
+    context: &AuditContext,
+    params: &TransactionParams,
+) -> Result<NewTransfer, LedgerError> {
+    let Some(from_account) = params.from_account else {
+        return Err(LedgerError::Invalid("No from account supplied".to_string()));
+    };
+    let Some(to_account) = params.to_account else {
+        return Err(LedgerError::Invalid("No to account supplied".to_string()));
+    };
+    let Some(amount) = params.amount else {
+        return Err(LedgerError::Invalid("No amount supplied".to_string()));
+    };
+    check_transfer(from_account, to_account, amount)?;
+    check_details(params)?;
+    if !owns(principal, from_account) {
+        return Err(LedgerError::Forbidden(
+            "From account not owned by caller".to_string(),
+        ));
+    }
+    // Set timestamp server-side
+    let tx = Transaction {
+        id: None, // To be set by Postgres
+        from_account: Some(from_account),
+        to_account: Some(to_account),
+        amount: Some(amount),
+        created_at: Some(Utc::now()),
+        description: params.description.clone(),
+        reference: params.reference.clone(),
+        category: params.category.clone(),
+        external_id: params.external_id.clone(),
+        created_by: Some(context.actor.clone()),
+    };
+    Ok(NewTransfer {
+        from_account,
+        amount,
+        tx,
+    })
+}
+
+// insert_transfer stores a checked transfer with its audit entry and webhook
+// delivery, applying the per account velocity limits when configured. A
+// transfer repeating the external id of one the caller made before fails with
+// that transaction. One losing the race against a concurrent transfer with the
+// same external id fails with a Conflict and leaves the transaction aborted.
+pub async fn insert_transfer(
+    client: &impl GenericClient,
+    context: &AuditContext,
+    velocity: Option<&VelocityConfig>,
+    transfer: NewTransfer,
+) -> Result<Transaction, LedgerError> {
+    let NewTransfer {
+        from_account,
+        amount,
+        tx,
+    } = transfer;
+    if let Some(external_id) = &tx.external_id {
+        if let Some(existing) =
+            db::get_transaction_by_external_id(client, &context.actor, external_id).await?
+        {
+            return Err(LedgerError::Duplicate(Box::new(existing)));
+        }
+    }
+    lock_transfer_accounts(client, from_account, tx.to_account.unwrap_or_default()).await?;
+    if let Some(velocity) = velocity {
+        check_velocity(client, velocity, from_account, amount).await?;
+    }
+    let new_tx = db::create_transaction(client, tx)
+        .await
+        .map_err(|err| match &err {
+            MyError::PGError(pg_err) if pg_err.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+                LedgerError::Invalid("Account does not exist".to_string())
+            }
+            MyError::PGError(pg_err) if pg_err.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                LedgerError::Conflict("External id already used".to_string())
+            }
+            _ => err.into(),
+        })?;
+    audit::record(
+        client,
+        context,
+        "create_transaction",
+        "transaction",
+        new_tx.id,
+        None,
+        Some(&new_tx),
+    )
+    .await?;
+    webhooks::enqueue(client, webhooks::TRANSFER_CREATED, &new_tx).await?;
+    Ok(new_tx)
+}
+
+// create_transaction transfers amount between two accounts, applying the per
+// account velocity limits when configured. A transfer repeating the external
+// id of one the caller made before fails with that transaction, so that
+// retried requests transfer once.
+pub async fn create_transaction(
+    client: &mut Client,
+    principal: Option<&Principal>,
+    context: &AuditContext,
+    velocity: Option<&VelocityConfig>,
+    params: TransactionParams,
+) -> Result<Transaction, LedgerError> {
+    let transfer = new_transfer(principal, context, &params)?;
+
+    // the transfer and its audit entry are committed together
+    let db_tx = client.transaction().await?;
+    let new_tx = match insert_transfer(&db_tx, context, velocity, transfer).await {
+        Ok(new_tx) => new_tx,
+        // a concurrent request with the same external id committed first
+        Err(LedgerError::Conflict(message)) => {
+            db_tx.rollback().await?;
+            let external_id = params.external_id.unwrap_or_default();
+            return Err(
+                match db::get_transaction_by_external_id(&*client, &context.actor, &external_id)
+                    .await?
+                {
+                    Some(existing) => LedgerError::Duplicate(Box::new(existing)),
+                    None => LedgerError::Conflict(message),
+                },
+            );
+        }
+        Err(err) => return Err(err),
+    };
+    db_tx.commit().await?;
+
+    Ok(new_tx)
+}
+
+// BatchOutcome is the result of a batch of accounts or transfers.
+pub enum BatchOutcome<T> {
+    // every item was attempted; the outcomes are in input order
+    Done(Vec<Result<T, LedgerError>>),
+    // the atomic batch was rolled back because of these items
+    RolledBack(Vec<(usize, LedgerError)>),
+}
+
+// create_transactions executes a batch of transfers. Atomic batches commit all
+// transfers or none, best effort batches run each transfer in its own
+// savepoint and keep the ones that succeed. The checks that need no database
+// run first, so that an atomic batch reports every invalid transfer at once.
+pub async fn create_transactions(
+    client: &mut Client,
+    principal: Option<&Principal>,
+    context: &AuditContext,
+    velocity: Option<&VelocityConfig>,
+    mode: BatchMode,
+    transfers: &[TransactionParams],
+) -> Result<BatchOutcome<Transaction>, LedgerError> {
+    let mut external_ids = HashSet::new();
+    let checked: Vec<Result<NewTransfer, LedgerError>> = transfers
+        .iter()
+        .map(|params| {
+            let transfer = new_transfer(principal, context, params)?;
+            match &params.external_id {
+                Some(external_id) if !external_ids.insert(external_id.clone()) => Err(
+                    LedgerError::Conflict("External id repeated in batch".to_string()),
+                ),
+                _ => Ok(transfer),
+            }
+        })
+        .collect();
+    if mode == BatchMode::Atomic && checked.iter().any(Result::is_err) {
+        return Ok(BatchOutcome::RolledBack(
+            checked
+                .into_iter()
+                .enumerate()
+                .filter_map(|(index, check)| check.err().map(|err| (index, err)))
+                .collect(),
+        ));
+    }
+
+    let mut db_tx = client.transaction().await?;
+    let mut outcomes = Vec::with_capacity(checked.len());
+    for (index, check) in checked.into_iter().enumerate() {
+        let outcome = match check {
+            Err(err) => Err(err),
+            Ok(transfer) if mode == BatchMode::Atomic => {
+                match insert_transfer(&db_tx, context, velocity, transfer).await {
+                    Ok(new_tx) => Ok(new_tx),
+                    Err(err) => {
+                        db_tx.rollback().await?;
+                        return Ok(BatchOutcome::RolledBack(vec![(index, err)]));
+                    }
+                }
+            }
+            Ok(transfer) => {
+                let savepoint = db_tx.transaction().await?;
+                match insert_transfer(&savepoint, context, velocity, transfer).await {
+                    Ok(new_tx) => {
+                        savepoint.commit().await?;
+                        Ok(new_tx)
+                    }
+                    Err(err) => {
+                        savepoint.rollback().await?;
+                        Err(err)
+                    }
+                }
+            }
+        };
+        outcomes.push(outcome);
+    }
+    db_tx.commit().await?;
+
+    Ok(BatchOutcome::Done(outcomes))
+}
+
+// create_scheduled_transfer schedules a transfer for a later time or on a
+// recurrence. The scheduler executes it from the from account, which only its
+// owner may schedule transfers out of.
+pub async fn create_scheduled_transfer(
+    client: &mut Client,
+    principal: Option<&Principal>,
+    context: &AuditContext,
+    params: ScheduledTransferParams,
+) -> Result<ScheduledTransfer, LedgerError> {
+    let Some(from_account) = params.from_account else {
+        return Err(LedgerError::Invalid("No from account supplied".to_string()));
+    };
+    let Some(to_account) = params.to_account else {
+        return Err(LedgerError::Invalid("No to account supplied".to_string()));
+    };
+    let Some(amount) = params.amount else {
+        return Err(LedgerError::Invalid("No amount supplied".to_string()));
+    };
+    check_transfer(from_account, to_account, amount)?;
+    if params.run_at.is_none() && params.recurrence.is_none() {
+        return Err(LedgerError::Invalid(
+            "No run_at or recurrence supplied".to_string(),
+        ));
+    }
+    if !owns(principal, from_account) {
+        return Err(LedgerError::Forbidden(
+            "From account not owned by caller".to_string(),
+        ));
+    }
+
+    let recurrence = params
+        .recurrence
+        .as_deref()
+        .map(str::parse::<Recurrence>)
+        .transpose()
+        .map_err(LedgerError::Invalid)?;
+    // a one-off transfer runs once at run_at, which must not have passed
+    if let (Some(run_at), None) = (params.run_at, &recurrence) {
+        if run_at < Utc::now() - TimeDelta::seconds(scheduler::RUN_AT_SKEW_SECS) {
+            return Err(LedgerError::Invalid("run_at is in the past".to_string()));
+        }
+    }
+    let from = params.run_at.unwrap_or_else(Utc::now);
+    let Some(first_run) = recurrence.as_ref().map_or(Some(from), |r| r.first(from)) else {
+        return Err(LedgerError::Invalid(
+            "Recurrence has no future occurrence".to_string(),
+        ));
+    };
+
+    let schedule = ScheduledTransfer {
+        id: 0, // To be set by Postgres
+        from_account,
+        to_account,
+        amount,
+        recurrence: params.recurrence,
+        status: scheduler::ACTIVE.to_string(),
+        starts_at: Some(first_run),
+        next_run_at: Some(first_run),
+        retry_at: None,
+        attempts: 0,
+        last_error: None,
+        last_run_at: None,
+        runs: 0,
+        created_by: Some(context.actor.clone()),
+        created_at: None,
+        updated_at: None,
+    };
+
+    // the schedule and its audit entry are committed together
+    let db_tx = client.transaction().await?;
+    let schedule = match db::create_scheduled_transfer(&db_tx, &schedule).await {
+        Ok(schedule) => schedule,
+        Err(MyError::PGError(err)) if err.code() == Some(&SqlState::FOREIGN_KEY_VIOLATION) => {
+            return Err(LedgerError::Invalid("Account does not exist".to_string()));
+        }
+        Err(err) => return Err(err.into()),
+    };
+    audit::record(
+        &db_tx,
+        context,
+        "create_scheduled_transfer",
+        "scheduled_transfer",
+        Some(schedule.id),
+        None,
+        Some(&schedule),
+    )
+    .await?;
+    db_tx.commit().await?;
+
+    Ok(schedule)
+}
+
+// ScheduleAction is a change of status made to a scheduled transfer by its
+// owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScheduleAction {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+impl ScheduleAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScheduleAction::Pause => "pause",
+            ScheduleAction::Resume => "resume",
+            ScheduleAction::Cancel => "cancel",
+        }
+    }
+
+    // transition returns the statuses the action applies to and the status
+    // it leads to.
+    fn transition(&self) -> (&'static [&'static str], &'static str) {
+        match self {
+            ScheduleAction::Pause => (&[scheduler::ACTIVE], scheduler::PAUSED),
+            ScheduleAction::Resume => (&[scheduler::PAUSED, scheduler::FAILED], scheduler::ACTIVE),
+            ScheduleAction::Cancel => (
+                &[scheduler::ACTIVE, scheduler::PAUSED, scheduler::FAILED],
+                scheduler::CANCELLED,
+            ),
+        }
+    }
+}
+
+// change_scheduled_transfer pauses, resumes or cancels a scheduled transfer.
+// The row is locked so that the change cannot interleave with a run of the
+// scheduler. Resuming clears the retry state, and the occurrences that fell due
+// meanwhile are executed once at the next poll.
+pub async fn change_scheduled_transfer(
+    client: &mut Client,
+    principal: Option<&Principal>,
+    context: &AuditContext,
+    id: i64,
+    action: ScheduleAction,
+) -> Result<ScheduledTransfer, LedgerError> {
+    let (allowed, status) = action.transition();
+
+    // the change, its audit entry and webhook delivery are committed together
+    let db_tx = client.transaction().await?;
+    let before = db::get_scheduled_transfer_by_id(&db_tx, id, true).await?;
+    if !owns(principal, before.from_account) {
+        return Err(LedgerError::Forbidden(
+            "From account not owned by caller".to_string(),
+        ));
+    }
+    if !allowed.contains(&before.status.as_str()) {
+        return Err(LedgerError::Conflict(format!(
+            "Cannot {} a {} scheduled transfer",
+            action.name(),
+            before.status
+        )));
+    }
+
+    let mut schedule = before.clone();
+    schedule.status = status.to_string();
+    if action == ScheduleAction::Resume {
+        schedule.attempts = 0;
+        schedule.retry_at = None;
+    }
+    let schedule = db::update_scheduled_transfer(&db_tx, &schedule).await?;
+    audit::record(
+        &db_tx,
+        context,
+        &format!("{}_scheduled_transfer", action.name()),
+        "scheduled_transfer",
+        Some(schedule.id),
+        Some(&before),
+        Some(&schedule),
+    )
+    .await?;
+    webhooks::enqueue(
+        &db_tx,
+        webhooks::SCHEDULED_TRANSFER_STATUS_CHANGED,
+        &schedule,
+    )
+    .await?;
+    db_tx.commit().await?;
+
+    Ok(schedule)
+}
+
+// lock_transfer_accounts locks both accounts of a transfer until the end of
+// the transaction, before their balances change.
+pub async fn lock_transfer_accounts(
+    client: &impl GenericClient,
+    from_account: i64,
+    to_account: i64,
+) -> Result<(), LedgerError> {
+    if db::lock_accounts(client, &[from_account, to_account]).await? < 2 {
+        return Err(LedgerError::Invalid("Account does not exist".to_string()));
+    }
+    Ok(())
+}
+
+// check_velocity applies the per account velocity limits on transfers out of
+// the debited account. It locks the account until the end of the transaction,
+// so that concurrent transfers out of it are checked one after the other and
+// the transfers made earlier in the transaction count towards the limits.
+pub async fn check_velocity(
+    client: &impl GenericClient,
+    velocity: &VelocityConfig,
+    from_account: i64,
+    amount: i64,
+) -> Result<(), LedgerError> {
+    if let Some(max) = velocity.max_amount.filter(|max| amount > *max) {
+        return Err(LedgerError::Invalid(format!(
+            "Amount exceeds the limit of {} per {}s",
+            max, velocity.window_secs
+        )));
+    }
+
+    db::lock_account(client, from_account)
+        .await
+        .map_err(|err| match err {
+            MyError::NotFound => LedgerError::Invalid("Account does not exist".to_string()),
+            err => err.into(),
+        })?;
+    let (count, total, oldest) =
+        db::transfer_velocity(client, from_account, velocity.window_secs).await?;
+    if velocity.max_transfers.is_some_and(|max| count >= max)
+        || velocity.max_amount.is_some_and(|max| total + amount > max)
+    {
+        // the window frees up once the oldest transfer in it expires
+        let retry_after = oldest
+            .map(|oldest| {
+                let expires = oldest + chrono::Duration::seconds(velocity.window_secs as i64);
+                (expires - Utc::now()).to_std().unwrap_or_default()
+            })
+            .unwrap_or(Duration::from_secs(velocity.window_secs));
+        return Err(LedgerError::Limited(
+            "Transfer velocity limit exceeded for from account".to_string(),
+            retry_after,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_details, check_metadata, check_tags, LedgerError, MAX_DESCRIPTION_LEN, MAX_LABEL_LEN,
+    };
+    use crate::{config::AccountsConfig, model::TransactionParams};
+    use serde_json::json;
+
+    fn limits() -> AccountsConfig {
+        AccountsConfig {
+            max_metadata_bytes: 32,
+            max_tags: 3,
+            max_tag_len: 8,
+        }
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    fn invalid<T: std::fmt::Debug>(result: Result<T, LedgerError>) -> String {
+        match result {
+            Err(LedgerError::Invalid(message)) => message,
+            other => panic!("expected Invalid, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn metadata_must_be_an_object() {
+        assert!(check_metadata(&limits(), &json!({})).is_ok());
+        assert!(check_metadata(&limits(), &json!({"team": "ops"})).is_ok());
+        for metadata in [json!([1]), json!("text"), json!(1), json!(null)] {
+            assert_eq!(
+                invalid(check_metadata(&limits(), &metadata)),
+                "metadata must be a JSON object"
+            );
+        }
+    }
+
+    #[test]
+    fn metadata_size_counts_compact_json() {
+        // {"k":"<24 characters>"} is 32 bytes
+        let fits = json!({"k": "a".repeat(24)});
+        assert_eq!(fits.to_string().len(), 32);
+        assert!(check_metadata(&limits(), &fits).is_ok());
+        assert_eq!(
+            invalid(check_metadata(&limits(), &json!({"k": "a".repeat(25)}))),
+            "metadata exceeds the limit of 32 bytes"
+        );
+    }
+
+    #[test]
+    fn tags_are_sorted_and_deduplicated() {
+        assert_eq!(
+            check_tags(&limits(), tags(&["vip", "eu", "vip", "b2b"])).unwrap(),
+            tags(&["b2b", "eu", "vip"])
+        );
+        assert!(check_tags(&limits(), Vec::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn tags_must_be_short_and_without_commas() {
+        // lengths count characters, not bytes
+        assert!(check_tags(&limits(), tags(&["éééééééé"])).is_ok());
+        for tag in ["", "a,b", "ninechars"] {
+            assert_eq!(
+                invalid(check_tags(&limits(), tags(&[tag]))),
+                format!(
+                    "Invalid tag {:?}: tags must be 1 to 8 characters without commas",
+                    tag
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn tags_are_limited_after_deduplication() {
+        assert!(check_tags(&limits(), tags(&["a", "b", "c", "a", "c"])).is_ok());
+        assert_eq!(
+            invalid(check_tags(&limits(), tags(&["a", "b", "c", "d"]))),
+            "Accounts may carry at most 3 tags"
+        );
+    }
+
+    fn details(description: Option<&str>, label: Option<&str>) -> TransactionParams {
+        TransactionParams {
+            id: None,
+            from_account: Some(1),
+            to_account: Some(2),
+            amount: Some(1),
+            description: description.map(str::to_string),
+            reference: label.map(str::to_string),
+            category: label.map(str::to_string),
+            external_id: label.map(str::to_string),
+        }
+    }
+
+    // label applies value to one of the single line fields
+    fn label(name: &str, value: &str) -> TransactionParams {
+        let mut params = details(None, None);
+        let field = match name {
+            "reference" => &mut params.reference,
+            "category" => &mut params.category,
+            _ => &mut params.external_id,
+        };
+        *field = Some(value.to_string());
+        params
+    }
+
+    #[test]
+    fn details_are_optional() {
+        assert!(check_details(&details(None, None)).is_ok());
+        assert!(check_details(&details(Some("Salary October"), Some("PAY-2026-10"))).is_ok());
+    }
+
+    #[test]
+    fn details_may_not_be_blank() {
+        assert_eq!(
+            invalid(check_details(&details(Some(" \t"), None))),
+            "description may not be empty"
+        );
+        for name in ["reference", "category", "external_id"] {
+            assert_eq!(
+                invalid(check_details(&label(name, ""))),
+                format!("{} may not be empty", name)
+            );
+        }
+    }
+
+    #[test]
+    fn details_are_limited_in_characters() {
+        // multi-byte characters count once
+        let longest = "é".repeat(MAX_DESCRIPTION_LEN);
+        assert!(check_details(&details(Some(&longest), None)).is_ok());
+        assert_eq!(
+            invalid(check_details(&details(
+                Some(&format!("{}a", longest)),
+                None
+            ))),
+            "description exceeds the limit of 1000 characters"
+        );
+
+        let longest = "€".repeat(MAX_LABEL_LEN);
+        assert!(check_details(&details(None, Some(&longest))).is_ok());
+        for name in ["reference", "category", "external_id"] {
+            assert_eq!(
+                invalid(check_details(&label(name, &format!("{}a", longest)))),
+                format!("{} exceeds the limit of 255 characters", name)
+            );
+        }
+    }
+
+    #[test]
+    fn details_reject_control_characters() {
+        assert!(check_details(&details(Some("line one\r\nline two\tend"), None)).is_ok());
+        for description in [
+            "nul\0byte",
+            "bell\u{7}",
+            "escape\u{1b}[0m",
+            "next\u{85}line",
+        ] {
+            assert_eq!(
+                invalid(check_details(&details(Some(description), None))),
+                "description may not contain control characters"
+            );
+        }
+        for name in ["reference", "category", "external_id"] {
+            for value in ["two\nlines", "tab\there", "nul\0"] {
+                assert_eq!(
+                    invalid(check_details(&label(name, value))),
+                    format!("{} may not contain control characters", name)
+                );
+            }
+        }
+        // other scripts and symbols are accepted
+        assert!(check_details(&details(Some("Miete März 🏠"), Some("Ref-Ž/01 #3"))).is_ok());
+    }
+}
@@ -1,7 +1,30 @@
+pub mod audit;
+pub mod auth;
 pub mod client;
 pub mod config;
 pub mod db;
 pub mod errors;
+pub mod events;
+pub mod graphql;
+pub mod grpc;
 pub mod handlers;
+pub mod health;
+pub mod jwt;
+pub mod ledger;
+pub mod ledger_io;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
 pub mod model;
-pub mod server;
\ No newline at end of file
+pub mod openapi;
+pub mod outbox;
+pub mod ratelimit;
+pub mod reconcile;
+pub mod scheduler;
+pub mod search;
+pub mod server;
+pub mod shutdown;
+pub mod snapshots;
+pub mod statement;
+pub mod tls;
+pub mod webhooks;
@@ -0,0 +1,60 @@
+// Logger initialisation. Records are written either as plain text
+// (env_logger default format) or as one JSON object per line so that
+// log aggregators can index the structured key-values attached to
+// each record (request id, route, status, latency, account ids...).
+use crate::config::LogFormat;
+use chrono::{SecondsFormat, Utc};
+use env_logger::{fmt::Formatter, Env};
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Value as JsonValue};
+use std::io::Write;
+
+pub fn init(log_level: &str, format: LogFormat) {
+    let mut builder = env_logger::Builder::from_env(Env::default().default_filter_or(log_level));
+    builder.format_timestamp_millis();
+    if format == LogFormat::Json {
+        builder.format(format_json);
+    }
+    builder.init();
+}
+
+// format_json renders a log record (and its key-values) as a single line JSON object.
+fn format_json(buf: &mut Formatter, record: &Record) -> std::io::Result<()> {
+    let mut fields = Map::new();
+    fields.insert(
+        "ts".to_string(),
+        Utc::now()
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+            .into(),
+    );
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+
+    let mut visitor = JsonFields(&mut fields);
+    let _ = record.key_values().visit(&mut visitor);
+
+    writeln!(buf, "{}", JsonValue::Object(fields))
+}
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        // keep numbers and booleans typed, everything else is rendered as a string
+        let json = if let Some(v) = value.to_u64() {
+            JsonValue::from(v)
+        } else if let Some(v) = value.to_i64() {
+            JsonValue::from(v)
+        } else if let Some(v) = value.to_bool() {
+            JsonValue::from(v)
+        } else if let Some(v) = value.to_f64() {
+            JsonValue::from(v)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.insert(key.as_str().to_string(), json);
+        Ok(())
+    }
+}
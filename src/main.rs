@@ -1,13 +1,37 @@
+mod audit;
+mod auth;
 mod cli;
+mod commands;
 mod config;
 mod db;
 mod errors;
+mod events;
+mod graphql;
+mod grpc;
 mod handlers;
+mod health;
+mod jwt;
+mod ledger;
+mod ledger_io;
+mod logging;
+mod metrics;
+mod middleware;
 mod model;
+mod openapi;
+mod outbox;
+mod ratelimit;
+mod reconcile;
+mod scheduler;
+mod search;
 mod server;
+mod shutdown;
+mod snapshots;
+mod statement;
+mod tls;
+mod webhooks;
 
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, ConfigCommands, WebhooksCommands};
 use server::run_server;
 
 #[actix_web::main]
@@ -15,7 +39,21 @@ async fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Run(args) => run_server(&args.config).await,
+        Commands::Run(args) => run_server(&args.config.load_options()).await,
+        Commands::Config(ConfigCommands::Print(args)) => commands::print_config(&args),
+        Commands::Keys(command) => commands::keys(command).await,
+        Commands::Audit(command) => commands::audit(command).await,
+        Commands::Reconcile(args) => commands::reconcile(&args).await,
+        Commands::Statement(args) => commands::statement(&args).await,
+        Commands::Export(args) => commands::export(&args).await,
+        Commands::Import(args) => commands::import(&args).await,
+        Commands::Webhooks(WebhooksCommands::Receive(args)) => {
+            webhooks::receive(args.addr, args.secret, args.status).await
+        }
+        Commands::Openapi => {
+            print!("{}", openapi::spec());
+            Ok(())
+        }
         Commands::Version => {
             println!("Version: {}", env!("CARGO_PKG_VERSION"));
             println!("Compilation Date: {}", env!("BUILD_DATE"));
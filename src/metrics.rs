@@ -0,0 +1,121 @@
+// Service metrics exposed at /metrics in the Prometheus text format.
+use crate::{errors::MyError, model::ReconcileReport};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Default)]
+pub struct Metrics {
+    reconcile: Mutex<ReconcileMetrics>,
+}
+
+#[derive(Default)]
+struct ReconcileMetrics {
+    runs_succeeded: u64,
+    runs_failed: u64,
+    last_success_timestamp: f64,
+    last_duration_seconds: f64,
+    accounts_checked: i64,
+    discrepancies: usize,
+    // sum of the absolute balance differences found by the last run
+    drift: i64,
+    repaired_total: i64,
+}
+
+impl Metrics {
+    pub fn record_reconcile(&self, result: &Result<ReconcileReport, MyError>) {
+        let mut metrics = self.reconcile.lock().unwrap_or_else(|e| e.into_inner());
+        match result {
+            Ok(report) => {
+                metrics.runs_succeeded += 1;
+                metrics.last_success_timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or_default();
+                metrics.last_duration_seconds = report.duration_ms / 1000.0;
+                metrics.accounts_checked = report.accounts_checked;
+                metrics.discrepancies = report.discrepancies.len() - report.repaired as usize;
+                metrics.drift = report
+                    .discrepancies
+                    .iter()
+                    .map(|d| d.difference.abs())
+                    .sum();
+                metrics.repaired_total += report.repaired;
+            }
+            Err(_) => metrics.runs_failed += 1,
+        }
+    }
+
+    // render returns every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let reconcile = self.reconcile.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "ledger_reconcile_runs_total",
+            "counter",
+            "Balance reconciliation runs by result.",
+            &[
+                ("result=\"success\"", reconcile.runs_succeeded as f64),
+                ("result=\"failure\"", reconcile.runs_failed as f64),
+            ],
+        );
+        write_metric(
+            &mut out,
+            "ledger_reconcile_last_success_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful reconciliation run.",
+            &[("", reconcile.last_success_timestamp)],
+        );
+        write_metric(
+            &mut out,
+            "ledger_reconcile_duration_seconds",
+            "gauge",
+            "Duration of the last successful reconciliation run.",
+            &[("", reconcile.last_duration_seconds)],
+        );
+        write_metric(
+            &mut out,
+            "ledger_reconcile_accounts_checked",
+            "gauge",
+            "Accounts checked by the last successful reconciliation run.",
+            &[("", reconcile.accounts_checked as f64)],
+        );
+        write_metric(
+            &mut out,
+            "ledger_reconcile_discrepancies",
+            "gauge",
+            "Accounts left with a drifted balance after the last reconciliation run.",
+            &[("", reconcile.discrepancies as f64)],
+        );
+        write_metric(
+            &mut out,
+            "ledger_reconcile_drift_amount",
+            "gauge",
+            "Sum of absolute balance differences found by the last reconciliation run.",
+            &[("", reconcile.drift as f64)],
+        );
+        write_metric(
+            &mut out,
+            "ledger_reconcile_repaired_total",
+            "counter",
+            "Account balances repaired by reconciliation.",
+            &[("", reconcile.repaired_total as f64)],
+        );
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
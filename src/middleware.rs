@@ -0,0 +1,125 @@
+// Request context middleware. Every request is tagged with an id taken
+// from the X-Request-Id header (or generated when absent/invalid). The id
+// is echoed in the response headers, made available to handlers for error
+// bodies and attached to a structured access log line emitted once the
+// response is ready.
+use crate::auth::Principal;
+use crate::shutdown::Shutdown;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderName, HeaderValue},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error, HttpMessage, HttpRequest,
+};
+use log::Level;
+use std::time::Instant;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// longest client supplied request id that will be accepted as-is
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+// LedgerAccounts lists the account ids touched by a ledger operation. Handlers
+// record it on the request so that the access log can report it.
+#[derive(Clone, Debug, Default)]
+pub struct LedgerAccounts(pub Vec<i64>);
+
+pub async fn request_context(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let _in_flight = req
+        .app_data::<web::Data<Shutdown>>()
+        .map(|shutdown| shutdown.track_request());
+
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let mut res = next.call(req).await?;
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    let status = res.status();
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let request = res.request();
+    let method = request.method().to_string();
+    let route = request
+        .match_pattern()
+        .unwrap_or_else(|| request.path().to_string());
+    let accounts = request
+        .extensions()
+        .get::<LedgerAccounts>()
+        .map(|a| {
+            a.0.iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    let principal = request
+        .extensions()
+        .get::<Principal>()
+        .map(|p| p.name.clone())
+        .unwrap_or_default();
+
+    log::log!(
+        log_level(status),
+        request_id = request_id.as_str(),
+        method = method.as_str(),
+        route = route.as_str(),
+        status = status.as_u16(),
+        latency_ms = latency_ms,
+        accounts = accounts.as_str(),
+        principal = principal.as_str();
+        "{} {} {}",
+        method,
+        route,
+        status.as_u16()
+    );
+
+    Ok(res)
+}
+
+// request_id returns the id assigned to the request by the request_context middleware.
+pub fn request_id(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestId>().map(|id| id.0.clone())
+}
+
+// record_accounts attaches the account ids involved in a ledger operation to the request.
+pub fn record_accounts(req: &HttpRequest, accounts: &[i64]) {
+    req.extensions_mut()
+        .insert(LedgerAccounts(accounts.to_vec()));
+}
+
+fn log_level(status: StatusCode) -> Level {
+    if status.is_server_error() {
+        Level::Error
+    } else if status == StatusCode::NOT_FOUND
+        || status == StatusCode::BAD_REQUEST
+        || status == StatusCode::UNAUTHORIZED
+        || status == StatusCode::FORBIDDEN
+        || status == StatusCode::TOO_MANY_REQUESTS
+    {
+        Level::Warn
+    } else {
+        Level::Info
+    }
+}
@@ -1,16 +1,40 @@
+use async_graphql::InputObject;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize}; //
+use std::collections::BTreeMap;
 use tokio_pg_mapper_derive::PostgresMapper;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct AccountParams {
     pub id: Option<i64>,
     pub username: Option<String>,
     pub email: Option<String>,
     pub balance: Option<i64>,
+    // JSON object of caller supplied identifiers, e.g. a customer id
+    pub metadata: Option<serde_json::Value>,
+    pub tags: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+// AccountUpdate holds the fields changed by PATCH /accounts/{id}. Supplied
+// fields replace the stored ones.
+#[derive(Deserialize, Serialize, Debug, Default, ToSchema)]
+pub struct AccountUpdate {
+    pub metadata: Option<serde_json::Value>,
+    pub tags: Option<Vec<String>>,
+}
+
+// AccountsQuery holds the GET /accounts filters.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AccountsQuery {
+    // comma separated tags the accounts must all carry
+    pub tags: Option<String>,
+    // JSON object the account metadata must contain
+    pub metadata: Option<String>,
+}
+
+#[derive(Clone, Deserialize, PostgresMapper, Serialize, Debug, ToSchema)]
 #[pg_mapper(table = "accounts")]
 pub struct Account {
     pub id: Option<i64>,
@@ -24,17 +48,36 @@ pub struct Account {
         default // Use default for deserialization, which for Option<T> is None
     )]
     pub created_at: Option<DateTime<Utc>>,
+    pub metadata: Option<serde_json::Value>,
+    pub tags: Option<Vec<String>>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct TransactionParams {
     pub id: Option<i64>,
     pub from_account: Option<i64>,
     pub to_account: Option<i64>,
     pub amount: Option<i64>,
+    pub description: Option<String>,
+    pub reference: Option<String>,
+    pub category: Option<String>,
+    // the caller's own id of the transfer, unique per caller. A transfer
+    // repeating it is rejected with the existing transaction.
+    pub external_id: Option<String>,
 }
 
-#[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+// TransactionsQuery holds the GET /transactions filters.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct TransactionsQuery {
+    // text the description contains, ignoring case
+    pub description: Option<String>,
+    pub reference: Option<String>,
+    pub category: Option<String>,
+    pub external_id: Option<String>,
+}
+
+#[derive(Clone, Deserialize, PostgresMapper, Serialize, Debug, ToSchema)]
 #[pg_mapper(table = "transactions")]
 pub struct Transaction {
     pub id: Option<i64>,
@@ -48,22 +91,551 @@ pub struct Transaction {
         default // Use default for deserialization, which for Option<T> is None
     )]
     pub created_at: Option<DateTime<Utc>>,
+    pub description: Option<String>,
+    pub reference: Option<String>,
+    pub category: Option<String>,
+    pub external_id: Option<String>,
+    // principal that made the transfer, external ids are unique per principal
+    pub created_by: Option<String>,
+}
+
+// BatchMode selects how a batch request handles failing items: atomic
+// batches are rolled back entirely, best effort batches keep the items that
+// succeeded.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchMode {
+    #[default]
+    Atomic,
+    BestEffort,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct TransactionBatchParams {
+    #[serde(default)]
+    pub mode: BatchMode,
+    pub transfers: Vec<TransactionParams>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct AccountBatchParams {
+    #[serde(default)]
+    pub mode: BatchMode,
+    pub accounts: Vec<AccountParams>,
+}
+
+// BatchItemResult is the outcome of one item of a batch, in input order.
+// status is the HTTP status the item would have had as a single request.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct BatchItemResult<T> {
+    pub index: usize,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct BatchResult<T> {
+    pub mode: BatchMode,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<BatchItemResult<T>>,
+}
+
+// ScheduledTransfer is a transfer executed later by the scheduler, once or on
+// a recurrence.
+#[derive(Deserialize, PostgresMapper, Serialize, Debug, Clone, ToSchema)]
+#[pg_mapper(table = "scheduled_transfers")]
+pub struct ScheduledTransfer {
+    pub id: i64,
+    pub from_account: i64,
+    pub to_account: i64,
+    pub amount: i64,
+    pub recurrence: Option<String>,
+    pub status: String,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub starts_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub next_run_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub retry_at: Option<DateTime<Utc>>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub runs: i64,
+    pub created_by: Option<String>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct ScheduledTransferParams {
+    pub from_account: Option<i64>,
+    pub to_account: Option<i64>,
+    pub amount: Option<i64>,
+    // first execution, defaults to now for recurring transfers
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub run_at: Option<DateTime<Utc>>,
+    // daily, weekly, monthly or a five field cron expression (UTC)
+    pub recurrence: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ScheduledTransferQuery {
+    pub status: Option<String>,
+}
+
+// WebhookSubscription receives the ledger events of the listed types. The
+// secret is only returned when the subscription is created.
+#[derive(Clone, Deserialize, PostgresMapper, Serialize, Debug, ToSchema)]
+#[pg_mapper(table = "webhook_subscriptions")]
+pub struct WebhookSubscription {
+    pub id: i64,
+    pub url: String,
+    pub event_types: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub description: Option<String>,
+    pub active: bool,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct WebhookParams {
+    pub url: Option<String>,
+    pub event_types: Option<Vec<String>>,
+    // generated when not supplied
+    pub secret: Option<String>,
+    pub description: Option<String>,
+}
+
+// WebhookDelivery is one event sent, or to be sent, to a subscription.
+#[derive(Deserialize, PostgresMapper, Serialize, Debug, ToSchema)]
+#[pg_mapper(table = "webhook_deliveries")]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub subscription_id: i64,
+    pub event_id: String,
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    pub status: String,
+    pub attempts: i32,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WebhookDeliveryQuery {
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+// OutboxEvent is a ledger change recorded in the outbox for publication.
+#[derive(Deserialize, PostgresMapper, Serialize, Debug)]
+#[pg_mapper(table = "outbox")]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub event_type: String,
+    pub aggregate: String,
+    pub aggregate_id: i64,
+    pub payload: serde_json::Value,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct EventsQuery {
+    // comma separated account ids
+    pub accounts: Option<String>,
+    // comma separated event names: transaction, balance
+    pub types: Option<String>,
+    // resume after this event, for clients that cannot send Last-Event-ID
+    pub last_event_id: Option<i64>,
+}
+
+// ApiKey describes an issued API key. The key itself is only shown once on
+// creation, Postgres stores a SHA-256 hash of it.
+#[derive(Deserialize, PostgresMapper, Serialize, Debug, Clone)]
+#[pg_mapper(table = "api_keys")]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    pub key_prefix: String,
+    pub scopes: Vec<String>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+// AuditEntry is one link of the hash-chained audit log. hash covers every
+// other field except id together with prev_hash (see audit.rs).
+#[derive(Deserialize, PostgresMapper, Serialize, Debug, Clone, ToSchema)]
+#[pg_mapper(table = "audit_log")]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor: String,
+    pub request_id: Option<String>,
+    pub operation: String,
+    pub entity: String,
+    pub entity_id: Option<i64>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub source_ip: Option<String>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+// AuditQuery holds the GET /audit filters. Results are ordered by id and
+// paginated with after_id.
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    pub entity: Option<String>,
+    pub entity_id: Option<i64>,
+    pub actor: Option<String>,
+    pub operation: Option<String>,
+    pub request_id: Option<String>,
+    #[serde(deserialize_with = "deserialize_datetime", default)]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(deserialize_with = "deserialize_datetime", default)]
+    pub until: Option<DateTime<Utc>>,
+    pub after_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+// AccountFilter holds the filters of the account lists (GET /accounts, the
+// GraphQL accounts query and ListAccounts). Every field that is set must match.
+#[derive(Debug, Default, InputObject)]
+pub struct AccountFilter {
+    pub ids: Option<Vec<i64>>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub min_balance: Option<i64>,
+    pub max_balance: Option<i64>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    // tags the accounts must all carry
+    pub tags: Option<Vec<String>>,
+    // JSON object the account metadata must contain
+    pub metadata: Option<serde_json::Value>,
+}
+
+// TransactionFilter holds the filters of the transaction lists, like
+// AccountFilter. account_id matches transfers from or to the account.
+#[derive(Debug, Default, InputObject)]
+pub struct TransactionFilter {
+    pub ids: Option<Vec<i64>>,
+    pub account_id: Option<i64>,
+    pub from_account: Option<i64>,
+    pub to_account: Option<i64>,
+    pub min_amount: Option<i64>,
+    pub max_amount: Option<i64>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    // text the description contains, ignoring case
+    pub description: Option<String>,
+    pub reference: Option<String>,
+    pub category: Option<String>,
+    pub external_id: Option<String>,
+}
+
+// Discrepancy is an account whose stored balance differs from the balance
+// computed from its transaction history.
+#[derive(Deserialize, Serialize, Debug, Clone, ToSchema)]
+pub struct Discrepancy {
+    pub account_id: i64,
+    pub recorded: i64,
+    pub expected: i64,
+    // expected - recorded
+    pub difference: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, ToSchema)]
+pub struct ReconcileReport {
+    pub accounts_checked: i64,
+    pub discrepancies: Vec<Discrepancy>,
+    // number of balances overwritten with the expected value
+    pub repaired: i64,
+    pub duration_ms: f64,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReconcileParams {
+    pub repair: Option<bool>,
+}
+
+// AccountBalance is the balance of an account at a point in time, computed
+// from the transaction history.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct AccountBalance {
+    pub account_id: i64,
+    pub balance: i64,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct BalanceParams {
+    // defaults to now
+    #[serde(deserialize_with = "deserialize_datetime", default)]
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+// Statement lists the transfers of an account over a period [from, to)
+// together with the opening and closing balances.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct Statement {
+    pub account_id: i64,
+    pub username: String,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub from: Option<DateTime<Utc>>,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub to: Option<DateTime<Utc>>,
+    pub opening_balance: i64,
+    pub closing_balance: i64,
+    pub total_in: i64,
+    pub total_out: i64,
+    pub lines: Vec<StatementLine>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct StatementLine {
+    pub transaction_id: i64,
+    #[serde(
+        serialize_with = "serialize_datetime",
+        deserialize_with = "deserialize_datetime",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub created_at: Option<DateTime<Utc>>,
+    pub counterparty_id: Option<i64>,
+    pub counterparty: Option<String>,
+    // positive for funds received, negative for funds sent
+    pub amount: i64,
+    // running balance after the transfer
+    pub balance: i64,
+    pub description: Option<String>,
+    pub reference: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatementParams {
+    // defaults to the start of the current month
+    #[serde(deserialize_with = "deserialize_datetime", default)]
+    pub from: Option<DateTime<Utc>>,
+    // defaults to now
+    #[serde(deserialize_with = "deserialize_datetime", default)]
+    pub to: Option<DateTime<Utc>>,
+    // json (default), csv or text
+    pub format: Option<String>,
 }
 
 // status represents the default JSON
 // response format (also used to encode error messages)
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct Status {
     pub service: String,
     pub version: String,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+// Health is returned by the liveness and readiness probes, with a
+// breakdown of every dependency check that was run.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 pub struct Health {
     pub service: String,
     pub version: String,
-    pub failures: Vec<String>,
+    pub status: CheckStatus,
+    pub checks: Vec<HealthCheck>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct HealthCheck {
+    pub name: String,
+    pub status: CheckStatus,
+    pub duration_ms: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    // search text: words or parts of words of a username, email, description
+    // or reference
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchKind {
+    Account,
+    Transaction,
+}
+
+// SearchResult is an account or a transaction found by a search. highlights
+// holds each field containing a searched word, HTML escaped, with the words
+// wrapped in <mark> tags; similar words found by fuzzy matching are not
+// highlighted.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct SearchResult {
+    pub kind: SearchKind,
+    pub id: i64,
+    // 0 to 1, higher is a better match
+    pub score: f64,
+    pub highlights: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account: Option<Account>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transaction: Option<Transaction>,
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct SearchResults {
+    pub q: String,
+    pub limit: i64,
+    pub offset: i64,
+    // offset of the next page, None on the last page
+    pub next_offset: Option<i64>,
+    pub results: Vec<SearchResult>,
 }
 
 // Custom serialization function for DateTime<Utc>
@@ -0,0 +1,98 @@
+// OpenAPI 3 document of the HTTP API, generated from the #[utoipa::path]
+// annotations of the handlers and the model types. It is served at
+// /openapi.json with Swagger UI at /swagger-ui/, and checked in as
+// openapi.json (regenerate with `make openapi`).
+use crate::handlers;
+use utoipa::{
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "A simple transaction ledger implemented in Rust and PostgreSQL"),
+    paths(
+        handlers::status,
+        handlers::livez,
+        handlers::readyz,
+        handlers::get_metrics,
+        handlers::get_accounts,
+        handlers::get_balances,
+        handlers::get_account_balance,
+        handlers::get_statement,
+        handlers::get_account_by_id,
+        handlers::create_account,
+        handlers::create_accounts,
+        handlers::update_account,
+        handlers::get_transactions,
+        handlers::get_transaction_by_id,
+        handlers::create_transaction,
+        handlers::create_transactions,
+        handlers::get_scheduled_transfers,
+        handlers::create_scheduled_transfer,
+        handlers::get_scheduled_transfer,
+        handlers::pause_scheduled_transfer,
+        handlers::resume_scheduled_transfer,
+        handlers::cancel_scheduled_transfer,
+        handlers::get_events,
+        handlers::events_ws,
+        handlers::graphql_request,
+        handlers::search,
+        handlers::get_audit,
+        handlers::get_webhooks,
+        handlers::create_webhook,
+        handlers::delete_webhook,
+        handlers::get_webhook_deliveries,
+        handlers::reconcile_balances,
+    ),
+    modifiers(&Defaults)
+)]
+pub struct ApiDoc;
+
+// Defaults declares the two ways of authenticating referenced by the paths, an
+// API key in X-Api-Key or a JWT bearer token, and drops the empty license
+// taken from Cargo.toml.
+struct Defaults;
+
+impl Modify for Defaults {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi.info.license = None;
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+    }
+}
+
+// spec renders the document as pretty printed JSON, as checked in.
+pub fn spec() -> String {
+    let mut spec = ApiDoc::openapi()
+        .to_pretty_json()
+        .expect("OpenAPI document serializes");
+    spec.push('\n');
+    spec
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spec;
+
+    #[test]
+    fn checked_in_spec_is_up_to_date() {
+        let checked_in = include_str!("../openapi.json");
+        assert!(
+            checked_in == spec(),
+            "openapi.json is out of date with the handlers, regenerate it with `make openapi`"
+        );
+    }
+}
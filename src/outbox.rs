@@ -0,0 +1,339 @@
+// Transactional outbox. Every ledger mutation in db.rs writes an event row in
+// the same statement as the change, so an event exists exactly when the change
+// committed. The relay reads the undelivered events in id order, publishes each
+// one to every sink and marks it delivered. Ids are taken before commit, so a
+// lower id can commit after a higher one: events are held back while a
+// transaction that was in flight when they were written is still running, and
+// a lower id committing later is published on a following poll rather than
+// skipped. Publication is at least once: an event is published again if the
+// relay stops before marking it, so consumers should deduplicate by event id.
+use crate::{
+    config::{redact_url, OutboxConfig, OutboxSinkConfig},
+    db,
+    errors::MyError,
+    model::OutboxEvent,
+    shutdown::stopped,
+};
+use async_trait::async_trait;
+use awc::Client;
+use deadpool_postgres::Pool;
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::time::Duration;
+use tokio::sync::watch;
+
+// Sink is a destination for outbox events. publish must only return Ok once
+// the event is durably accepted; an error stops the relay at that event and it
+// is retried on the next poll, so later events are never published before it.
+#[async_trait(?Send)]
+pub trait Sink {
+    // name identifies the sink in logs
+    fn name(&self) -> String;
+
+    async fn publish(&self, event: &OutboxEvent) -> std::io::Result<()>;
+}
+
+// StdoutSink prints one JSON line per event.
+pub struct StdoutSink;
+
+#[async_trait(?Send)]
+impl Sink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".to_string()
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        serde_json::to_writer(&mut stdout, event)?;
+        writeln!(stdout)?;
+        stdout.flush()
+    }
+}
+
+// FileSink appends one JSON line per event to a file.
+pub struct FileSink {
+    path: String,
+    file: File,
+}
+
+impl FileSink {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FileSink {
+            path: path.to_string(),
+            file,
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl Sink for FileSink {
+    fn name(&self) -> String {
+        format!("file {}", self.path)
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        (&self.file).write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+// HttpSink POSTs each event as JSON. Any 2xx answer acknowledges the event.
+pub struct HttpSink {
+    url: String,
+    headers: Vec<(String, String)>,
+    client: Client,
+}
+
+impl HttpSink {
+    pub fn new(url: &str, headers: Vec<(String, String)>, timeout: Duration) -> Self {
+        HttpSink {
+            url: url.to_string(),
+            headers,
+            client: Client::builder().timeout(timeout).finish(),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Sink for HttpSink {
+    fn name(&self) -> String {
+        format!("http {}", redact_url(&self.url))
+    }
+
+    async fn publish(&self, event: &OutboxEvent) -> std::io::Result<()> {
+        let mut request = self.client.post(&self.url);
+        for (name, value) in &self.headers {
+            request = request.insert_header((name.as_str(), value.as_str()));
+        }
+        let response = request
+            .send_json(event)
+            .await
+            .map_err(|err| Error::other(err.to_string()))?;
+        if !response.status().is_success() {
+            return Err(Error::other(format!(
+                "receiver responded {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// sinks builds the sinks configured in outbox.sinks.
+pub fn sinks(config: &OutboxConfig) -> std::io::Result<Vec<Box<dyn Sink>>> {
+    config
+        .sinks
+        .iter()
+        .map(|sink| -> std::io::Result<Box<dyn Sink>> {
+            Ok(match sink {
+                OutboxSinkConfig::Stdout => Box::new(StdoutSink),
+                OutboxSinkConfig::File { path } => {
+                    Box::new(FileSink::open(path).map_err(|err| {
+                        Error::new(err.kind(), format!("outbox sink {}: {}", path, err))
+                    })?)
+                }
+                OutboxSinkConfig::Http {
+                    url,
+                    headers,
+                    timeout_secs,
+                } => {
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "outbox sink url {} must be an http or https URL",
+                                redact_url(url)
+                            ),
+                        ));
+                    }
+                    Box::new(HttpSink::new(
+                        url,
+                        headers.clone().into_iter().collect(),
+                        Duration::from_secs(*timeout_secs),
+                    ))
+                }
+            })
+        })
+        .collect()
+}
+
+// relay publishes undelivered events every interval until shutdown is
+// triggered. Custom sinks can be passed alongside the configured ones. The
+// interval must not be 0, which disables the relay in the server.
+pub async fn relay(
+    pool: Pool,
+    config: OutboxConfig,
+    sinks: Vec<Box<dyn Sink>>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    let mut accepted = vec![HashSet::new(); sinks.len()];
+
+    loop {
+        tokio::select! {
+            _ = stopped(&mut shutdown) => return,
+            _ = ticker.tick() => {}
+        }
+
+        // keep going while full batches are published to drain a backlog
+        loop {
+            match publish_pending(&pool, &sinks, &mut accepted, config.batch_size).await {
+                Ok(0) => break,
+                Ok(count) => {
+                    log::debug!("Published {} outbox events", count);
+                    if (count as i64) < config.batch_size {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    log::error!("Outbox relay failed: {}", err);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+// publish_pending publishes up to limit undelivered events in id order and
+// returns how many were delivered. It stops at the first event a sink rejects.
+// accepted holds the ids each sink accepted that are not marked delivered yet,
+// so that a retry only goes to the sinks that have not accepted the event.
+pub async fn publish_pending(
+    pool: &Pool,
+    sinks: &[Box<dyn Sink>],
+    accepted: &mut [HashSet<i64>],
+    limit: i64,
+) -> Result<usize, MyError> {
+    let mut client = pool.get().await?;
+    let db_tx = client.transaction().await?;
+    if !db::lock_outbox(&db_tx).await? {
+        // another relay is publishing
+        return Ok(0);
+    }
+
+    let events = db::get_pending_outbox_events(&db_tx, limit).await?;
+    let delivered = publish_events(sinks, accepted, &events).await;
+
+    db::mark_outbox_delivered(&db_tx, &delivered).await?;
+    db_tx.commit().await?;
+    for ids in accepted.iter_mut() {
+        for id in &delivered {
+            ids.remove(id);
+        }
+    }
+    Ok(delivered.len())
+}
+
+// publish_events publishes events in order to every sink that has not
+// accepted them yet and returns the ids all sinks accepted. It stops at the
+// first event a sink rejects.
+async fn publish_events(
+    sinks: &[Box<dyn Sink>],
+    accepted: &mut [HashSet<i64>],
+    events: &[OutboxEvent],
+) -> Vec<i64> {
+    let mut delivered = Vec::new();
+    'events: for event in events {
+        for (sink, ids) in sinks.iter().zip(accepted.iter_mut()) {
+            if ids.contains(&event.id) {
+                continue;
+            }
+            if let Err(err) = sink.publish(event).await {
+                log::warn!(
+                    "Outbox sink {} failed to publish event {}: {}",
+                    sink.name(),
+                    event.id,
+                    err
+                );
+                break 'events;
+            }
+            ids.insert(event.id);
+        }
+        delivered.push(event.id);
+    }
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{publish_events, Sink};
+    use crate::model::OutboxEvent;
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    // RecordingSink records the ids it accepts and rejects the ids in failing.
+    #[derive(Default)]
+    struct RecordingSink {
+        published: RefCell<Vec<i64>>,
+        failing: RefCell<HashSet<i64>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Sink for Rc<RecordingSink> {
+        fn name(&self) -> String {
+            "recording".to_string()
+        }
+
+        async fn publish(&self, event: &OutboxEvent) -> std::io::Result<()> {
+            if self.failing.borrow().contains(&event.id) {
+                return Err(std::io::Error::other("unavailable"));
+            }
+            self.published.borrow_mut().push(event.id);
+            Ok(())
+        }
+    }
+
+    fn event(id: i64) -> OutboxEvent {
+        OutboxEvent {
+            id,
+            event_type: "transfer.created".to_string(),
+            aggregate: "transaction".to_string(),
+            aggregate_id: id,
+            payload: json!({}),
+            created_at: None,
+            delivered_at: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn publishes_lower_ids_committed_later() {
+        let (a, b) = (
+            Rc::new(RecordingSink::default()),
+            Rc::new(RecordingSink::default()),
+        );
+        let sinks: Vec<Box<dyn Sink>> = vec![Box::new(a.clone()), Box::new(b.clone())];
+        let mut accepted = vec![HashSet::new(); 2];
+
+        // b rejects event 10 after a accepted it
+        b.failing.borrow_mut().insert(10);
+        let delivered = publish_events(&sinks, &mut accepted, &[event(10)]).await;
+        assert!(delivered.is_empty());
+
+        // event 9 committed in the meantime and is read before 10
+        b.failing.borrow_mut().clear();
+        let delivered = publish_events(&sinks, &mut accepted, &[event(9), event(10)]).await;
+        assert_eq!(delivered, vec![9, 10]);
+        assert_eq!(*a.published.borrow(), vec![10, 9]);
+        assert_eq!(*b.published.borrow(), vec![9, 10]);
+    }
+
+    #[actix_web::test]
+    async fn stops_at_the_first_rejected_event() {
+        let a = Rc::new(RecordingSink::default());
+        a.failing.borrow_mut().insert(2);
+        let sinks: Vec<Box<dyn Sink>> = vec![Box::new(a.clone())];
+        let mut accepted = vec![HashSet::new()];
+
+        let events = [event(1), event(2), event(3)];
+        let delivered = publish_events(&sinks, &mut accepted, &events).await;
+        assert_eq!(delivered, vec![1]);
+        assert_eq!(*a.published.borrow(), vec![1]);
+    }
+}
@@ -0,0 +1,383 @@
+// Request rate limiting. Each client gets a token bucket per route, keyed by
+// the authenticated principal once its credentials are verified, or else by
+// the peer IP address, so that made-up credentials do not get fresh buckets.
+// Every request is charged to its IP bucket before its credentials are
+// checked, so that floods of bogus credentials never reach the database.
+// Requests arriving on an empty bucket are rejected with 429 and a
+// Retry-After header. Buckets live in memory and are per instance.
+use crate::{
+    auth::{authenticate_request, Principal},
+    config::{AuthConfig, BucketConfig, RateLimitConfig, VelocityConfig},
+    middleware::request_id,
+    model::Status,
+};
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, StatusCode},
+    middleware::Next,
+    web, Error, HttpRequest, HttpResponse,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// probes are never limited so that orchestrators can always reach them
+const EXEMPT_ROUTES: &[&str] = &["/status", "/health", "/livez", "/readyz"];
+
+// /metrics is only exempt while it is public; otherwise it checks credentials
+// like any other route and is charged first
+const METRICS_ROUTE: &str = "/metrics";
+
+// most buckets tracked at once. Reaching it evicts the buckets that refilled,
+// then the least recently used ones, down to the low water mark so that the
+// eviction cost is spread over the buckets created in between.
+const MAX_BUCKETS: usize = 10_000;
+const EVICT_TO: usize = MAX_BUCKETS * 9 / 10;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    buckets: Mutex<HashMap<(String, String), Bucket>>,
+    max_buckets: usize,
+    evict_to: usize,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            max_buckets: MAX_BUCKETS,
+            evict_to: EVICT_TO,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    pub fn velocity(&self) -> Option<&VelocityConfig> {
+        self.config.velocity.as_ref()
+    }
+
+    // check takes a token from the client's bucket for the route, returning
+    // how long to wait for the next token when the bucket is empty.
+    pub fn check(&self, route: &str, client: &str) -> Result<(), Duration> {
+        self.check_at(route, client, Instant::now())
+    }
+
+    fn check_at(&self, route: &str, client: &str, now: Instant) -> Result<(), Duration> {
+        let limit = self.limit(route);
+        let key = (route.to_string(), client.to_string());
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() >= self.max_buckets && !buckets.contains_key(&key) {
+            self.evict(&mut buckets, now);
+        }
+
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+        });
+        if refill(bucket, limit, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = if limit.per_second > 0.0 {
+            (1.0 - bucket.tokens) / limit.per_second
+        } else {
+            // a bucket that never refills is exhausted for good
+            u32::MAX as f64
+        };
+        Err(Duration::from_secs_f64(wait))
+    }
+
+    // evict drops the buckets that refilled, which a new bucket would match,
+    // then the least recently used ones until evict_to are left.
+    fn evict(&self, buckets: &mut HashMap<(String, String), Bucket>, now: Instant) {
+        let config = &self.config;
+        // fullness is worked out without refilling so that updated still
+        // orders the remaining buckets by last use
+        buckets.retain(|(route, _), bucket| {
+            let limit = route_limit(config, route);
+            let earned = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+            bucket.tokens + earned < limit.burst as f64
+        });
+        if buckets.len() <= self.evict_to {
+            return;
+        }
+        let mut idle: Vec<(Instant, (String, String))> = buckets
+            .iter()
+            .map(|(key, bucket)| (bucket.updated, key.clone()))
+            .collect();
+        idle.sort_unstable_by_key(|(updated, _)| *updated);
+        let excess = buckets.len() - self.evict_to;
+        for (_, key) in idle.into_iter().take(excess) {
+            buckets.remove(&key);
+        }
+    }
+
+    // refund gives back the token check took from the client's bucket.
+    pub fn refund(&self, route: &str, client: &str) {
+        let limit = self.limit(route);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bucket) = buckets.get_mut(&(route.to_string(), client.to_string())) {
+            bucket.tokens = (bucket.tokens + 1.0).min(limit.burst as f64);
+        }
+    }
+
+    // charge takes a token from the bucket of a route for a call that reached
+    // the ledger without going through the route, such as a gRPC call or a
+    // GraphQL mutation: the principal's bucket when one was verified, else the
+    // peer IP's.
+    pub fn charge(
+        &self,
+        route: &str,
+        principal: Option<&Principal>,
+        ip: Option<&str>,
+    ) -> Result<(), Duration> {
+        if !self.config.enabled {
+            return Ok(());
+        }
+        match principal {
+            Some(principal) => self.check(route, &principal_client(principal)),
+            None => self.check(route, &ip_client(ip)),
+        }
+    }
+
+    fn limit(&self, route: &str) -> &BucketConfig {
+        route_limit(&self.config, route)
+    }
+}
+
+// route_limit returns the bucket configured for a route pattern such as "/create-tx".
+fn route_limit<'a>(config: &'a RateLimitConfig, route: &str) -> &'a BucketConfig {
+    config
+        .routes
+        .get(route.trim_start_matches('/'))
+        .unwrap_or(&config.default)
+}
+
+// ip_client names the bucket of a peer IP address.
+pub fn ip_client(ip: Option<&str>) -> String {
+    format!("ip:{}", ip.unwrap_or("unknown"))
+}
+
+// principal_client names the bucket of a verified principal.
+pub fn principal_client(principal: &Principal) -> String {
+    format!("principal:{}", principal.name)
+}
+
+// refill adds the tokens earned since the last update and returns the new level.
+fn refill(bucket: &mut Bucket, limit: &BucketConfig, now: Instant) -> f64 {
+    let earned = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second;
+    bucket.tokens = (bucket.tokens + earned).min(limit.burst as f64);
+    bucket.updated = now;
+    bucket.tokens
+}
+
+pub async fn rate_limit<B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    let limiter = match req.app_data::<web::Data<RateLimiter>>() {
+        Some(limiter) if limiter.config.enabled => limiter.clone(),
+        _ => return Ok(next.call(req).await?.map_into_left_body()),
+    };
+
+    let public_metrics = req
+        .app_data::<web::Data<AuthConfig>>()
+        .is_none_or(|config| config.public_metrics);
+    let route = match req.match_pattern() {
+        Some(route) if route == METRICS_ROUTE && public_metrics => {
+            return Ok(next.call(req).await?.map_into_left_body())
+        }
+        Some(route) if !EXEMPT_ROUTES.contains(&route.as_str()) => route,
+        // unknown routes are answered with 404 without reaching a handler
+        _ => return Ok(next.call(req).await?.map_into_left_body()),
+    };
+
+    let ip = ip_client(req.peer_addr().map(|addr| addr.ip().to_string()).as_deref());
+    let mut checked = limiter.check(&route, &ip);
+    if checked.is_ok() {
+        // verified callers are limited on their own bucket instead of their IP's
+        if let Some(principal) = verified_principal(&req).await {
+            limiter.refund(&route, &ip);
+            checked = limiter.check(&route, &principal_client(&principal));
+        }
+    }
+    if let Err(retry_after) = checked {
+        let response = too_many_requests(req.request(), "Rate limit exceeded", retry_after);
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+// verified_principal returns the caller when authentication is enabled and
+// the request credentials verify.
+async fn verified_principal(req: &ServiceRequest) -> Option<Principal> {
+    let auth_enabled = req
+        .app_data::<web::Data<AuthConfig>>()
+        .is_some_and(|config| config.enabled);
+    if !auth_enabled {
+        return None;
+    }
+    authenticate_request(req).await.ok()
+}
+
+// too_many_requests builds a 429 response in the standard Status format.
+pub fn too_many_requests(req: &HttpRequest, message: &str, retry_after: Duration) -> HttpResponse {
+    let response: Status = Status {
+        service: env!("SERVICE_NAME").to_string(),
+        message: message.to_string(),
+        version: env!("VERSION").to_string(),
+        request_id: request_id(req),
+    };
+    // Retry-After is in whole seconds, round up so that clients do not retry early
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+        .insert_header((header::RETRY_AFTER, secs.to_string()))
+        .json(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use crate::config::{BucketConfig, RateLimitConfig};
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    // a bucket of 2 refilled at 1 per second on every route
+    fn limiter(max_buckets: usize, evict_to: usize) -> RateLimiter {
+        let mut limiter = RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            default: BucketConfig {
+                burst: 2,
+                per_second: 1.0,
+            },
+            routes: HashMap::from([(
+                "frozen".to_string(),
+                BucketConfig {
+                    burst: 1,
+                    per_second: 0.0,
+                },
+            )]),
+            velocity: None,
+        });
+        limiter.max_buckets = max_buckets;
+        limiter.evict_to = evict_to;
+        limiter
+    }
+
+    fn tracked(limiter: &RateLimiter) -> Vec<String> {
+        let buckets = limiter.buckets.lock().unwrap();
+        let mut clients: Vec<String> = buckets.keys().map(|(_, c)| c.clone()).collect();
+        clients.sort();
+        clients
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let limiter = limiter(100, 90);
+        let start = Instant::now();
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        assert_eq!(
+            limiter.check_at("/accounts", "ip:a", start),
+            Err(Duration::from_secs(1))
+        );
+        // half a token is earned after half a second
+        let half = start + Duration::from_millis(500);
+        assert_eq!(
+            limiter.check_at("/accounts", "ip:a", half),
+            Err(Duration::from_millis(500))
+        );
+        assert!(limiter
+            .check_at("/accounts", "ip:a", start + Duration::from_secs(1))
+            .is_ok());
+        // other clients and routes have their own buckets
+        assert!(limiter.check_at("/accounts", "ip:b", start).is_ok());
+        assert!(limiter.check_at("/transactions", "ip:a", start).is_ok());
+    }
+
+    #[test]
+    fn bucket_without_refill_stays_empty() {
+        let limiter = limiter(100, 90);
+        let start = Instant::now();
+        assert!(limiter.check_at("/frozen", "ip:a", start).is_ok());
+        let later = start + Duration::from_secs(3600);
+        assert!(
+            limiter.check_at("/frozen", "ip:a", later).unwrap_err() > Duration::from_secs(3600)
+        );
+    }
+
+    #[test]
+    fn refund_returns_a_token_up_to_the_burst() {
+        let limiter = limiter(100, 90);
+        let start = Instant::now();
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        limiter.refund("/accounts", "ip:a");
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_err());
+
+        // refunds never raise a bucket above its burst
+        for _ in 0..5 {
+            limiter.refund("/accounts", "ip:a");
+        }
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_ok());
+        assert!(limiter.check_at("/accounts", "ip:a", start).is_err());
+        // refunding an unknown bucket does not create it
+        limiter.refund("/accounts", "ip:unknown");
+        assert_eq!(tracked(&limiter), vec!["ip:a"]);
+    }
+
+    #[test]
+    fn eviction_drops_refilled_buckets_first() {
+        let limiter = limiter(3, 2);
+        let start = Instant::now();
+        // a is used up, b and c only used once
+        for _ in 0..2 {
+            limiter.check_at("/accounts", "ip:a", start).unwrap();
+        }
+        limiter.check_at("/accounts", "ip:b", start).unwrap();
+        limiter.check_at("/accounts", "ip:c", start).unwrap();
+
+        // a second later b and c are full again, a is not
+        let later = start + Duration::from_secs(1);
+        limiter.check_at("/accounts", "ip:d", later).unwrap();
+        assert_eq!(tracked(&limiter), vec!["ip:a", "ip:d"]);
+        // a kept its state
+        limiter.check_at("/accounts", "ip:a", later).unwrap();
+        assert!(limiter.check_at("/accounts", "ip:a", later).is_err());
+    }
+
+    #[test]
+    fn eviction_caps_busy_buckets_by_last_use() {
+        let limiter = limiter(4, 2);
+        let start = Instant::now();
+        // none of the buckets refills before the cap is reached
+        for (offset, client) in ["ip:a", "ip:b", "ip:c", "ip:d"].iter().enumerate() {
+            let at = start + Duration::from_millis(offset as u64);
+            limiter.check_at("/accounts", client, at).unwrap();
+            limiter.check_at("/accounts", client, at).unwrap();
+        }
+        limiter
+            .check_at("/accounts", "ip:e", start + Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(tracked(&limiter), vec!["ip:c", "ip:d", "ip:e"]);
+        // known buckets are served without evicting
+        limiter
+            .check_at("/accounts", "ip:c", start + Duration::from_millis(11))
+            .unwrap_err();
+        assert_eq!(tracked(&limiter).len(), 3);
+    }
+}
@@ -0,0 +1,109 @@
+// Balance reconciliation. Account balances are stored separately from the
+// transactions that move funds between them, so the two can drift apart.
+// Reconciliation recomputes every balance from the transaction history and
+// reports the accounts that differ, optionally overwriting their balance with
+// the computed value. It runs from the CLI, the admin endpoint, or on a
+// schedule inside the server.
+use crate::{
+    audit::{self, AuditContext},
+    config::ReconcileConfig,
+    db,
+    errors::MyError,
+    metrics::Metrics,
+    model::ReconcileReport,
+    shutdown::stopped,
+};
+use deadpool_postgres::{Client, Pool};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio_postgres::IsolationLevel;
+
+// run checks every account balance, repairing drifted balances when asked to.
+// Repairs are audited and block new transfers while they are applied.
+pub async fn run(
+    client: &mut Client,
+    repair: bool,
+    context: &AuditContext,
+) -> Result<ReconcileReport, MyError> {
+    let start = Instant::now();
+    // balances and transactions are read from the same snapshot
+    let db_tx = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .start()
+        .await?;
+    if repair {
+        db::lock_transactions(&db_tx).await?;
+    }
+
+    let mut report = ReconcileReport {
+        accounts_checked: db::count_accounts(&db_tx).await?,
+        discrepancies: db::balance_discrepancies(&db_tx).await?,
+        ..Default::default()
+    };
+
+    if repair {
+        for discrepancy in &report.discrepancies {
+            let before = db::get_account_by_id(&db_tx, discrepancy.account_id).await?;
+            let after =
+                db::set_account_balance(&db_tx, discrepancy.account_id, discrepancy.expected)
+                    .await?;
+            audit::record(
+                &db_tx,
+                context,
+                "reconcile_balance",
+                "account",
+                after.id,
+                Some(&before),
+                Some(&after),
+            )
+            .await?;
+            report.repaired += 1;
+        }
+    }
+    db_tx.commit().await?;
+
+    report.duration_ms = start.elapsed().as_secs_f64() * 1000.0;
+    Ok(report)
+}
+
+// schedule runs reconciliation every interval until shutdown is triggered.
+pub async fn schedule(
+    pool: Pool,
+    config: ReconcileConfig,
+    metrics: Arc<Metrics>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let context = AuditContext::system("reconcile");
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    // the first tick completes immediately, start checking after one interval
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = stopped(&mut shutdown) => return,
+            _ = ticker.tick() => {}
+        }
+
+        let result = match pool.get().await {
+            Ok(mut client) => run(&mut client, config.repair, &context).await,
+            Err(err) => Err(MyError::from(err)),
+        };
+        match &result {
+            Ok(report) if report.discrepancies.is_empty() => log::info!(
+                "Reconciliation found no discrepancies ({} accounts, {:.1}ms)",
+                report.accounts_checked,
+                report.duration_ms
+            ),
+            Ok(report) => log::warn!(
+                "Reconciliation found {} discrepancies in {} accounts, {} repaired",
+                report.discrepancies.len(),
+                report.accounts_checked,
+                report.repaired
+            ),
+            Err(err) => log::error!("Reconciliation failed: {}", err),
+        }
+        metrics.record_reconcile(&result);
+    }
+}
@@ -0,0 +1,340 @@
+// Scheduled and recurring transfers. The worker polls for due transfers and
+// executes each one in its own database transaction. Runs go through the same
+// checks as other transfers, including the velocity limits. The scheduled transfer
+// row is locked while it runs, and the transfer, its run record and the next
+// occurrence commit together, so every occurrence is executed exactly once
+// even with several server instances. Failed runs are retried with backoff.
+use crate::{
+    audit::{self, AuditContext},
+    config::{SchedulerConfig, VelocityConfig},
+    db,
+    errors::MyError,
+    ledger::{self, LedgerError},
+    model::{ScheduledTransfer, Transaction},
+    shutdown::stopped,
+    webhooks,
+};
+use chrono::{DateTime, Datelike, Months, TimeDelta, Utc};
+use croner::Cron;
+use deadpool_postgres::{Pool, Transaction as DbTransaction};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::watch;
+
+pub const ACTIVE: &str = "active";
+pub const PAUSED: &str = "paused";
+pub const COMPLETED: &str = "completed";
+pub const FAILED: &str = "failed";
+pub const CANCELLED: &str = "cancelled";
+
+// how far in the past a one-off transfer's run_at may be, to allow for clock
+// skew between the client and the server
+pub const RUN_AT_SKEW_SECS: i64 = 60;
+
+// Recurrence is how often a scheduled transfer repeats. Interval recurrences
+// are counted from the first occurrence, so monthly transfers starting on the
+// 31st run on the last day of shorter months and return to the 31st after.
+#[derive(Debug, Clone)]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+    Monthly,
+    Cron(Box<Cron>),
+}
+
+impl FromStr for Recurrence {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Recurrence::Daily),
+            "weekly" => Ok(Recurrence::Weekly),
+            "monthly" => Ok(Recurrence::Monthly),
+            _ => Cron::new(s)
+                .parse()
+                .map(|cron| Recurrence::Cron(Box::new(cron)))
+                .map_err(|err| {
+                    format!(
+                        "invalid recurrence {} (expected daily, weekly, monthly or a cron expression): {}",
+                        s, err
+                    )
+                }),
+        }
+    }
+}
+
+impl Recurrence {
+    // first returns the first occurrence at or after from.
+    pub fn first(&self, from: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Recurrence::Cron(cron) => cron.find_next_occurrence(&from, true).ok(),
+            _ => Some(from),
+        }
+    }
+
+    // next returns the first occurrence after `after` of a schedule whose first
+    // occurrence was start.
+    pub fn next(&self, start: DateTime<Utc>, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if after < start {
+            return self.first(start);
+        }
+        let period = match self {
+            Recurrence::Daily => TimeDelta::days(1),
+            Recurrence::Weekly => TimeDelta::weeks(1),
+            Recurrence::Monthly => {
+                let months = (after.year() - start.year()) * 12 + after.month() as i32
+                    - start.month() as i32;
+                return (months.max(0)..months.max(0) + 2)
+                    .filter_map(|k| start.checked_add_months(Months::new(k as u32)))
+                    .find(|candidate| *candidate > after);
+            }
+            Recurrence::Cron(cron) => return cron.find_next_occurrence(&after, false).ok(),
+        };
+        let elapsed = (after - start).num_seconds() / period.num_seconds();
+        Some(start + period * (elapsed as i32 + 1))
+    }
+}
+
+// schedule executes due transfers every interval until shutdown is triggered.
+pub async fn schedule(
+    pool: Pool,
+    config: SchedulerConfig,
+    velocity: Option<VelocityConfig>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+
+    loop {
+        tokio::select! {
+            _ = stopped(&mut shutdown) => return,
+            _ = ticker.tick() => {}
+        }
+
+        match run_due(&pool, &config, velocity.as_ref()).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Executed {} scheduled transfer runs", count),
+            Err(err) => log::error!("Scheduled transfers failed: {}", err),
+        }
+    }
+}
+
+// run_due processes up to batch_size due scheduled transfers and returns how
+// many were processed.
+pub async fn run_due(
+    pool: &Pool,
+    config: &SchedulerConfig,
+    velocity: Option<&VelocityConfig>,
+) -> Result<u32, MyError> {
+    let mut client = pool.get().await?;
+    let mut processed = 0;
+
+    while processed < config.batch_size {
+        let mut db_tx = client.transaction().await?;
+        let Some(schedule) = db::claim_due_scheduled_transfer(&db_tx).await? else {
+            break;
+        };
+        let now = Utc::now();
+        let previous_status = schedule.status.clone();
+        let updated = match execute(&mut db_tx, &schedule, velocity).await {
+            Ok(tx) => {
+                log::info!(
+                    "Scheduled transfer {} executed as transaction {}",
+                    schedule.id,
+                    tx.id.unwrap_or_default()
+                );
+                succeeded(schedule, now)
+            }
+            Err(err) => {
+                log::warn!("Scheduled transfer {} failed: {}", schedule.id, err);
+                failed(schedule, err.to_string(), now, config)
+            }
+        };
+        let updated = db::update_scheduled_transfer(&db_tx, &updated).await?;
+        if updated.status != previous_status {
+            webhooks::enqueue(
+                &db_tx,
+                webhooks::SCHEDULED_TRANSFER_STATUS_CHANGED,
+                &updated,
+            )
+            .await?;
+        }
+        db_tx.commit().await?;
+        processed += 1;
+    }
+    Ok(processed)
+}
+
+// execute makes the transfer of the current occurrence in a savepoint, so that
+// a failure leaves the schedule row locked for recording the failure. The
+// transfer and velocity checks apply as if its creator made it now. Ownership
+// of the debited account is only checked when the schedule is created.
+async fn execute(
+    db_tx: &mut DbTransaction<'_>,
+    schedule: &ScheduledTransfer,
+    velocity: Option<&VelocityConfig>,
+) -> Result<Transaction, LedgerError> {
+    let savepoint = db_tx.transaction().await?;
+    let result = async {
+        ledger::check_transfer(schedule.from_account, schedule.to_account, schedule.amount)?;
+        ledger::lock_transfer_accounts(&savepoint, schedule.from_account, schedule.to_account)
+            .await?;
+        if let Some(velocity) = velocity {
+            ledger::check_velocity(&savepoint, velocity, schedule.from_account, schedule.amount)
+                .await?;
+        }
+        let tx = db::create_transaction(
+            &savepoint,
+            Transaction {
+                id: None,
+                from_account: Some(schedule.from_account),
+                to_account: Some(schedule.to_account),
+                amount: Some(schedule.amount),
+                created_at: Some(Utc::now()),
+                description: None,
+                reference: None,
+                category: None,
+                external_id: None,
+                created_by: schedule.created_by.clone(),
+            },
+        )
+        .await?;
+        if let (Some(scheduled_for), Some(tx_id)) = (schedule.next_run_at, tx.id) {
+            db::record_scheduled_run(&savepoint, schedule.id, scheduled_for, tx_id).await?;
+        }
+        audit::record(
+            &savepoint,
+            &AuditContext::system("scheduler"),
+            "create_transaction",
+            "transaction",
+            tx.id,
+            None,
+            Some(&tx),
+        )
+        .await?;
+        webhooks::enqueue(&savepoint, webhooks::TRANSFER_CREATED, &tx).await?;
+        Ok::<_, LedgerError>(tx)
+    }
+    .await;
+
+    match result {
+        Ok(tx) => {
+            savepoint.commit().await?;
+            Ok(tx)
+        }
+        Err(err) => {
+            savepoint.rollback().await?;
+            Err(err)
+        }
+    }
+}
+
+// succeeded moves a schedule past a successful run. Occurrences missed while
+// the worker was not running are skipped rather than executed in a burst.
+fn succeeded(mut schedule: ScheduledTransfer, now: DateTime<Utc>) -> ScheduledTransfer {
+    let occurrence = schedule.next_run_at.unwrap_or(now);
+    let next = match (&schedule.recurrence, schedule.starts_at) {
+        (Some(recurrence), Some(start)) => recurrence
+            .parse::<Recurrence>()
+            .ok()
+            .and_then(|r| r.next(start, occurrence.max(now))),
+        _ => None,
+    };
+    if next.is_none() {
+        schedule.status = COMPLETED.to_string();
+    }
+    schedule.next_run_at = next;
+    schedule.retry_at = None;
+    schedule.attempts = 0;
+    schedule.last_error = None;
+    schedule.last_run_at = Some(now);
+    schedule.runs += 1;
+    schedule
+}
+
+// failed records a failed run and schedules a retry, or marks the schedule
+// failed once the attempts are exhausted.
+fn failed(
+    mut schedule: ScheduledTransfer,
+    error: String,
+    now: DateTime<Utc>,
+    config: &SchedulerConfig,
+) -> ScheduledTransfer {
+    schedule.attempts += 1;
+    schedule.last_error = Some(error);
+    schedule.last_run_at = Some(now);
+    if schedule.attempts >= config.max_attempts {
+        schedule.status = FAILED.to_string();
+        schedule.retry_at = None;
+    } else {
+        let backoff = config.retry_delay_secs << (schedule.attempts - 1).min(16);
+        schedule.retry_at = Some(now + TimeDelta::seconds(backoff as i64));
+    }
+    schedule
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Recurrence;
+    use chrono::{DateTime, Utc};
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn daily_and_weekly_count_from_the_start() {
+        let start = at("2026-01-01T09:00:00Z");
+        let daily: Recurrence = "daily".parse().unwrap();
+        assert_eq!(daily.next(start, start), Some(at("2026-01-02T09:00:00Z")));
+        assert_eq!(
+            daily.next(start, at("2026-01-05T12:00:00Z")),
+            Some(at("2026-01-06T09:00:00Z"))
+        );
+
+        let weekly: Recurrence = "weekly".parse().unwrap();
+        assert_eq!(
+            weekly.next(start, at("2026-01-08T09:00:00Z")),
+            Some(at("2026-01-15T09:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn next_before_the_start_is_the_first_occurrence() {
+        let start = at("2026-03-01T09:00:00Z");
+        let monthly: Recurrence = "monthly".parse().unwrap();
+        assert_eq!(monthly.next(start, at("2026-02-01T00:00:00Z")), Some(start));
+    }
+
+    #[test]
+    fn monthly_falls_back_to_month_end_and_returns_to_the_start_day() {
+        let start = at("2026-01-31T09:00:00Z");
+        let monthly: Recurrence = "monthly".parse().unwrap();
+        let feb = monthly.next(start, start).unwrap();
+        assert_eq!(feb, at("2026-02-28T09:00:00Z"));
+        let mar = monthly.next(start, feb).unwrap();
+        assert_eq!(mar, at("2026-03-31T09:00:00Z"));
+        assert_eq!(monthly.next(start, mar), Some(at("2026-04-30T09:00:00Z")));
+        // leap years keep the 29th
+        assert_eq!(
+            monthly.next(start, at("2028-01-31T09:00:00Z")),
+            Some(at("2028-02-29T09:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_finds_the_next_matching_time() {
+        let mondays: Recurrence = "0 9 * * MON".parse().unwrap();
+        let start = at("2026-10-19T09:00:00Z");
+        assert_eq!(mondays.first(at("2026-10-18T12:00:00Z")), Some(start));
+        assert_eq!(mondays.first(start), Some(start));
+        assert_eq!(mondays.next(start, start), Some(at("2026-10-26T09:00:00Z")));
+    }
+
+    #[test]
+    fn invalid_recurrences_are_rejected() {
+        for recurrence in ["yearly", "", "61 * * * *", "0 9 * * FUNDAY"] {
+            let err = recurrence.parse::<Recurrence>().unwrap_err();
+            assert!(err.starts_with("invalid recurrence"), "{}", err);
+        }
+    }
+}
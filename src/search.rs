@@ -0,0 +1,392 @@
+// Search across accounts and transactions for support staff. Accounts are
+// found by username or email, transactions by description or reference. Each
+// word of the search text matches as a word prefix (Postgres full-text
+// search), a substring or a similar word (trigram indexes), so partial and
+// misspelt names are found. Results of both kinds are ranked together.
+use crate::{
+    db,
+    errors::MyError,
+    model::{Account, SearchKind, SearchResult, SearchResults, Transaction},
+};
+use deadpool_postgres::Client;
+use std::collections::{BTreeMap, HashMap};
+
+// longest accepted search text, in characters
+const MAX_QUERY_LEN: usize = 200;
+
+// word similarity (0 to 1) from which a word of a field counts as a
+// misspelling of a searched word, below the pg_trgm default of 0.6
+const MIN_SIMILARITY: f64 = 0.4;
+
+// highlighted fields longer than SNIPPET_LEN characters are cut to a snippet
+// starting SNIPPET_CONTEXT characters before the first match
+const SNIPPET_LEN: usize = 120;
+const SNIPPET_CONTEXT: usize = 40;
+
+// query checks the search text and returns it trimmed.
+pub fn query(q: Option<&str>) -> Result<String, String> {
+    let q = q.map(str::trim).unwrap_or_default();
+    if q.is_empty() {
+        return Err("q is required".to_string());
+    }
+    if q.chars().count() > MAX_QUERY_LEN {
+        return Err(format!(
+            "q exceeds the limit of {} characters",
+            MAX_QUERY_LEN
+        ));
+    }
+    Ok(q.to_string())
+}
+
+// terms splits the search text into lowercase words. Punctuation separates
+// words, so the words never carry tsquery syntax.
+fn terms(q: &str) -> Vec<String> {
+    q.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+// tsquery returns the full-text query in which every word must start a word
+// of the document, or None when the search text has no words.
+fn tsquery(terms: &[String]) -> Option<String> {
+    Some(
+        terms
+            .iter()
+            .map(|term| format!("{}:*", term))
+            .collect::<Vec<_>>()
+            .join(" & "),
+    )
+    .filter(|tsquery| !tsquery.is_empty())
+}
+
+// search runs a search and returns the page of results at offset, best match
+// first. owned restricts the results to those accounts and the transfers from
+// or to them.
+pub async fn search(
+    client: &mut Client,
+    q: &str,
+    owned: Option<&Vec<i64>>,
+    limit: i64,
+    offset: i64,
+) -> Result<SearchResults, MyError> {
+    let terms = terms(q);
+    let tsquery = tsquery(&terms);
+
+    // one extra row tells whether there is a next page
+    let mut hits = db::search(
+        client,
+        q,
+        tsquery.as_deref(),
+        MIN_SIMILARITY,
+        owned,
+        limit + 1,
+        offset,
+    )
+    .await?;
+    let next_offset = (hits.len() as i64 > limit).then_some(offset + limit);
+    hits.truncate(limit as usize);
+
+    let ids = |kind: &str| -> Vec<i64> {
+        hits.iter()
+            .filter(|(hit_kind, _, _)| hit_kind == kind)
+            .map(|(_, id, _)| *id)
+            .collect()
+    };
+    let mut accounts: HashMap<i64, Account> = db::get_accounts_by_ids(client, &ids("account"))
+        .await?
+        .into_iter()
+        .map(|account| (account.id.unwrap_or_default(), account))
+        .collect();
+    let mut transactions: HashMap<i64, Transaction> =
+        db::get_transactions_by_ids(client, &ids("transaction"))
+            .await?
+            .into_iter()
+            .map(|tx| (tx.id.unwrap_or_default(), tx))
+            .collect();
+
+    let terms: Vec<Vec<char>> = terms.iter().map(|term| term.chars().collect()).collect();
+    let mut results = Vec::with_capacity(hits.len());
+    for (kind, id, score) in hits {
+        // rows deleted since the search are left out
+        let result = if kind == "account" {
+            accounts.remove(&id).map(|account| SearchResult {
+                kind: SearchKind::Account,
+                id,
+                score,
+                highlights: highlights(
+                    &terms,
+                    &[
+                        ("username", account.username.as_deref()),
+                        ("email", account.email.as_deref()),
+                    ],
+                ),
+                account: Some(account),
+                transaction: None,
+            })
+        } else {
+            transactions.remove(&id).map(|tx| SearchResult {
+                kind: SearchKind::Transaction,
+                id,
+                score,
+                highlights: highlights(
+                    &terms,
+                    &[
+                        ("description", tx.description.as_deref()),
+                        ("reference", tx.reference.as_deref()),
+                    ],
+                ),
+                account: None,
+                transaction: Some(tx),
+            })
+        };
+        results.extend(result);
+    }
+
+    Ok(SearchResults {
+        q: q.to_string(),
+        limit,
+        offset,
+        next_offset,
+        results,
+    })
+}
+
+// highlights returns the highlighted text of the fields containing a term.
+fn highlights(terms: &[Vec<char>], fields: &[(&str, Option<&str>)]) -> BTreeMap<String, String> {
+    fields
+        .iter()
+        .filter_map(|(name, value)| {
+            highlight(value.unwrap_or_default(), terms).map(|text| (name.to_string(), text))
+        })
+        .collect()
+}
+
+// highlight wraps every occurrence of the terms in text in <mark> tags,
+// ignoring case, and HTML escapes the rest. It returns None when no term
+// occurs in text. Long texts are cut to a snippet around the first match.
+fn highlight(text: &str, terms: &[Vec<char>]) -> Option<String> {
+    let chars: Vec<char> = text.chars().collect();
+    // lowercased char by char, so that positions match those of chars
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let mut marked = vec![false; chars.len()];
+    for start in 0..lower.len() {
+        for term in terms {
+            if lower[start..].starts_with(term) {
+                marked[start..start + term.len()].fill(true);
+            }
+        }
+    }
+    let first = marked.iter().position(|marked| *marked)?;
+
+    let (from, to) = if chars.len() > SNIPPET_LEN {
+        let from = first.saturating_sub(SNIPPET_CONTEXT);
+        (from, (from + SNIPPET_LEN).min(chars.len()))
+    } else {
+        (0, chars.len())
+    };
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    for i in from..to {
+        if marked[i] && (i == from || !marked[i - 1]) {
+            out.push_str("<mark>");
+        }
+        match chars[i] {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+        if marked[i] && (i + 1 == to || !marked[i + 1]) {
+            out.push_str("</mark>");
+        }
+    }
+    if to < chars.len() {
+        out.push('…');
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        highlight, query, search, terms, tsquery, MAX_QUERY_LEN, SNIPPET_CONTEXT, SNIPPET_LEN,
+    };
+    use crate::{
+        config::{Config, LoadOptions},
+        model::SearchKind,
+        server::create_pool,
+    };
+
+    fn chars(terms: &[&str]) -> Vec<Vec<char>> {
+        terms.iter().map(|term| term.chars().collect()).collect()
+    }
+
+    #[test]
+    fn query_is_trimmed_and_limited() {
+        assert_eq!(query(Some("  alice ")).unwrap(), "alice");
+        assert_eq!(query(None).unwrap_err(), "q is required");
+        assert_eq!(query(Some(" \t")).unwrap_err(), "q is required");
+
+        // the limit counts characters, not bytes
+        let longest = "é".repeat(MAX_QUERY_LEN);
+        assert!(query(Some(&longest)).is_ok());
+        assert_eq!(
+            query(Some(&format!("{}e", longest))).unwrap_err(),
+            "q exceeds the limit of 200 characters"
+        );
+    }
+
+    #[test]
+    fn terms_are_lowercase_words() {
+        assert_eq!(terms("Alice SMITH"), vec!["alice", "smith"]);
+        assert_eq!(terms("alice@example.com"), vec!["alice", "example", "com"]);
+        assert_eq!(terms("Müller-Lüdenscheid"), vec!["müller", "lüdenscheid"]);
+        assert!(terms(" -- ").is_empty());
+    }
+
+    #[test]
+    fn tsquery_requires_every_word_as_a_prefix() {
+        assert_eq!(
+            tsquery(&terms("Rent March")).as_deref(),
+            Some("rent:* & march:*")
+        );
+        // tsquery operators in the text are word separators
+        assert_eq!(
+            tsquery(&terms("a&b | !c:*")).as_deref(),
+            Some("a:* & b:* & c:*")
+        );
+        assert_eq!(tsquery(&terms("'&|!()")), None);
+    }
+
+    #[test]
+    fn highlight_marks_terms_ignoring_case() {
+        assert_eq!(
+            highlight("Rent March, rent April", &chars(&["rent"])).as_deref(),
+            Some("<mark>Rent</mark> March, <mark>rent</mark> April")
+        );
+        // overlapping and adjacent matches merge into one mark
+        assert_eq!(
+            highlight("abcd", &chars(&["abc", "bcd"])).as_deref(),
+            Some("<mark>abcd</mark>")
+        );
+        assert_eq!(highlight("Rent", &chars(&["salary"])), None);
+    }
+
+    #[test]
+    fn highlight_escapes_html() {
+        assert_eq!(
+            highlight("<b>Tom & Jerry's \"rent\"</b>", &chars(&["rent"])).as_deref(),
+            Some("&lt;b&gt;Tom &amp; Jerry&#39;s &quot;<mark>rent</mark>&quot;&lt;/b&gt;")
+        );
+    }
+
+    #[test]
+    fn highlight_cuts_long_text_around_the_first_match() {
+        let text = format!("{}rent{}", "a".repeat(100), "b".repeat(100));
+        let snippet = highlight(&text, &chars(&["rent"])).unwrap();
+        assert_eq!(
+            snippet,
+            format!(
+                "…{}<mark>rent</mark>{}…",
+                "a".repeat(SNIPPET_CONTEXT),
+                "b".repeat(SNIPPET_LEN - SNIPPET_CONTEXT - 4)
+            )
+        );
+
+        // a match near the start keeps the start of the text
+        let text = format!("rent{}", "b".repeat(200));
+        let snippet = highlight(&text, &chars(&["rent"])).unwrap();
+        assert!(snippet.starts_with("<mark>rent</mark>"));
+        // the snippet, its mark tags and the trailing ellipsis
+        assert_eq!(
+            snippet.chars().count(),
+            SNIPPET_LEN + "<mark></mark>".len() + 1
+        );
+    }
+
+    // Exact matches rank above misspelt ones, which the similarity threshold
+    // still finds, accounts come before transactions of the same score, and
+    // owned restricts the results.
+    #[actix_web::test]
+    #[ignore = "needs a PostgreSQL database with the migrations applied"]
+    async fn search_ranks_exact_matches_first() {
+        let config = Config::load(&LoadOptions::default()).unwrap().config;
+        let mut client = create_pool(&config).unwrap().get().await.unwrap();
+        let pid = std::process::id();
+        let mut ids = Vec::new();
+        for username in ["quirkleberry", "quirklebery", "unrelated"] {
+            let row = client
+                .query_one(
+                    "INSERT INTO accounts (username, balance, email)
+                     VALUES ($1, 0, 'search@example.com') RETURNING id",
+                    &[&format!("{}_{}", username, pid)],
+                )
+                .await
+                .unwrap();
+            ids.push(row.get::<_, i64>(0));
+        }
+        let tx: i64 = client
+            .query_one(
+                "INSERT INTO transactions (from_account, to_account, amount, description)
+                 VALUES ($1, $2, 1, 'Quirkleberry invoice') RETURNING id",
+                &[&ids[2], &ids[1]],
+            )
+            .await
+            .unwrap()
+            .get(0);
+
+        let found = search(&mut client, "quirkleberry", Some(&ids), 10, 0)
+            .await
+            .unwrap();
+        let hits: Vec<(SearchKind, i64)> = found.results.iter().map(|r| (r.kind, r.id)).collect();
+        assert_eq!(
+            hits,
+            vec![
+                (SearchKind::Account, ids[0]),
+                (SearchKind::Transaction, tx),
+                (SearchKind::Account, ids[1]),
+            ]
+        );
+        assert!(found.results[0].score > found.results[2].score);
+        assert_eq!(found.next_offset, None);
+        assert_eq!(
+            found.results[1]
+                .highlights
+                .get("description")
+                .map(String::as_str),
+            Some("<mark>Quirkleberry</mark> invoice")
+        );
+
+        // pages end early when more results follow
+        let page = search(&mut client, "quirkleberry", Some(&ids), 1, 1)
+            .await
+            .unwrap();
+        assert_eq!(page.results[0].id, tx);
+        assert_eq!(page.next_offset, Some(2));
+
+        // the transaction involves the unrelated account, the exact match does not
+        let owned = vec![ids[2]];
+        let found = search(&mut client, "quirkleberry", Some(&owned), 10, 0)
+            .await
+            .unwrap();
+        let hits: Vec<i64> = found.results.iter().map(|r| r.id).collect();
+        assert_eq!(hits, vec![tx]);
+
+        client
+            .execute("DELETE FROM transactions WHERE id = $1", &[&tx])
+            .await
+            .unwrap();
+        client
+            .execute("DELETE FROM accounts WHERE id = ANY($1)", &[&ids])
+            .await
+            .unwrap();
+    }
+}
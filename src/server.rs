@@ -1,73 +1,484 @@
-use actix_contrib_logger::middleware::Logger;
-use actix_web::{http::StatusCode, web, App, HttpServer};
-use env_logger::Env;
-use log::Level;
-use tokio_postgres::NoTls;
-use crate::config::{default_config, Config};
+use crate::auth::{require_admin, require_create_accounts, require_read, require_transfer};
+use crate::config::{Config, LoadOptions, LoadedConfig};
+use crate::events::{self, EventHub};
+use crate::graphql;
+use crate::grpc::{self, LedgerService};
 use crate::handlers::{
-    create_account, create_transaction, get_account_by_id, get_accounts, get_transaction_by_id,
-    get_transactions, health, status,
+    cancel_scheduled_transfer, create_account, create_accounts, create_scheduled_transfer,
+    create_transaction, create_transactions, create_webhook, delete_webhook, events_ws,
+    get_account_balance, get_account_by_id, get_accounts, get_audit, get_balances, get_events,
+    get_metrics, get_scheduled_transfer, get_scheduled_transfers, get_statement,
+    get_transaction_by_id, get_transactions, get_webhook_deliveries, get_webhooks, graphiql,
+    graphql_request, json_error_handler, livez, path_error_handler, pause_scheduled_transfer,
+    query_error_handler, readyz, reconcile_balances, resume_scheduled_transfer, search, status,
+    update_account,
+};
+use crate::jwt::JwtVerifier;
+use crate::logging;
+use crate::metrics::Metrics;
+use crate::middleware::request_context;
+use crate::openapi::ApiDoc;
+use crate::outbox;
+use crate::ratelimit::{rate_limit, RateLimiter};
+use crate::reconcile;
+use crate::scheduler;
+use crate::shutdown::{wait_for_signal, BackgroundTasks, Shutdown};
+use crate::snapshots;
+use crate::tls;
+use crate::webhooks;
+use actix_web::{
+    middleware::{from_fn, Condition},
+    rt, web, App, HttpServer,
 };
+use deadpool_postgres::{Pool, SslMode};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio_postgres::NoTls;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
-pub async fn run_server(config_file: &str) -> std::io::Result<()> {
+pub async fn run_server(options: &LoadOptions) -> std::io::Result<()> {
     // Load configuration
-    let config = match Config::from_file(config_file) {
-        Ok(config) => {
-            log::info!("Loaded configuration from file.");
-            config
-        }
-        Err(err) => {
-            log::warn!("Failed to load configuration from file: {}", err);
-            log::info!("Using default configuration.");
-            default_config()
-        }
-    };
+    let LoadedConfig {
+        config,
+        sources,
+        warnings,
+    } = Config::load(options).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid configuration: {}", err),
+        )
+    })?;
 
     // Initialize logger
-    env_logger::Builder::from_env(Env::default().default_filter_or(&config.log_level))
-        .format_timestamp_millis()
-        .init();
+    logging::init(&config.log_level, config.log_format);
 
     log::info!("Welcome to {}", env!("SERVICE_NAME"));
     log::info!("Version: {}", env!("VERSION"));
     log::info!("Compilation date: {}", env!("BUILD_DATE"));
     log::info!("Log level: {}", &config.log_level);
-    log::info!("Using config file: {}", config_file);
-    log::debug!("PostgreSQL Configuration: {:?}", config.pg);
+    log::info!("Log format: {:?}", config.log_format);
+    log::info!("Configuration sources: {}", sources.join(" < "));
+    for warning in warnings {
+        log::warn!("{}", warning);
+    }
+    log::debug!("Effective configuration: {}", config.redacted());
 
     // Create PostgreSQL connection pool
-    let pool = config.pg.create_pool(None, NoTls).unwrap();
+    let pool = create_pool(&config)?;
 
-    // Start Actix Web server
-    let server = HttpServer::new(move || {
-        let logger = Logger::default().custom_level(|status| {
-            if status.is_server_error() {
-                Level::Error
-            } else if status == StatusCode::NOT_FOUND || status == StatusCode::BAD_REQUEST {
-                Level::Warn
-            } else {
-                Level::Info
-            }
-        });
+    let shutdown = web::Data::new(Shutdown::new());
+    let metrics = web::Data::new(Metrics::default());
+    let mut background_tasks = BackgroundTasks::default();
+
+    if config.reconcile.interval_secs > 0 {
+        log::info!(
+            "Balance reconciliation scheduled every {}s (repair: {})",
+            config.reconcile.interval_secs,
+            config.reconcile.repair
+        );
+        background_tasks.spawn(
+            "reconcile",
+            reconcile::schedule(
+                pool.clone(),
+                config.reconcile.clone(),
+                metrics.clone().into_inner(),
+                shutdown.subscribe(),
+            ),
+        );
+    }
+
+    // Start Actix Web server. Signals are handled below so that readiness can be
+    // flipped before the listener stops accepting connections.
+    if config.auth.enabled {
+        log::info!("Authentication enabled");
+    } else {
+        log::warn!("Authentication disabled, ledger routes are open to all clients");
+    }
+    let jwt_verifier = match &config.auth.jwt {
+        Some(jwt_config) => {
+            let verifier = JwtVerifier::new(jwt_config)?;
+            log::info!(
+                "JWT bearer authentication enabled (algorithm: {:?})",
+                jwt_config.algorithm
+            );
+            Some(web::Data::new(verifier))
+        }
+        None => None,
+    };
+
+    if config.rate_limit.enabled {
+        log::info!(
+            "Rate limiting enabled ({} route overrides)",
+            config.rate_limit.routes.len()
+        );
+    }
+    if let Some(velocity) = &config.rate_limit.velocity {
+        log::info!(
+            "Transfer velocity limits enabled (window: {}s, max transfers: {:?}, max amount: {:?})",
+            velocity.window_secs,
+            velocity.max_transfers,
+            velocity.max_amount
+        );
+    }
+    let rate_limiter = web::Data::new(RateLimiter::new(config.rate_limit.clone()));
+
+    if config.scheduler.interval_secs > 0 {
+        background_tasks.spawn(
+            "scheduled transfers",
+            scheduler::schedule(
+                pool.clone(),
+                config.scheduler.clone(),
+                config.rate_limit.velocity.clone(),
+                shutdown.subscribe(),
+            ),
+        );
+    }
+
+    if config.snapshots.interval_secs > 0 {
+        background_tasks.spawn(
+            "balance snapshots",
+            snapshots::schedule(pool.clone(), config.snapshots.clone(), shutdown.subscribe()),
+        );
+    }
+
+    if config.webhooks.interval_secs > 0 {
+        background_tasks.spawn(
+            "webhook deliveries",
+            webhooks::dispatch(pool.clone(), config.webhooks.clone(), shutdown.subscribe()),
+        );
+    }
+
+    let outbox_sinks = outbox::sinks(&config.outbox)?;
+    if outbox_sinks.is_empty() {
+        log::info!("No outbox sinks configured, events are kept until one is");
+    } else if config.outbox.interval_secs == 0 {
+        log::info!("Outbox relay disabled, events are kept until it is enabled");
+    } else {
+        background_tasks.spawn(
+            "outbox relay",
+            outbox::relay(
+                pool.clone(),
+                config.outbox.clone(),
+                outbox_sinks,
+                shutdown.subscribe(),
+            ),
+        );
+    }
 
-        App::new()
-            .app_data(web::Data::new(pool.clone()))
-            .wrap(logger)
+    let event_hub = web::Data::new(EventHub::new(config.events.clone()));
+    background_tasks.spawn(
+        "ledger event listener",
+        events::listen(
+            pool.clone(),
+            config.pg.clone(),
+            config.pg_tls.clone(),
+            event_hub.clone().into_inner(),
+            shutdown.subscribe(),
+        ),
+    );
+
+    if let Some(grpc_addr) = &config.grpc_addr {
+        let service = LedgerService {
+            pool: pool.clone(),
+            auth: config.auth.clone(),
+            verifier: jwt_verifier.clone().map(|verifier| verifier.into_inner()),
+            limiter: rate_limiter.clone().into_inner(),
+            accounts: config.accounts.clone(),
+            health: config.health.clone(),
+            shutdown: shutdown.clone().into_inner(),
+        };
+        let grpc_server = grpc::serve(
+            grpc_addr,
+            config.tls.as_ref(),
+            service,
+            shutdown.subscribe(),
+            Duration::from_secs(config.shutdown_grace_secs),
+        )
+        .await?;
+        log::info!(
+            "gRPC server running at {} (TLS: {})",
+            grpc_addr,
+            config.tls.is_some()
+        );
+        background_tasks.spawn("gRPC server", grpc_server);
+    }
+
+    let health_config = web::Data::new(config.health.clone());
+    let auth_config = web::Data::new(config.auth.clone());
+    let batch_config = web::Data::new(config.batch.clone());
+    let accounts_config = web::Data::new(config.accounts.clone());
+    let graphql_schema = web::Data::new(graphql::schema(
+        pool.clone(),
+        &config.graphql,
+        rate_limiter.clone().into_inner(),
+    ));
+    let api_doc = ApiDoc::openapi();
+    let app_pool = pool.clone();
+    let app_shutdown = shutdown.clone();
+    let public_metrics = config.auth.public_metrics;
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
+            .app_data(web::Data::new(app_pool.clone()))
+            .app_data(app_shutdown.clone())
+            .app_data(metrics.clone())
+            .app_data(health_config.clone())
+            .app_data(auth_config.clone())
+            .app_data(batch_config.clone())
+            .app_data(accounts_config.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(event_hub.clone())
+            .app_data(graphql_schema.clone())
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+            .app_data(web::PathConfig::default().error_handler(path_error_handler));
+        if let Some(verifier) = &jwt_verifier {
+            app = app.app_data(verifier.clone());
+        }
+        // the request context wraps the rate limiter so that rejected requests are logged
+        app.wrap(from_fn(rate_limit))
+            .wrap(from_fn(request_context))
             .service(web::resource("/status").route(web::get().to(status)))
-            .service(web::resource("/health").route(web::get().to(health)))
-            .service(web::resource("/accounts").route(web::get().to(get_accounts)))
-            .service(web::resource("/transactions").route(web::get().to(get_transactions)))
-            .service(web::resource("/account-by-id").route(web::post().to(get_account_by_id)))
+            .service(web::resource("/health").route(web::get().to(readyz)))
+            .service(web::resource("/livez").route(web::get().to(livez)))
+            .service(web::resource("/readyz").route(web::get().to(readyz)))
+            .service(
+                web::resource("/metrics")
+                    .wrap(Condition::new(!public_metrics, from_fn(require_read)))
+                    .route(web::get().to(get_metrics)),
+            )
+            .service(SwaggerUi::new("/swagger-ui/{_:.*}").url("/openapi.json", api_doc.clone()))
+            .service(
+                web::resource("/accounts")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_accounts)),
+            )
+            .service(
+                web::resource("/accounts/balances")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_balances)),
+            )
+            .service(
+                web::resource("/accounts/{id}/balance")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_account_balance)),
+            )
+            .service(
+                web::resource("/accounts/{id}/statement")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_statement)),
+            )
+            .service(
+                web::resource("/transactions")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_transactions)),
+            )
+            .service(
+                web::resource("/search")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(search)),
+            )
+            .service(
+                web::resource("/account-by-id")
+                    .wrap(from_fn(require_read))
+                    .route(web::post().to(get_account_by_id)),
+            )
+            .service(
+                web::resource("/transaction-by-id")
+                    .wrap(from_fn(require_read))
+                    .route(web::post().to(get_transaction_by_id)),
+            )
+            .service(
+                web::resource("/create-account")
+                    .wrap(from_fn(require_create_accounts))
+                    .route(web::put().to(create_account)),
+            )
+            .service(
+                web::resource("/accounts/batch")
+                    .wrap(from_fn(require_create_accounts))
+                    .route(web::post().to(create_accounts)),
+            )
+            // after the fixed /accounts/* paths, which it would otherwise shadow
+            .service(
+                web::resource("/accounts/{id}")
+                    // owners update their own accounts, which JWT callers
+                    // do with the read and transfer scopes they carry
+                    .wrap(from_fn(require_transfer))
+                    .route(web::patch().to(update_account)),
+            )
+            .service(
+                web::resource("/create-tx")
+                    .wrap(from_fn(require_transfer))
+                    .route(web::put().to(create_transaction)),
+            )
+            .service(
+                web::resource("/transactions/batch")
+                    .wrap(from_fn(require_transfer))
+                    .route(web::post().to(create_transactions)),
+            )
+            .service(
+                web::resource("/scheduled-transfers")
+                    .route(
+                        web::get()
+                            .to(get_scheduled_transfers)
+                            .wrap(from_fn(require_read)),
+                    )
+                    .route(
+                        web::post()
+                            .to(create_scheduled_transfer)
+                            .wrap(from_fn(require_transfer)),
+                    ),
+            )
+            .service(
+                web::resource("/scheduled-transfers/{id}")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_scheduled_transfer)),
+            )
+            .service(
+                web::resource("/scheduled-transfers/{id}/pause")
+                    .wrap(from_fn(require_transfer))
+                    .route(web::post().to(pause_scheduled_transfer)),
+            )
+            .service(
+                web::resource("/scheduled-transfers/{id}/resume")
+                    .wrap(from_fn(require_transfer))
+                    .route(web::post().to(resume_scheduled_transfer)),
+            )
+            .service(
+                web::resource("/scheduled-transfers/{id}/cancel")
+                    .wrap(from_fn(require_transfer))
+                    .route(web::post().to(cancel_scheduled_transfer)),
+            )
+            .service(
+                web::resource("/events")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(get_events)),
+            )
+            .service(
+                web::resource("/events/ws")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(events_ws)),
+            )
+            .service(
+                web::resource("/graphql")
+                    .wrap(from_fn(require_read))
+                    .route(web::get().to(graphiql))
+                    .route(web::post().to(graphql_request)),
+            )
+            .service(
+                web::resource("/audit")
+                    .wrap(from_fn(require_admin))
+                    .route(web::get().to(get_audit)),
+            )
+            .service(
+                web::resource("/webhooks")
+                    .wrap(from_fn(require_admin))
+                    .route(web::get().to(get_webhooks))
+                    .route(web::post().to(create_webhook)),
+            )
             .service(
-                web::resource("/transaction-by-id").route(web::post().to(get_transaction_by_id)),
+                web::resource("/webhooks/{id}")
+                    .wrap(from_fn(require_admin))
+                    .route(web::delete().to(delete_webhook)),
+            )
+            .service(
+                web::resource("/webhooks/{id}/deliveries")
+                    .wrap(from_fn(require_admin))
+                    .route(web::get().to(get_webhook_deliveries)),
+            )
+            .service(
+                web::resource("/admin/reconcile")
+                    .wrap(from_fn(require_admin))
+                    .route(web::post().to(reconcile_balances)),
             )
-            .service(web::resource("/create-account").route(web::put().to(create_account)))
-            .service(web::resource("/create-tx").route(web::put().to(create_transaction)))
     })
-    .bind(config.server_addr.clone())?
-    .run();
+    .disable_signals()
+    .shutdown_timeout(config.shutdown_timeout_secs);
+
+    let server = match &config.tls {
+        Some(tls_config) => {
+            let server = server
+                .bind_rustls_0_23(config.server_addr.clone(), tls::server_config(tls_config)?)?
+                .run();
+            log::info!("PSQL Server running at https://{}", config.server_addr);
+            if tls_config.client_ca_file.is_some() {
+                log::info!(
+                    "Client certificate authentication enabled (required: {})",
+                    tls_config.require_client_cert
+                );
+            }
+            server
+        }
+        None => {
+            let server = server.bind(config.server_addr.clone())?.run();
+            log::info!("PSQL Server running at http://{}", config.server_addr);
+            server
+        }
+    };
+
+    let handle = server.handle();
+    let mut server_task = rt::spawn(server);
+
+    tokio::select! {
+        res = &mut server_task => {
+            // server exited without a shutdown signal
+            shutdown.trigger();
+            background_tasks.join(Duration::from_secs(config.shutdown_timeout_secs)).await;
+            pool.close();
+            return res?;
+        }
+        signal = wait_for_signal() => {
+            log::info!("{} received, starting graceful shutdown", signal);
+        }
+    }
+
+    // Fail the health check first so that load balancers stop routing new traffic
+    shutdown.trigger();
+    log::info!("Health check now reporting service as unavailable");
+    if config.shutdown_grace_secs > 0 {
+        log::info!(
+            "Waiting {}s before closing the listener",
+            config.shutdown_grace_secs
+        );
+        tokio::time::sleep(Duration::from_secs(config.shutdown_grace_secs)).await;
+    }
 
-    log::info!("PSQL Server running at http://{}", config.server_addr);
+    log::info!(
+        "Stopping HTTP server, draining {} in-flight request(s) (timeout {}s)",
+        shutdown.in_flight(),
+        config.shutdown_timeout_secs
+    );
+    handle.stop(true).await;
+    if shutdown.in_flight() > 0 {
+        log::warn!(
+            "HTTP server stopped with {} request(s) still in flight",
+            shutdown.in_flight()
+        );
+    } else {
+        log::info!("HTTP server stopped, all in-flight requests drained");
+    }
+    let res = server_task.await?;
 
-    server.await
+    log::info!("Stopping background tasks");
+    background_tasks
+        .join(Duration::from_secs(config.shutdown_timeout_secs))
+        .await;
+
+    log::info!("Closing PostgreSQL connection pool");
+    pool.close();
+
+    log::info!("Shutdown complete");
+    res
+}
+
+// create_pool builds the PostgreSQL connection pool. Connections negotiate TLS
+// unless pg.ssl_mode is Disable.
+pub fn create_pool(config: &Config) -> std::io::Result<Pool> {
+    let pool = match config.pg.ssl_mode {
+        Some(SslMode::Disable) => config.pg.create_pool(None, NoTls),
+        _ => config
+            .pg
+            .create_pool(None, tls::pg_connector(&config.pg_tls)?),
+    };
+    pool.map_err(|err| Error::new(ErrorKind::InvalidInput, err))
 }
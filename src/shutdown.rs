@@ -0,0 +1,128 @@
+// Shutdown coordination shared between the HTTP server, the request
+// middleware and background tasks. Once shutdown is triggered the health
+// check reports failing, in-flight requests are counted down while the
+// server drains and background tasks are notified to stop.
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{sync::watch, task::JoinHandle};
+
+pub struct Shutdown {
+    shutting_down: AtomicBool,
+    in_flight: Arc<AtomicUsize>,
+    notify: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (notify, _) = watch::channel(false);
+        Shutdown {
+            shutting_down: AtomicBool::new(false),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            notify,
+        }
+    }
+
+    // trigger marks the service as shutting down and notifies background tasks.
+    pub fn trigger(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        self.notify.send_replace(true);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    // subscribe returns a receiver that background tasks can await to learn
+    // that the service is shutting down.
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.notify.subscribe()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // track_request counts a request as in-flight until the returned guard is dropped.
+    pub fn track_request(&self) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.in_flight.clone())
+    }
+}
+
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// wait_for_signal resolves once SIGTERM or SIGINT (ctrl-c) is received.
+pub async fn wait_for_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = sigterm.recv() => "SIGTERM",
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+            },
+            Err(err) => {
+                log::warn!("Unable to listen for SIGTERM: {}", err);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
+
+// stopped resolves once shutdown has been triggered on the given receiver.
+pub async fn stopped(rx: &mut watch::Receiver<bool>) {
+    // an error means the sender was dropped, which only happens on exit
+    let _ = rx.wait_for(|stop| *stop).await;
+}
+
+// BackgroundTasks holds the long running tasks spawned alongside the HTTP
+// server so that they can be awaited during shutdown.
+#[derive(Default)]
+pub struct BackgroundTasks {
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl BackgroundTasks {
+    // spawn runs a task that is expected to exit once shutdown is triggered.
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        log::info!("Starting background task: {}", name);
+        self.tasks.push((name, actix_web::rt::spawn(task)));
+    }
+
+    // join waits for every task to exit, aborting those still running after the timeout.
+    pub async fn join(self, timeout: Duration) {
+        for (name, task) in self.tasks {
+            let abort = task.abort_handle();
+            match tokio::time::timeout(timeout, task).await {
+                Ok(_) => log::info!("Background task stopped: {}", name),
+                Err(_) => {
+                    log::warn!("Background task {} did not stop in time, aborting", name);
+                    abort.abort();
+                }
+            }
+        }
+    }
+}
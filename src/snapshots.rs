@@ -0,0 +1,37 @@
+// Periodic balance snapshots. Point-in-time balances are computed from the
+// latest snapshot before the requested time plus the transfers made since,
+// so regular snapshots bound the amount of history each query has to scan.
+use crate::{config::SnapshotConfig, db, shutdown::stopped};
+use chrono::{TimeDelta, Utc};
+use deadpool_postgres::Pool;
+use std::time::Duration;
+use tokio::sync::watch;
+
+// schedule takes a snapshot every interval until shutdown is triggered.
+pub async fn schedule(pool: Pool, config: SnapshotConfig, mut shutdown: watch::Receiver<bool>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+
+    loop {
+        tokio::select! {
+            _ = stopped(&mut shutdown) => return,
+            _ = ticker.tick() => {}
+        }
+
+        let as_of = Utc::now() - TimeDelta::seconds(config.settle_secs as i64);
+        let client = match pool.get().await {
+            Ok(client) => client,
+            Err(err) => {
+                log::error!("Balance snapshot failed: {}", err);
+                continue;
+            }
+        };
+        match db::create_balance_snapshots(&client, as_of).await {
+            Ok(count) => log::info!(
+                "Balance snapshot taken as of {} ({} accounts)",
+                as_of.to_rfc3339(),
+                count
+            ),
+            Err(err) => log::error!("Balance snapshot failed: {}", err),
+        }
+    }
+}
@@ -0,0 +1,395 @@
+// Account statements. A statement covers the period [from, to) and lists the
+// opening balance, every transfer with its counterparty and running balance,
+// and the closing balance. Statements render as JSON, CSV or a fixed-width
+// plain-text layout suitable for printing or email.
+use crate::{db, errors::MyError, model::Statement};
+use chrono::{DateTime, Datelike, TimeDelta, TimeZone, Utc};
+use deadpool_postgres::Client;
+use std::fmt::Write;
+use std::str::FromStr;
+
+const TEXT_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// widest counterparty name shown in the text layout
+const TEXT_COUNTERPARTY_WIDTH: usize = 24;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StatementFormat {
+    #[default]
+    Json,
+    Csv,
+    Text,
+}
+
+impl StatementFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StatementFormat::Json => "application/json",
+            StatementFormat::Csv => "text/csv; charset=utf-8",
+            StatementFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StatementFormat::Json => "json",
+            StatementFormat::Csv => "csv",
+            StatementFormat::Text => "txt",
+        }
+    }
+}
+
+impl FromStr for StatementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(StatementFormat::Json),
+            "csv" => Ok(StatementFormat::Csv),
+            "text" => Ok(StatementFormat::Text),
+            _ => Err(format!(
+                "unknown statement format {} (expected json, csv or text)",
+                s
+            )),
+        }
+    }
+}
+
+// period fills in the default statement period, the current month to date,
+// and checks that it is not empty.
+pub fn period(
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<(DateTime<Utc>, DateTime<Utc>), String> {
+    let now = Utc::now();
+    let from = match from {
+        Some(from) => from,
+        None => Utc
+            .with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+            .single()
+            .unwrap_or(now),
+    };
+    let to = to.unwrap_or(now);
+    if from >= to {
+        return Err("from must be before to".to_string());
+    }
+    Ok((from, to))
+}
+
+// build generates the statement of an account for [from, to).
+pub async fn build(
+    client: &Client,
+    account_id: i64,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Statement, MyError> {
+    let account = db::get_account_by_id(client, account_id).await?;
+
+    let mut lines = db::get_statement_lines(client, account_id, from, to).await?;
+
+    // the opening balance includes everything before from; timestamps are
+    // stored with microsecond precision. An account created in the period has
+    // no balance before from, so its history is read at creation, less the
+    // transfers of the period already made by then.
+    let opening_balance = match account.created_at {
+        Some(created) if created >= from => {
+            let at_creation = db::account_balance_at(client, account_id, created)
+                .await?
+                .balance;
+            at_creation
+                - lines
+                    .iter()
+                    .filter(|line| line.created_at.is_some_and(|at| at <= created))
+                    .map(|line| line.amount)
+                    .sum::<i64>()
+        }
+        _ => {
+            db::account_balance_at(client, account_id, from - TimeDelta::microseconds(1))
+                .await?
+                .balance
+        }
+    };
+
+    let mut balance = opening_balance;
+    let (mut total_in, mut total_out) = (0, 0);
+    for line in &mut lines {
+        balance += line.amount;
+        line.balance = balance;
+        if line.amount >= 0 {
+            total_in += line.amount;
+        } else {
+            total_out -= line.amount;
+        }
+    }
+
+    Ok(Statement {
+        account_id,
+        username: account.username.unwrap_or_default(),
+        from: Some(from),
+        to: Some(to),
+        opening_balance,
+        closing_balance: balance,
+        total_in,
+        total_out,
+        lines,
+    })
+}
+
+pub fn render(statement: &Statement, format: StatementFormat) -> String {
+    match format {
+        StatementFormat::Json => serde_json::to_string_pretty(statement).unwrap_or_default(),
+        StatementFormat::Csv => render_csv(statement),
+        StatementFormat::Text => render_text(statement),
+    }
+}
+
+// render_csv writes one row per transfer, framed by opening and closing balance rows.
+fn render_csv(statement: &Statement) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "date,transaction_id,counterparty_id,counterparty,description,amount,balance"
+    );
+    let _ = writeln!(
+        out,
+        "{},,,,Opening balance,,{}",
+        rfc3339(statement.from),
+        statement.opening_balance
+    );
+    for line in &statement.lines {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            rfc3339(line.created_at),
+            line.transaction_id,
+            line.counterparty_id
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+            csv_field(line.counterparty.as_deref().unwrap_or_default()),
+            // the transfer's own description, if it has one
+            csv_field(line.description.as_deref().unwrap_or(match line.amount {
+                amount if amount > 0 => "Received",
+                amount if amount < 0 => "Sent",
+                // transfers to the account itself
+                _ => "Transfer",
+            })),
+            line.amount,
+            line.balance
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{},,,,Closing balance,,{}",
+        rfc3339(statement.to),
+        statement.closing_balance
+    );
+    out
+}
+
+// render_text lays the statement out in fixed-width columns.
+fn render_text(statement: &Statement) -> String {
+    let mut out = String::new();
+    let rule = "-".repeat(84);
+    let _ = writeln!(
+        out,
+        "STATEMENT  Account {} ({})",
+        statement.account_id, statement.username
+    );
+    let _ = writeln!(
+        out,
+        "Period     {} UTC to {} UTC",
+        text_date(statement.from),
+        text_date(statement.to)
+    );
+    let _ = writeln!(out, "{}", rule);
+    let _ = writeln!(
+        out,
+        "{:<19}  {:>8}  {:<24}  {:>12}  {:>12}",
+        "DATE", "TX ID", "COUNTERPARTY", "AMOUNT", "BALANCE"
+    );
+    let _ = writeln!(out, "{}", rule);
+    let _ = writeln!(
+        out,
+        "{:<19}  {:>8}  {:<24}  {:>12}  {:>12}",
+        text_date(statement.from),
+        "",
+        "Opening balance",
+        "",
+        statement.opening_balance
+    );
+    for line in &statement.lines {
+        let counterparty = match (&line.counterparty_id, &line.counterparty) {
+            (Some(id), Some(name)) => format!("{} {}", id, name),
+            (Some(id), None) => id.to_string(),
+            _ => String::new(),
+        };
+        let _ = writeln!(
+            out,
+            "{:<19}  {:>8}  {:<24}  {:>12}  {:>12}",
+            text_date(line.created_at),
+            line.transaction_id,
+            truncate(&counterparty, TEXT_COUNTERPARTY_WIDTH),
+            line.amount,
+            line.balance
+        );
+    }
+    let _ = writeln!(
+        out,
+        "{:<19}  {:>8}  {:<24}  {:>12}  {:>12}",
+        text_date(statement.to),
+        "",
+        "Closing balance",
+        "",
+        statement.closing_balance
+    );
+    let _ = writeln!(out, "{}", rule);
+    let _ = writeln!(
+        out,
+        "Transfers: {}   Total in: {}   Total out: {}",
+        statement.lines.len(),
+        statement.total_in,
+        statement.total_out
+    );
+    out
+}
+
+fn rfc3339(date: Option<DateTime<Utc>>) -> String {
+    date.map(|d| d.to_rfc3339()).unwrap_or_default()
+}
+
+fn text_date(date: Option<DateTime<Utc>>) -> String {
+    date.map(|d| d.format(TEXT_DATE_FORMAT).to_string())
+        .unwrap_or_default()
+}
+
+// csv_field quotes a value containing separators, quotes or line breaks.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn truncate(value: &str, width: usize) -> String {
+    if value.chars().count() <= width {
+        value.to_string()
+    } else {
+        let mut truncated: String = value.chars().take(width - 1).collect();
+        truncated.push('~');
+        truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_field, period, render_csv, render_text, truncate};
+    use crate::model::{Statement, StatementLine};
+    use chrono::{DateTime, Datelike, Utc};
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn line(id: i64, amount: i64, balance: i64) -> StatementLine {
+        StatementLine {
+            transaction_id: id,
+            created_at: Some(at("2026-03-02T10:00:00Z")),
+            counterparty_id: Some(7),
+            counterparty: Some("bob".to_string()),
+            amount,
+            balance,
+            description: None,
+            reference: None,
+        }
+    }
+
+    fn statement() -> Statement {
+        let mut described = line(2, -40, 60);
+        described.description = Some("rent, march".to_string());
+        Statement {
+            account_id: 3,
+            username: "alice".to_string(),
+            from: Some(at("2026-03-01T00:00:00Z")),
+            to: Some(at("2026-04-01T00:00:00Z")),
+            opening_balance: 50,
+            closing_balance: 60,
+            total_in: 50,
+            total_out: 40,
+            lines: vec![line(1, 50, 100), described],
+        }
+    }
+
+    #[test]
+    fn period_defaults_to_the_month_to_date() {
+        let (from, to) = period(None, None).unwrap();
+        assert_eq!((from.day(), from.month()), (1, to.month()));
+        assert_eq!(from.time(), chrono::NaiveTime::MIN);
+
+        let (from, to) = period(
+            Some(at("2026-03-01T00:00:00Z")),
+            Some(at("2026-04-01T00:00:00Z")),
+        )
+        .unwrap();
+        assert_eq!(from, at("2026-03-01T00:00:00Z"));
+        assert_eq!(to, at("2026-04-01T00:00:00Z"));
+    }
+
+    #[test]
+    fn period_must_not_be_empty() {
+        let day = at("2026-03-01T00:00:00Z");
+        assert!(period(Some(day), Some(day)).is_err());
+        assert!(period(Some(day), Some(at("2026-02-01T00:00:00Z"))).is_err());
+    }
+
+    #[test]
+    fn csv_lists_lines_between_the_balances() {
+        assert_eq!(
+            render_csv(&statement()),
+            "date,transaction_id,counterparty_id,counterparty,description,amount,balance\n\
+            2026-03-01T00:00:00+00:00,,,,Opening balance,,50\n\
+            2026-03-02T10:00:00+00:00,1,7,bob,Received,50,100\n\
+            2026-03-02T10:00:00+00:00,2,7,bob,\"rent, march\",-40,60\n\
+            2026-04-01T00:00:00+00:00,,,,Closing balance,,60\n"
+        );
+    }
+
+    #[test]
+    fn text_aligns_columns_and_sums_up() {
+        let text = render_text(&statement());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "STATEMENT  Account 3 (alice)");
+        assert_eq!(
+            lines[1],
+            "Period     2026-03-01 00:00:00 UTC to 2026-04-01 00:00:00 UTC"
+        );
+        assert_eq!(
+            lines[5],
+            "2026-03-01 00:00:00            Opening balance                                   50"
+        );
+        assert_eq!(
+            lines[7],
+            "2026-03-02 10:00:00         2  7 bob                              -40            60"
+        );
+        assert_eq!(lines[10], "Transfers: 2   Total in: 50   Total out: 40");
+        assert!(lines[2..=9].iter().all(|line| line.chars().count() <= 84));
+    }
+
+    #[test]
+    fn csv_field_quotes_when_needed() {
+        assert_eq!(csv_field("plain text"), "plain text");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+
+    #[test]
+    fn truncate_marks_cut_values() {
+        assert_eq!(truncate("short", 24), "short");
+        assert_eq!(truncate("exactly", 7), "exactly");
+        assert_eq!(truncate("too long", 5), "too ~");
+        // widths count characters, not bytes
+        assert_eq!(truncate("ééééé", 3), "éé~");
+    }
+}
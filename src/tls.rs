@@ -0,0 +1,475 @@
+// rustls configuration for the HTTPS and gRPC listeners (with optional client
+// certificate authentication) and for connections to PostgreSQL.
+use crate::config::{PgTlsConfig, PgTlsVerify, ServerTlsConfig};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::WebPkiClientVerifier,
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig as GrpcTlsConfig};
+
+// server_config builds the rustls configuration for the HTTP listener. When a
+// client CA bundle is configured, clients are asked for a certificate signed
+// by one of those CAs (mutual TLS).
+pub fn server_config(config: &ServerTlsConfig) -> Result<ServerConfig, Error> {
+    let certs = load_certs(&config.cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_file).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("failed to read private key {}: {}", config.key_file, err),
+        )
+    })?;
+
+    let builder = match &config.client_ca_file {
+        Some(client_ca_file) => {
+            let roots = Arc::new(load_roots(client_ca_file)?);
+            let verifier = if config.require_client_cert {
+                WebPkiClientVerifier::builder(roots).build()
+            } else {
+                WebPkiClientVerifier::builder(roots)
+                    .allow_unauthenticated()
+                    .build()
+            }
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, key)
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+}
+
+// grpc_server_config builds the TLS configuration of the gRPC listener from
+// the same files as the HTTP listener, including client certificate checks.
+pub fn grpc_server_config(config: &ServerTlsConfig) -> Result<GrpcTlsConfig, Error> {
+    let read = |file: &str| {
+        fs::read(file).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to read {}: {}", file, err),
+            )
+        })
+    };
+
+    let mut tls_config = GrpcTlsConfig::new().identity(Identity::from_pem(
+        read(&config.cert_file)?,
+        read(&config.key_file)?,
+    ));
+    if let Some(client_ca_file) = &config.client_ca_file {
+        tls_config = tls_config
+            .client_ca_root(Certificate::from_pem(read(client_ca_file)?))
+            .client_auth_optional(!config.require_client_cert);
+    }
+    Ok(tls_config)
+}
+
+// pg_connector builds the TLS connector used by the connection pool. Server
+// certificates are checked against root_cert_file if set, otherwise against
+// the system trust store, according to the configured verification level.
+pub fn pg_connector(config: &PgTlsConfig) -> Result<MakeRustlsConnect, Error> {
+    Ok(MakeRustlsConnect::new(pg_client_config(config)?))
+}
+
+fn pg_client_config(config: &PgTlsConfig) -> Result<ClientConfig, Error> {
+    let roots = match &config.root_cert_file {
+        Some(root_cert_file) => load_roots(root_cert_file)?,
+        None => {
+            let mut roots = RootCertStore::empty();
+            let native = rustls_native_certs::load_native_certs();
+            for err in native.errors {
+                log::warn!("Failed to load system root certificate: {}", err);
+            }
+            roots.add_parsable_certificates(native.certs);
+            roots
+        }
+    };
+
+    Ok(match config.verify {
+        PgTlsVerify::Full => ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+        PgTlsVerify::Ca => {
+            let verifier = WebPkiServerVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipHostnameVerifier(verifier)))
+                .with_no_client_auth()
+        }
+        PgTlsVerify::None => ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerifier(provider())))
+            .with_no_client_auth(),
+    })
+}
+
+fn load_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(file)
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to read certificates {}: {}", file, err),
+            )
+        })?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("no certificates found in {}", file),
+        ));
+    }
+    Ok(certs)
+}
+
+fn load_roots(file: &str) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(file)? {
+        roots
+            .add(cert)
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    }
+    Ok(roots)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()))
+}
+
+// SkipHostnameVerifier checks the certificate chain but accepts certificates
+// issued for another host name (libpq sslmode=verify-ca).
+#[derive(Debug)]
+struct SkipHostnameVerifier(Arc<WebPkiServerVerifier>);
+
+impl ServerCertVerifier for SkipHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self
+            .0
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) => Ok(ServerCertVerified::assertion()),
+            res => res,
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+// NoCertificateVerifier encrypts the connection without authenticating the
+// server (libpq sslmode=require without a root certificate).
+#[derive(Debug)]
+struct NoCertificateVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pg_client_config, server_config};
+    use crate::config::{PgTlsConfig, PgTlsVerify, ServerTlsConfig};
+    use rcgen::{
+        BasicConstraints, CertificateParams, DnType, IsCa, Issuer, KeyPair, KeyUsagePurpose,
+    };
+    use rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+    };
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    // Ca is a test certificate authority issuing leaf certificates.
+    struct Ca {
+        issuer: Issuer<'static, KeyPair>,
+        pem: String,
+    }
+
+    // Leaf is a certificate with its private key, in PEM.
+    struct Leaf {
+        cert: String,
+        key: String,
+    }
+
+    impl Ca {
+        fn new(name: &str) -> Ca {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+            let key = KeyPair::generate().unwrap();
+            let pem = params.self_signed(&key).unwrap().pem();
+            Ca {
+                issuer: Issuer::new(params, key),
+                pem,
+            }
+        }
+
+        fn issue(&self, name: &str) -> Leaf {
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let key = KeyPair::generate().unwrap();
+            Leaf {
+                cert: params.signed_by(&key, &self.issuer).unwrap().pem(),
+                key: key.serialize_pem(),
+            }
+        }
+    }
+
+    // write stores a PEM file under a name unique to the test.
+    fn write(test: &str, name: &str, pem: &str) -> String {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "psql-ledger-tls-{}-{}-{}",
+            std::process::id(),
+            test,
+            name
+        ));
+        std::fs::write(&path, pem).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn server_tls_config(test: &str, leaf: &Leaf, client_ca: Option<&Ca>) -> ServerTlsConfig {
+        ServerTlsConfig {
+            cert_file: write(test, "server.crt", &leaf.cert),
+            key_file: write(test, "server.key", &leaf.key),
+            client_ca_file: client_ca.map(|ca| write(test, "client-ca.crt", &ca.pem)),
+            require_client_cert: true,
+        }
+    }
+
+    fn client_config(trusted: &Ca, identity: Option<&Leaf>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(trusted.pem.as_bytes()).unwrap())
+            .unwrap();
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        match identity {
+            Some(leaf) => builder
+                .with_client_auth_cert(
+                    vec![CertificateDer::from_pem_slice(leaf.cert.as_bytes()).unwrap()],
+                    PrivateKeyDer::from_pem_slice(leaf.key.as_bytes()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    // handshake runs a TLS handshake in memory and returns the first error of
+    // either side.
+    fn handshake(
+        client: ClientConfig,
+        server: ServerConfig,
+        name: &str,
+    ) -> Result<(), rustls::Error> {
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut client = Connection::from(ClientConnection::new(Arc::new(client), name)?);
+        let mut server = Connection::from(ServerConnection::new(Arc::new(server))?);
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+            pump(&mut client, &mut server)?;
+            pump(&mut server, &mut client)?;
+        }
+        Err(rustls::Error::General(
+            "handshake did not complete".to_string(),
+        ))
+    }
+
+    fn pump(from: &mut Connection, to: &mut Connection) -> Result<(), rustls::Error> {
+        let mut buf = Vec::new();
+        while from.wants_write() {
+            from.write_tls(&mut buf).unwrap();
+        }
+        let mut rd = buf.as_slice();
+        while !rd.is_empty() {
+            to.read_tls(&mut rd).unwrap();
+            to.process_new_packets()?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn server_config_loads_pem_files() {
+        let ca = Ca::new("server ca");
+        let config = server_tls_config("load", &ca.issue("localhost"), None);
+        let server = server_config(&config).unwrap();
+        handshake(client_config(&ca, None), server, "localhost").unwrap();
+
+        let missing = ServerTlsConfig {
+            key_file: "/nonexistent/server.key".to_string(),
+            ..config.clone()
+        };
+        let err = server_config(&missing).unwrap_err();
+        assert!(
+            err.to_string().contains("failed to read private key"),
+            "{}",
+            err
+        );
+
+        let empty = ServerTlsConfig {
+            cert_file: write("load", "empty.crt", ""),
+            ..config
+        };
+        let err = server_config(&empty).unwrap_err();
+        assert!(err.to_string().contains("no certificates found"), "{}", err);
+    }
+
+    #[test]
+    fn mutual_tls_accepts_clients_signed_by_the_client_ca() {
+        let server_ca = Ca::new("server ca");
+        let client_ca = Ca::new("client ca");
+        let config = server_tls_config("mtls", &server_ca.issue("localhost"), Some(&client_ca));
+
+        let client = client_config(&server_ca, Some(&client_ca.issue("client")));
+        handshake(client, server_config(&config).unwrap(), "localhost").unwrap();
+    }
+
+    #[test]
+    fn mutual_tls_rejects_unknown_or_missing_client_certs() {
+        let server_ca = Ca::new("server ca");
+        let client_ca = Ca::new("client ca");
+        let mut config = server_tls_config(
+            "mtls-reject",
+            &server_ca.issue("localhost"),
+            Some(&client_ca),
+        );
+
+        let stranger = client_config(&server_ca, Some(&Ca::new("other ca").issue("client")));
+        assert!(handshake(stranger, server_config(&config).unwrap(), "localhost").is_err());
+
+        let anonymous = client_config(&server_ca, None);
+        assert!(handshake(anonymous, server_config(&config).unwrap(), "localhost").is_err());
+
+        // optional client certificates let anonymous clients through
+        config.require_client_cert = false;
+        let anonymous = client_config(&server_ca, None);
+        handshake(anonymous, server_config(&config).unwrap(), "localhost").unwrap();
+    }
+
+    #[test]
+    fn pg_client_config_applies_the_verification_level() {
+        let ca = Ca::new("postgres ca");
+        let config = server_tls_config("pg", &ca.issue("db.internal"), None);
+        let pg_tls = |verify| PgTlsConfig {
+            root_cert_file: Some(write("pg", "root.crt", &ca.pem)),
+            verify,
+        };
+        let connect = |verify, name: &str| {
+            handshake(
+                pg_client_config(&pg_tls(verify)).unwrap(),
+                server_config(&config).unwrap(),
+                name,
+            )
+        };
+
+        connect(PgTlsVerify::Full, "db.internal").unwrap();
+        assert!(connect(PgTlsVerify::Full, "other.internal").is_err());
+        // verify-ca checks the chain but not the host name
+        connect(PgTlsVerify::Ca, "other.internal").unwrap();
+
+        let untrusted = PgTlsConfig {
+            root_cert_file: Some(write("pg", "other-root.crt", &Ca::new("other ca").pem)),
+            verify: PgTlsVerify::Ca,
+        };
+        let server = server_config(&config).unwrap();
+        assert!(handshake(pg_client_config(&untrusted).unwrap(), server, "db.internal").is_err());
+        let unverified = PgTlsConfig {
+            verify: PgTlsVerify::None,
+            ..untrusted
+        };
+        let server = server_config(&config).unwrap();
+        handshake(
+            pg_client_config(&unverified).unwrap(),
+            server,
+            "db.internal",
+        )
+        .unwrap();
+
+        let missing = PgTlsConfig {
+            root_cert_file: Some("/nonexistent/root.crt".to_string()),
+            verify: PgTlsVerify::Full,
+        };
+        assert!(pg_client_config(&missing).is_err());
+    }
+}
@@ -0,0 +1,426 @@
+// Webhook notifications. Ledger operations queue one delivery per matching
+// subscription in the same database transaction as the change, so an event is
+// never announced for a change that rolled back. The dispatcher sends the due
+// deliveries with an HMAC-SHA256 signature and retries failures with
+// exponential backoff; every attempt is recorded in webhook_deliveries.
+use crate::{config::WebhookConfig, db, errors::MyError, shutdown::stopped};
+use actix_web::{
+    http::{uri::Scheme, StatusCode, Uri},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+use awc::Client;
+use chrono::{TimeDelta, Utc};
+use deadpool_postgres::{GenericClient, Pool};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use rand::{rngs::OsRng, RngCore};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::Duration;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+pub const ACCOUNT_CREATED: &str = "account.created";
+pub const ACCOUNT_UPDATED: &str = "account.updated";
+pub const TRANSFER_CREATED: &str = "transfer.created";
+pub const SCHEDULED_TRANSFER_STATUS_CHANGED: &str = "scheduled_transfer.status_changed";
+
+// EVENT_TYPES lists the events a subscription can select.
+pub const EVENT_TYPES: [&str; 4] = [
+    ACCOUNT_CREATED,
+    ACCOUNT_UPDATED,
+    TRANSFER_CREATED,
+    SCHEDULED_TRANSFER_STATUS_CHANGED,
+];
+
+// delivery statuses
+pub const PENDING: &str = "pending";
+pub const DELIVERED: &str = "delivered";
+pub const FAILED: &str = "failed";
+pub const CANCELLED: &str = "cancelled";
+
+// DELIVERY_STATUSES lists the statuses deliveries can be filtered on.
+pub const DELIVERY_STATUSES: [&str; 4] = [PENDING, DELIVERED, FAILED, CANCELLED];
+
+// how far a signature timestamp may be from the receiver's clock, in seconds
+pub const SIGNATURE_TOLERANCE_SECS: i64 = 300;
+
+pub const SIGNATURE_HEADER: &str = "X-Ledger-Signature";
+pub const EVENT_HEADER: &str = "X-Ledger-Event";
+pub const DELIVERY_HEADER: &str = "X-Ledger-Delivery";
+
+// enqueue queues an event for the subscriptions to its type. It must run in
+// the database transaction making the change the event describes.
+pub async fn enqueue<T: Serialize>(
+    client: &impl GenericClient,
+    event_type: &str,
+    data: &T,
+) -> Result<(), MyError> {
+    let event_id = Uuid::new_v4().to_string();
+    let payload = json!({
+        "id": event_id,
+        "type": event_type,
+        "created_at": Utc::now().to_rfc3339(),
+        "data": serde_json::to_value(data).unwrap_or(Value::Null),
+    });
+    db::enqueue_webhook_deliveries(client, &event_id, event_type, &payload).await?;
+    Ok(())
+}
+
+// check_url accepts the absolute http and https URLs deliveries can be sent to.
+pub fn check_url(url: &str) -> Result<(), String> {
+    let uri: Uri = url
+        .parse()
+        .map_err(|_| "url is not a valid URL".to_string())?;
+    if uri.scheme() != Some(&Scheme::HTTP) && uri.scheme() != Some(&Scheme::HTTPS) {
+        return Err("url must be an http or https URL".to_string());
+    }
+    if uri.host().is_none_or(str::is_empty) {
+        return Err("url has no host".to_string());
+    }
+    Ok(())
+}
+
+// generate_secret returns a new random signing secret.
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 24];
+    OsRng.fill_bytes(&mut secret);
+    format!("whsec_{}", hex::encode(secret))
+}
+
+// sign returns the signature header value for a body sent at timestamp. The
+// signed content is "<timestamp>.<body>" so that a captured delivery cannot be
+// replayed with a different timestamp, and verify rejects timestamps older
+// than SIGNATURE_TOLERANCE_SECS, so that it cannot be replayed later as is.
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let signature = mac(secret, timestamp, body).finalize().into_bytes();
+    format!("t={},v1={}", timestamp, hex::encode(signature))
+}
+
+fn mac(secret: &str, timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+// verify checks a signature header against the body and returns its timestamp,
+// which must be within SIGNATURE_TOLERANCE_SECS of now.
+pub fn verify(secret: &str, header: &str, body: &[u8]) -> Result<i64, String> {
+    verify_at(secret, header, body, Utc::now().timestamp())
+}
+
+fn verify_at(secret: &str, header: &str, body: &[u8], now: i64) -> Result<i64, String> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or("missing or invalid timestamp")?;
+    let mac = mac(secret, timestamp, body);
+    let matched = signatures.iter().any(|candidate| {
+        hex::decode(candidate)
+            .map(|bytes| mac.clone().verify_slice(&bytes).is_ok())
+            .unwrap_or(false)
+    });
+    if !matched {
+        return Err("signature mismatch".to_string());
+    }
+    if (now - timestamp).abs() > SIGNATURE_TOLERANCE_SECS {
+        return Err("timestamp outside the tolerance window".to_string());
+    }
+    Ok(timestamp)
+}
+
+// dispatch sends due deliveries every interval until shutdown is triggered.
+pub async fn dispatch(pool: Pool, config: WebhookConfig, mut shutdown: watch::Receiver<bool>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(config.interval_secs));
+
+    loop {
+        tokio::select! {
+            _ = stopped(&mut shutdown) => return,
+            _ = ticker.tick() => {}
+        }
+
+        match deliver_due(&pool, &config).await {
+            Ok(0) => {}
+            Ok(count) => log::info!("Sent {} webhook deliveries", count),
+            Err(err) => log::error!("Webhook delivery failed: {}", err),
+        }
+    }
+}
+
+// deliver_due sends up to batch_size due deliveries concurrently and returns
+// how many were attempted.
+pub async fn deliver_due(pool: &Pool, config: &WebhookConfig) -> Result<usize, MyError> {
+    let client = pool.get().await?;
+    // the lease outlasts the request so that an attempt is not sent twice
+    let deliveries =
+        db::claim_webhook_deliveries(&client, config.batch_size, config.timeout_secs * 2 + 5)
+            .await?;
+    let count = deliveries.len();
+    if count == 0 {
+        return Ok(0);
+    }
+
+    let http = Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .finish();
+    let attempts = deliveries.iter().map(|(delivery, url, secret)| async {
+        let body = serde_json::to_vec(&delivery.payload).unwrap_or_default();
+        let signature = sign(secret, Utc::now().timestamp(), &body);
+        let response = http
+            .post(url.as_str())
+            .insert_header(("Content-Type", "application/json"))
+            .insert_header((SIGNATURE_HEADER, signature))
+            .insert_header((EVENT_HEADER, delivery.event_type.as_str()))
+            .insert_header((DELIVERY_HEADER, delivery.event_id.as_str()))
+            .send_body(body)
+            .await;
+        match response {
+            Ok(response) if response.status().is_success() => Ok(response.status().as_u16() as i32),
+            Ok(response) => Err((
+                Some(response.status().as_u16() as i32),
+                format!("receiver responded {}", response.status()),
+            )),
+            Err(err) => Err((None, err.to_string())),
+        }
+    });
+    let outcomes = join_all(attempts).await;
+
+    for ((delivery, url, _), outcome) in deliveries.iter().zip(outcomes) {
+        match outcome {
+            Ok(status) => {
+                db::finish_webhook_delivery(&client, delivery.id, true, Some(status), None, None)
+                    .await?;
+            }
+            Err((status, error)) => {
+                let retry_at =
+                    retry_delay(config, delivery.attempts).map(|delay| Utc::now() + delay);
+                log::warn!(
+                    "Webhook delivery {} to {} failed (attempt {}): {}",
+                    delivery.id,
+                    url,
+                    delivery.attempts,
+                    error
+                );
+                db::finish_webhook_delivery(
+                    &client,
+                    delivery.id,
+                    false,
+                    status,
+                    Some(&error),
+                    retry_at,
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(count)
+}
+
+// retry_delay returns how long to wait before retrying a delivery that failed
+// on its given attempt, or None once the attempts are exhausted.
+fn retry_delay(config: &WebhookConfig, attempts: i32) -> Option<TimeDelta> {
+    (attempts < config.max_attempts).then(|| {
+        let backoff = config.retry_delay_secs << (attempts - 1).clamp(0, 16);
+        TimeDelta::seconds(backoff as i64)
+    })
+}
+
+// receive runs a local receiver that verifies and prints every delivery,
+// answering with status. It is meant for testing subscriptions end to end.
+pub async fn receive(addr: String, secret: String, status: u16) -> std::io::Result<()> {
+    let status = StatusCode::from_u16(status)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let receiver = web::Data::new(Receiver { secret, status });
+    println!("Receiving webhooks at http://{}", addr);
+    HttpServer::new(move || {
+        App::new()
+            .app_data(receiver.clone())
+            .default_service(web::to(receive_delivery))
+    })
+    .workers(1)
+    .bind(addr)?
+    .run()
+    .await
+}
+
+// Receiver is the configuration of the local receiver.
+pub struct Receiver {
+    pub secret: String,
+    // status answered to verified deliveries
+    pub status: StatusCode,
+}
+
+// receive_delivery prints a delivery whose signature verifies and rejects
+// any other request with 401.
+pub async fn receive_delivery(
+    req: HttpRequest,
+    body: web::Bytes,
+    receiver: web::Data<Receiver>,
+) -> HttpResponse {
+    let header = req
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    match verify(&receiver.secret, header, &body) {
+        Ok(_) => {
+            println!("{}", String::from_utf8_lossy(&body));
+            HttpResponse::build(receiver.status).finish()
+        }
+        Err(err) => {
+            eprintln!("Rejected delivery: {}", err);
+            HttpResponse::Unauthorized().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_url, receive_delivery, retry_delay, sign, verify, verify_at, Receiver,
+        SIGNATURE_HEADER, SIGNATURE_TOLERANCE_SECS,
+    };
+    use crate::config::WebhookConfig;
+    use actix_web::{http::StatusCode, test::TestRequest, web};
+    use chrono::{TimeDelta, Utc};
+
+    const SECRET: &str = "whsec_test";
+    const BODY: &[u8] = br#"{"id":1}"#;
+    const NOW: i64 = 1_700_000_000;
+
+    #[test]
+    fn sign_covers_timestamp_and_body() {
+        assert_eq!(
+            sign(SECRET, NOW, BODY),
+            "t=1700000000,v1=2f441ba4b3b2d50d28a9ab9d9fd8880376ecd1eb5d0435401553f5d8d0a5dcf8"
+        );
+        assert_ne!(sign(SECRET, NOW + 1, BODY), sign(SECRET, NOW, BODY));
+    }
+
+    #[test]
+    fn verify_accepts_signed_bodies() {
+        let header = sign(SECRET, NOW, BODY);
+        assert_eq!(verify_at(SECRET, &header, BODY, NOW), Ok(NOW));
+        // any matching v1 signature is accepted, e.g. while rotating secrets
+        let rotated = format!("{},v1={}", header, "00".repeat(32));
+        assert_eq!(verify_at(SECRET, &rotated, BODY, NOW), Ok(NOW));
+        let now = Utc::now().timestamp();
+        assert_eq!(verify(SECRET, &sign(SECRET, now, BODY), BODY), Ok(now));
+    }
+
+    #[test]
+    fn verify_rejects_tampering() {
+        let header = sign(SECRET, NOW, BODY);
+        let mismatch = Err("signature mismatch".to_string());
+        assert_eq!(verify_at(SECRET, &header, br#"{"id":2}"#, NOW), mismatch);
+        assert_eq!(verify_at("whsec_other", &header, BODY, NOW), mismatch);
+        let replayed = header.replace("t=1700000000", "t=1700000099");
+        assert_eq!(verify_at(SECRET, &replayed, BODY, NOW), mismatch);
+        assert_eq!(verify_at(SECRET, "t=1700000000,v1=zz", BODY, NOW), mismatch);
+        assert!(verify_at(SECRET, "v1=abcd", BODY, NOW).is_err());
+        assert!(verify_at(SECRET, "", BODY, NOW).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_timestamps_outside_tolerance() {
+        let header = sign(SECRET, NOW, BODY);
+        for now in [
+            NOW - SIGNATURE_TOLERANCE_SECS,
+            NOW + SIGNATURE_TOLERANCE_SECS,
+        ] {
+            assert_eq!(verify_at(SECRET, &header, BODY, now), Ok(NOW));
+        }
+        let outside = Err("timestamp outside the tolerance window".to_string());
+        for now in [
+            NOW - SIGNATURE_TOLERANCE_SECS - 1,
+            NOW + SIGNATURE_TOLERANCE_SECS + 1,
+        ] {
+            assert_eq!(verify_at(SECRET, &header, BODY, now), outside);
+        }
+    }
+
+    #[test]
+    fn check_url_accepts_http_and_https_only() {
+        assert!(check_url("https://hooks.example.com/ledger").is_ok());
+        assert!(check_url("HTTP://localhost:9000").is_ok());
+        assert!(check_url("ftp://hooks.example.com").is_err());
+        assert!(check_url("file:///etc/passwd").is_err());
+        assert!(check_url("hooks.example.com/ledger").is_err());
+        assert!(check_url("https://").is_err());
+        assert!(check_url("not a url").is_err());
+    }
+
+    #[test]
+    fn retries_back_off_exponentially_until_attempts_run_out() {
+        let config = WebhookConfig {
+            max_attempts: 4,
+            retry_delay_secs: 30,
+            ..WebhookConfig::default()
+        };
+        let delays: Vec<_> = (1..=4)
+            .map(|attempts| retry_delay(&config, attempts))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(TimeDelta::seconds(30)),
+                Some(TimeDelta::seconds(60)),
+                Some(TimeDelta::seconds(120)),
+                None,
+            ]
+        );
+
+        // the doubling stops after 16 retries
+        let config = WebhookConfig {
+            max_attempts: 100,
+            retry_delay_secs: 1,
+            ..WebhookConfig::default()
+        };
+        assert_eq!(retry_delay(&config, 17), Some(TimeDelta::seconds(1 << 16)));
+        assert_eq!(retry_delay(&config, 50), Some(TimeDelta::seconds(1 << 16)));
+    }
+
+    #[actix_web::test]
+    async fn receiver_answers_verified_deliveries_only() {
+        let receiver = web::Data::new(Receiver {
+            secret: SECRET.to_string(),
+            status: StatusCode::ACCEPTED,
+        });
+        let deliver = |header: Option<String>| {
+            let mut req = TestRequest::post();
+            if let Some(header) = header {
+                req = req.insert_header((SIGNATURE_HEADER, header));
+            }
+            receive_delivery(
+                req.to_http_request(),
+                web::Bytes::from_static(BODY),
+                receiver.clone(),
+            )
+        };
+
+        let now = Utc::now().timestamp();
+        let signed = sign(SECRET, now, BODY);
+        assert_eq!(deliver(Some(signed)).await.status(), StatusCode::ACCEPTED);
+        let stale = sign(SECRET, NOW, BODY);
+        assert_eq!(
+            deliver(Some(stale)).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        let forged = sign("whsec_other", now, BODY);
+        assert_eq!(
+            deliver(Some(forged)).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(deliver(None).await.status(), StatusCode::UNAUTHORIZED);
+    }
+}